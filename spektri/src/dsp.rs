@@ -7,15 +7,36 @@ use multifft::MultiFft;
 
 mod spectrum;
 use spectrum::SpectrumAccumulator;
-pub use spectrum::SpectrumFormat;
+pub use spectrum::{SpectrumFlagParams, SpectrumFormat, SpectrumParams, SpectrumStatParams, SpectrumStatistic};
 
 mod fcfb;
 use fcfb::Fcfb;
 pub use fcfb::FilterParams;
 
+mod ssb;
+pub use ssb::SsbParams;
+
+mod channelizer;
+pub use channelizer::ChannelizerParams;
+
+mod multiband;
+pub use multiband::{Band, MultibandParams};
+
+pub mod calibration;
 pub mod data;
+pub mod demod;
+pub mod detector;
 pub mod fftutil;
+pub mod fir;
+pub mod history;
 pub mod output;
+pub mod rds;
+pub mod schedule;
+pub mod squelch;
+pub mod synthesis;
+pub mod telemetry;
+pub mod tracking;
+pub mod wbfm;
 
 pub use data::{Metadata, FftInfo};
 
@@ -29,9 +50,14 @@ pub struct DspParams {
     pub scaling: f32, // Scaling of input values
     pub ffts_per_buf: usize,
     //pub fft_overlap: usize, // Fixed for now
-    pub spectrum_format: SpectrumFormat, // Output format for spectrum data
-    pub spectrum_averages: u32, // Number of FFTs averaged
+    pub spectra: Vec<SpectrumParams>, // Spectrum products computed from the FFT results
+    pub calibration: calibration::Calibration, // Conversion of spectrum values to absolute power
     pub filters: Vec<FilterParams>, // Filter bank parameters
+    pub ssb_receivers: Vec<SsbParams>, // Frequency domain SSB receivers
+    pub channelizers: Vec<ChannelizerParams>, // Uniformly spaced channels
+    pub multibands: Vec<MultibandParams>, // Several bands packed into one output
+    pub telemetry: Option<telemetry::TelemetryParams>, // Channel telemetry for filters
+    pub history: f64, // Seconds of FFT results kept for filters starting in the past
 }
 
 /// Requirements for the buffers given to DspState::process
@@ -47,8 +73,9 @@ pub struct DspState {
     fft_interval: usize,
 
     mfft: MultiFft,
-    accu: SpectrumAccumulator,
+    accu: Vec<SpectrumAccumulator>,
     fb: Fcfb,
+    calibration: calibration::Calibration,
 
     window: Vec<f32>, // Window function,
     fft_result_buf: Vec<Complex<f32>>, // Pre-allocated buffer
@@ -73,7 +100,7 @@ fn rectangular_window(size: usize, scaling: f32) -> Vec<f32> {
 }
 
 impl DspState {
-    pub fn init(params: DspParams) -> (DspState, InputBufferSize) {
+    pub fn init(params: DspParams) -> Result<(DspState, InputBufferSize), String> {
         let fft_overlap = params.fft_size / 4; // 25% overlap
         let fft_interval = params.fft_size - fft_overlap; // FFT is taken every fft_interval samples
         let result_bins = if params.complex { params.fft_size } else { params.fft_size / 2 + 1 };
@@ -84,20 +111,40 @@ impl DspState {
             complex: params.complex,
         };
 
-        (DspState {
+        Ok((DspState {
             fft_info:     fft_info,
             ffts_per_buf: params.ffts_per_buf,
             fft_interval: fft_interval,
 
             mfft: MultiFft::init(params.fft_size),
-            accu: SpectrumAccumulator::init(fft_info, params.spectrum_averages, params.spectrum_format),
+            accu: params.spectra.iter().enumerate()
+                .map(|(i, p)| SpectrumAccumulator::init(fft_info, i, p, &params.calibration))
+                .collect::<Result<_, _>>()?,
             fb: {
-                let mut fb = Fcfb::init(fft_info);
+                let mut fb = Fcfb::init(fft_info, params.ffts_per_buf, params.telemetry.as_ref(), params.history);
                 for f in params.filters.iter() {
-                    fb.add_filter(f);
+                    if let Err(error) = fb.add_filter(&params.calibration.filter_params(f)) {
+                        eprintln!("Error creating filter: {}", error);
+                    }
+                }
+                for s in params.ssb_receivers.iter() {
+                    if let Err(error) = fb.add_ssb_receiver(s) {
+                        eprintln!("Error creating SSB receiver: {}", error);
+                    }
+                }
+                for c in params.channelizers.iter() {
+                    if let Err(error) = fb.add_channelizer(c) {
+                        eprintln!("Error creating channelizer: {}", error);
+                    }
+                }
+                for m in params.multibands.iter() {
+                    if let Err(error) = fb.add_multiband(m) {
+                        eprintln!("Error creating multi-band filter: {}", error);
+                    }
                 }
                 fb
             },
+            calibration: params.calibration.clone(),
 
             // TODO: Now that a rectangular window is used,
             // consider removing the multiplication with a window function
@@ -108,7 +155,38 @@ impl DspState {
             overlap: fft_overlap,
             new: fft_interval * params.ffts_per_buf,
             total: fft_overlap + fft_interval * params.ffts_per_buf
-        })
+        }))
+    }
+
+    /// Add a filter while running.
+    pub fn add_filter(
+        &mut self,
+        params: &FilterParams,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.fb.add_filter(&self.calibration.filter_params(params))
+    }
+
+    /// Return whether a filter has triggered a snapshot
+    /// of the input signal since the previous call.
+    pub fn take_snapshot_trigger(&mut self) -> bool {
+        self.fb.take_snapshot_trigger()
+    }
+
+    /// Remove a filter while running.
+    pub fn remove_filter(
+        &mut self,
+        name: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.fb.remove_filter(name)
+    }
+
+    /// Change the center frequency of a tracking filter.
+    pub fn set_filter_frequency(
+        &mut self,
+        name: &str,
+        freq: f64,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.fb.set_filter_frequency(name, freq)
     }
 
     pub fn process_complex(
@@ -130,7 +208,9 @@ impl DspState {
             ).collect::<Vec<&[Complex<f32>]>>(),
             &mut resultbufs
         );
-        self.accu.accumulate(&resultbufs, metadata, sock)?;
+        for accu in self.accu.iter_mut() {
+            accu.accumulate(&resultbufs, metadata, sock)?;
+        }
         self.fb.process(&resultbufs, metadata, sock);
         Ok(())
     }
@@ -154,7 +234,9 @@ impl DspState {
             ).collect::<Vec<&[f32]>>(),
             &mut resultbufs
         );
-        self.accu.accumulate(&resultbufs, metadata, sock)?;
+        for accu in self.accu.iter_mut() {
+            accu.accumulate(&resultbufs, metadata, sock)?;
+        }
         self.fb.process(&resultbufs, metadata, sock);
         Ok(())
     }
@@ -0,0 +1,121 @@
+//! Absolute power calibration
+//!
+//! Input samples are scaled by input_format_scaling so that full scale
+//! of the input format is 1. Spectrum values are then in dB relative
+//! to a full-scale complex sinusoid for complex input. For real input,
+//! a full-scale sinusoid shows up 6 dB lower in a single bin,
+//! so that is corrected to give dBFS relative to a full-scale sinusoid.
+//!
+//! For dBm, the power of a full-scale sinusoid at the receiver input
+//! is added and the gain in front of the receiver is subtracted.
+//! The gain can depend on frequency and is then read from a CSV file
+//! with a frequency in Hz and a gain in dB on each line,
+//! linearly interpolated between the given frequencies.
+//! Lines starting with # are comments.
+
+use std::error::Error;
+
+use super::data::*;
+use super::fcfb::FilterParams;
+
+arg_enum! { // needed for command line parsing
+    /// Unit of spectrum values
+    #[derive(Debug, Copy, Clone, PartialEq)]
+    pub enum PowerUnit { Dbfs, Dbm, DbmHz }
+}
+
+impl PowerUnit {
+    /// Code used for the unit in the topic
+    pub fn code(self) -> u8 {
+        match self {
+            PowerUnit::Dbfs  => SPECTRUM_UNIT_DBFS,
+            PowerUnit::Dbm   => SPECTRUM_UNIT_DBM,
+            PowerUnit::DbmHz => SPECTRUM_UNIT_DBM_PER_HZ,
+        }
+    }
+}
+
+/// Calibration parameters
+#[derive(Clone, Default)]
+pub struct Calibration {
+    /// Power in dBm of a full-scale sinusoid at the receiver input
+    pub fullscale_dbm: f32,
+    /// Gain in dB in front of the receiver at given frequencies,
+    /// sorted by frequency
+    pub gains: Vec<(f64, f32)>,
+    /// Also correct the gain of filter outputs, so that they are
+    /// scaled relative to the signal in front of the receiver
+    pub filters: bool,
+}
+
+impl Calibration {
+    /// Gain in front of the receiver at a frequency.
+    /// Outside the table, the gain at the nearest end is used.
+    pub fn gain_db(&self, f: f64) -> f32 {
+        let i = self.gains.partition_point(|&(tf, _)| tf < f);
+        match (i.checked_sub(1).map(|j| self.gains[j]), self.gains.get(i)) {
+            (Some((f0, g0)), Some(&(f1, g1))) =>
+                g0 + (g1 - g0) * ((f - f0) / (f1 - f0)) as f32,
+            (Some((_, g)), None) | (None, Some(&(_, g))) => g,
+            (None, None) => 0.0,
+        }
+    }
+
+    /// Correction in dB added to power of a bin at frequency f
+    /// to convert it into a given unit.
+    /// noise_bandwidth is the equivalent noise bandwidth of a bin in Hz.
+    pub fn correction_db(&self, unit: PowerUnit, complex: bool, f: f64, noise_bandwidth: f64) -> f32 {
+        let dbfs = if complex { 0.0 } else { 20.0 * 2.0f32.log10() };
+        match unit {
+            PowerUnit::Dbfs => dbfs,
+            PowerUnit::Dbm => dbfs + self.fullscale_dbm - self.gain_db(f),
+            PowerUnit::DbmHz => dbfs + self.fullscale_dbm - self.gain_db(f) - 10.0 * (noise_bandwidth as f32).log10(),
+        }
+    }
+
+    /// Filter parameters with the gain corrected
+    /// if calibration of filters is enabled.
+    pub fn filter_params(&self, p: &FilterParams) -> FilterParams {
+        let mut p = p.clone();
+        if self.filters {
+            p.gain -= self.gain_db(p.fc_out);
+        }
+        p
+    }
+}
+
+/// Load a gain table from a CSV file.
+pub fn load_gain_table(filename: &str) -> Result<Vec<(f64, f32)>, Box<dyn Error>> {
+    let mut gains = Vec::new();
+    for (n, line) in std::fs::read_to_string(filename)?.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let entry = line.split_once(',')
+            .and_then(|(f, g)| Some((f.trim().parse().ok()?, g.trim().parse().ok()?)))
+            .ok_or_else(|| format!("{} line {}: should be frequency,gain", filename, n + 1))?;
+        gains.push(entry);
+    }
+    gains.sort_by(|a: &(f64, f32), b| a.0.total_cmp(&b.0));
+    Ok(gains)
+}
+
+
+#[test]
+fn test_calibration() {
+    let calibration = Calibration {
+        fullscale_dbm: -10.0,
+        gains: vec![(1e6, 20.0), (2e6, 10.0)],
+        filters: true,
+    };
+    assert!(calibration.gain_db(0.0) == 20.0);
+    assert!(calibration.gain_db(1.5e6) == 15.0);
+    assert!(calibration.gain_db(3e6) == 10.0);
+    assert!(calibration.correction_db(PowerUnit::Dbfs, true, 1e6, 100.0) == 0.0);
+    assert!(calibration.correction_db(PowerUnit::Dbm, true, 1e6, 100.0) == -30.0);
+    assert!(calibration.correction_db(PowerUnit::DbmHz, true, 1e6, 100.0) == -50.0);
+    // Real input is 6 dB higher to compensate for the negative frequencies
+    assert!((calibration.correction_db(PowerUnit::Dbfs, false, 1e6, 100.0) - 6.02).abs() < 0.01);
+    assert!(Calibration::default().gain_db(1e6) == 0.0);
+}
@@ -0,0 +1,206 @@
+//! Uniform channelizer
+//!
+//! Produces many equally spaced channels of the same sample rate,
+//! like a set of filters, but with a single weight table and IFFT plan
+//! shared by all channels. Each FFT result is processed in one pass
+//! producing all channels from it, which keeps the FFT result in cache.
+
+use std::error::Error;
+use rayon::prelude::*;
+use rustfft::{FftPlanner, num_complex::Complex};
+
+use super::data::*;
+use super::fcfb::filter_weights;
+use super::fftutil::*;
+use super::output::OutputParams;
+
+/// Channelizer parameters
+pub struct ChannelizerParams {
+    pub start: f64, // Center frequency of the first channel
+    pub stop: f64, // Center frequency of the last channel, at most
+    pub spacing: f64, // Spacing between channel center frequencies
+    pub fs_out: f64, // Output sample rate of each channel
+    pub taps: Option<Vec<f32>>, // FIR coefficients at output sample rate
+    pub format: SignalFormat, // Output sample format
+    pub gain: f32, // Output gain in dB
+    /// Output file name for each channel.
+    /// {} is replaced with the center frequency of the channel.
+    pub output: OutputParams,
+}
+
+pub struct Channelizer {
+    fft_size: usize,
+    /// Number of the center bin of each channel
+    centers: Vec<isize>,
+    /// Frequency response shared by all channels
+    weights: Vec<Complex<f32>>,
+    ifft: std::sync::Arc<dyn rustfft::Fft<f32>>,
+    /// Number of FFT results processed so far
+    ffts: u64,
+    /// Output samples of a processing block,
+    /// ordered by FFT result, then by channel
+    samples: Vec<Complex<f32>>,
+}
+
+impl Channelizer {
+    pub fn init(
+        fft_info: FftInfo,
+        p: &ChannelizerParams,
+    ) -> Result<Self, Box<dyn Error>>
+    {
+        let bin_spacing = fft_info.fs / fft_info.size as f64;
+        let to_bins = |f: f64| (f / bin_spacing).round() as isize;
+
+        // IFFT size must be a multiple of 4 for the 25% overlap.
+        // Channel centers can be at any bin thanks to phase correction.
+        let bins = to_bins(p.fs_out / 4.0) * 4;
+        let start = to_bins(p.start - fft_info.fc);
+        let spacing = to_bins(p.spacing);
+        let exact = |bins: isize| bins as f64 * bin_spacing;
+        if bins <= 0 || spacing <= 0 ||
+            exact(bins) != p.fs_out ||
+            fft_info.fc + exact(start) != p.start ||
+            exact(spacing) != p.spacing
+        {
+            return Err(format!(
+                "Channelizer start={} spacing={} fs={} is not possible, nearest possible is start={} spacing={} fs={}",
+                p.start, p.spacing, p.fs_out,
+                fft_info.fc + exact(start), exact(spacing), exact(bins)).into());
+        }
+        if bins as usize > fft_info.size {
+            return Err(format!(
+                "Channelizer fs={} is wider than input bandwidth of {} Hz",
+                p.fs_out, fft_info.fs).into());
+        }
+
+        let centers: Vec<isize> = (0..)
+            .map(|i| start + i * spacing)
+            .take_while(|&c| fft_info.fc + exact(c) <= p.stop)
+            .collect();
+        if centers.is_empty() {
+            return Err(format!("Channelizer stop={} is below start={}", p.stop, p.start).into());
+        }
+
+        let mut planner = FftPlanner::new();
+        Ok(Self {
+            fft_size: fft_info.size,
+            centers,
+            weights: filter_weights(p.taps.as_deref(), bins as usize)?,
+            ifft: planner.plan_fft_inverse(bins as usize),
+            ffts: 0,
+            samples: Vec::new(),
+        })
+    }
+
+    /// Center frequencies of the channels relative to the input center frequency,
+    /// in units of FFT bins.
+    pub fn centers(&self) -> &[isize] {
+        &self.centers
+    }
+
+    /// Number of output samples produced from each FFT result for each channel
+    pub fn samples_per_fft(&self) -> usize {
+        // fixed 25% overlap
+        self.ifft.len() / 4 * 3
+    }
+
+    /// Produce all channels from the FFT results of a processing block.
+    pub fn process_block(
+        &mut self,
+        fft_results: &[&mut[Complex<f32>]],
+    ) {
+        let n = self.samples_per_fft() * self.centers.len();
+        self.samples.resize(n * fft_results.len(), Complex{ re: 0.0, im: 0.0 });
+        // Borrow samples separately from the rest of the state
+        let mut samples = std::mem::take(&mut self.samples);
+        let this = &*self;
+        samples.par_chunks_mut(n).zip(fft_results.par_iter()).enumerate().for_each(
+            |(i, (out, fft_result))| this.process(fft_result, this.ffts + i as u64, out));
+        self.samples = samples;
+        self.ffts += fft_results.len() as u64;
+    }
+
+    /// Samples of one channel from a given FFT result of the latest processing block
+    pub fn channel_samples(&self, fft_index: usize, channel: usize) -> &[Complex<f32>] {
+        let n = self.samples_per_fft();
+        let start = (fft_index * self.centers.len() + channel) * n;
+        &self.samples[start .. start + n]
+    }
+
+    /// Produce all channels from one FFT result.
+    fn process(
+        &self,
+        fft_result: &[Complex<f32>],
+        fft_number: u64,
+        output: &mut [Complex<f32>],
+    ) {
+        let ifft_size = self.ifft.len();
+        let half = ifft_size / 2;
+        let discard = ifft_size / 8;
+        let mut buf = vec![Complex{ re: 0.0, im: 0.0 }; ifft_size];
+        let mut scratch = vec![Complex{ re: 0.0, im: 0.0 }; self.ifft.get_inplace_scratch_len()];
+
+        for (&center, out) in self.centers.iter().zip(output.chunks_mut(self.samples_per_fft())) {
+            let rotation = quarter_turn_rotation(center, fft_number, -1.0);
+
+            for (i, b) in buf.iter_mut().enumerate() {
+                // Weight and input bin for output bin i, like in FilterDsp
+                let j = (i + half) % ifft_size;
+                *b = self.weights[j] * rotation *
+                    get_bin(fft_result, self.fft_size, center + j as isize - half as isize);
+            }
+            self.ifft.process_with_scratch(&mut buf, &mut scratch);
+
+            // fixed 25% overlap, discard 1/8 from each end
+            out.copy_from_slice(&buf[discard .. discard + out.len()]);
+        }
+    }
+}
+
+
+#[test]
+fn test_channelizer() {
+    // A tone in each channel should come out
+    // as a continuous tone in every channel.
+    let fft_info = FftInfo { fs: 102400.0, fc: 0.0, size: 1024, complex: true };
+    let fft_interval = fft_info.size / 4 * 3;
+    let mut ch = Channelizer::init(fft_info, &ChannelizerParams {
+        start: -3000.0,
+        stop: 3000.0,
+        spacing: 1500.0,
+        fs_out: 1600.0,
+        taps: None,
+        format: SignalFormat::Cf32,
+        gain: 0.0,
+        output: OutputParams { filename: None },
+    }).unwrap();
+    assert!(ch.centers() == [-30, -15, 0, 15, 30]);
+
+    let tone = |c: usize| -3000.0 + 1500.0 * c as f64 + 100.0;
+    let mut planner = FftPlanner::new();
+    let fft = planner.plan_fft_forward(fft_info.size);
+    let mut fft_results: Vec<Vec<Complex<f32>>> = (0..8).map(|b| {
+        let mut buf: Vec<Complex<f32>> = (0..fft_info.size).map(|i| {
+            let t = (b * fft_interval + i) as f64 / fft_info.fs;
+            (0..5).map(|c| {
+                let phase = 2.0 * std::f64::consts::PI * tone(c) * t;
+                Complex{ re: phase.cos() as f32, im: phase.sin() as f32 }
+            }).sum::<Complex<f32>>() / fft_info.size as f32
+        }).collect();
+        fft.process(&mut buf);
+        buf
+    }).collect();
+    let results: Vec<&mut [Complex<f32>]> = fft_results.iter_mut().map(|r| &mut r[..]).collect();
+
+    for block in results.chunks(4) {
+        ch.process_block(block);
+        for c in 0..5 {
+            let samples: Vec<Complex<f32>> = (0..block.len()).flat_map(|i| ch.channel_samples(i, c).to_vec()).collect();
+            let expected = 2.0 * std::f32::consts::PI * 100.0 / 1600.0;
+            for w in samples.windows(2) {
+                assert!((w[0].norm() - samples[0].norm()).abs() < 0.01);
+                assert!(((w[1] * w[0].conj()).arg() - expected).abs() < 0.01);
+            }
+        }
+    }
+}
@@ -35,21 +35,110 @@ pub struct SignalInfo {
     pub fs: f64,
     /// Center frequency
     pub fc: f64,
+    /// Sample format
+    pub format: SignalFormat,
 }
 
 
+/// Metadata for a record of signal data.
+/// This is placed after the common metadata.
+pub struct SignalMetadata {
+    /// Multiply samples by this to convert them
+    /// to the floating point values before output gain.
+    pub scale: f32,
+    /// Number of samples clipped in conversion to the sample format
+    pub saturated: u32,
+    /// Combination of SIGNAL_FLAG_* values
+    pub flags: u32,
+    /// Center frequency at the first sample of the record
+    /// relative to the center frequency in the topic, in Hz.
+    /// Only nonzero for tracking filters.
+    pub freq_offset: f32,
+}
+
+/// Filter has a squelch, so records are only published when it is open
+pub const SIGNAL_FLAG_SQUELCH: u32 = 0x1;
+/// Record is from before the squelch opened
+pub const SIGNAL_FLAG_PREROLL: u32 = 0x2;
+/// Signal has dropped but squelch is still open for its hang time
+pub const SIGNAL_FLAG_HANG:    u32 = 0x4;
+
+
+
 /// Information about spectrum data
 pub struct SpectrumInfo {
     /// Spacing of bins in frequency
     pub fd: f64,
     /// Frequency of the first bin
     pub f0: f64,
+    /// Code of the data format of the bins
+    pub format: u8,
+    /// A bin with value v has a power of v * db_scale + db_offset dB
+    pub db_scale: f32,
+    pub db_offset: f32,
+    pub fft_size: u32,
+    /// Number of FFTs averaged,
+    /// or time constant in FFTs for SPECTRUM_STATISTIC_EMA
+    pub averages: u32,
+    /// Window function, one of SPECTRUM_WINDOW_*
+    pub window: u8,
+    /// Statistic computed over the averaged FFTs, one of SPECTRUM_STATISTIC_*
+    pub statistic: u8,
+    /// Percentile from 0 to 100 for SPECTRUM_STATISTIC_PERCENTILE
+    pub percentile: u8,
+    /// Unit of the values, one of SPECTRUM_UNIT_*
+    pub unit: u8,
+    /// Whether a frequency-dependent gain table has been applied
+    pub gain_table: bool,
+    /// Power in dBm of a full-scale sinusoid used for calibration
+    pub fullscale_dbm: f32,
+    /// Equivalent noise bandwidth of an FFT bin in Hz
+    pub noise_bandwidth: f32,
+}
+
+/// Window functions given in spectrum topics
+pub const SPECTRUM_WINDOW_RECTANGULAR: u8 = 0;
+pub const SPECTRUM_WINDOW_HANN: u8 = 1;
+pub const SPECTRUM_WINDOW_BLACKMAN_HARRIS: u8 = 2;
+
+/// Statistics given in spectrum topics
+pub const SPECTRUM_STATISTIC_MEAN: u8 = 0;
+pub const SPECTRUM_STATISTIC_MAX: u8 = 1;
+pub const SPECTRUM_STATISTIC_MIN: u8 = 2;
+pub const SPECTRUM_STATISTIC_PERCENTILE: u8 = 3;
+pub const SPECTRUM_STATISTIC_EMA: u8 = 4;
+pub const SPECTRUM_STATISTIC_KURTOSIS: u8 = 5;
+
+/// Units of spectrum values
+pub const SPECTRUM_UNIT_DBFS: u8 = 0;
+pub const SPECTRUM_UNIT_DBM: u8 = 1;
+pub const SPECTRUM_UNIT_DBM_PER_HZ: u8 = 2;
+
+/// Information about demodulated audio
+pub struct AudioInfo {
+    /// Audio sample rate
+    pub fs: f64,
+    /// Frequency of the demodulated signal
+    pub fc: f64,
+    /// Number of interleaved audio channels
+    pub channels: u8,
+    /// Demodulator mode
+    pub mode: u8,
 }
 
 pub enum MessageType {
     Status   = 0x20,
     Waveform = 0x40,
     Spectrum = 0x60,
+    Audio    = 0x80,
+    Rds      = 0xA0,
+}
+
+/// Types of status messages
+pub enum StatusType {
+    Squelch    = 0x01,
+    Telemetry  = 0x02,
+    Detections = 0x03,
 }
 
 // Data format is encoded as:
@@ -62,7 +151,8 @@ pub enum MessageType {
 //   0 = signed two's complement integer (or fixed point)
 //   1 = float
 //   2 = unsigned integer (or fixed point)
-//   3 = reserved
+//   3 = block floating point, i.e. signed integers with a common
+//       scaling factor for each record given in its metadata
 // Next 3 bits: Number of bits per number
 //   0 = reserved
 //   1 = reserved
@@ -96,7 +186,44 @@ arg_enum! { // needed for command line parsing
 }
 
 
-const PROTOCOL_VERSION: u8 = 2;
+arg_enum! { // needed for command line parsing
+    /// Sample formats supported for filter bank outputs.
+    #[derive(Debug, Copy, Clone, PartialEq)]
+    pub enum SignalFormat { Cf32, Cs16, Cs8, Cs16bfp }
+}
+
+impl SignalFormat {
+    /// Code used for the format in the topic
+    pub fn code(self) -> u8 {
+        match self {
+            SignalFormat::Cf32    => DataFormat::Cf32le as u8,
+            SignalFormat::Cs16    => DataFormat::Cs16le as u8,
+            SignalFormat::Cs8     => DataFormat::Cs8 as u8,
+            SignalFormat::Cs16bfp => 0x78, // complex block floating point 16-bit, little endian
+        }
+    }
+
+    /// Format corresponding to a code in the topic
+    pub fn from_code(code: u8) -> Option<Self> {
+        [SignalFormat::Cf32, SignalFormat::Cs16, SignalFormat::Cs8, SignalFormat::Cs16bfp]
+            .iter().copied().find(|f| f.code() == code)
+    }
+
+    pub fn bytes_per_sample(self) -> usize {
+        match self {
+            SignalFormat::Cf32    => 8,
+            SignalFormat::Cs16    => 4,
+            SignalFormat::Cs8     => 2,
+            SignalFormat::Cs16bfp => 4,
+        }
+    }
+}
+
+
+const PROTOCOL_VERSION: u8 = 4;
+
+/// Size of the serialized common metadata
+pub const METADATA_SIZE: usize = 24;
 
 
 /// Serialize metadata for a single measurement record.
@@ -132,6 +259,144 @@ pub fn serialize_metadata(
 }
 
 
+/// Size of the serialized signal metadata
+pub const SIGNAL_METADATA_SIZE: usize = 16;
+
+/// Serialize metadata specific to a record of signal data.
+pub fn serialize_signal_metadata(
+    buf: &mut [u8],
+    offset: &mut usize,
+    metadata: &SignalMetadata,
+) -> byte::Result<()> {
+    buf.write_with(offset, metadata.scale, LE)?;
+    buf.write_with(offset, metadata.saturated, LE)?;
+    buf.write_with(offset, metadata.flags, LE)?;
+    buf.write_with(offset, metadata.freq_offset, LE)?;
+    Ok(())
+}
+
+
+/// Change the flags of an already serialized record of signal data.
+pub fn set_signal_flags(
+    record: &mut [u8],
+    flags: u32,
+) {
+    // Flags are after the common metadata, scale and saturated count
+    let mut offset = METADATA_SIZE + 8;
+    record.write_with(&mut offset, flags, LE).unwrap();
+}
+
+
+/// Serialize complex samples in a given sample format.
+///
+/// Samples are multiplied by gain and, for integer formats,
+/// scaled such that 1.0 corresponds to the largest integer value.
+/// For the block floating point format, the gain is ignored and
+/// the scaling is chosen such that the largest value in the block
+/// just fits in the integer range.
+///
+/// Return metadata describing the scaling and saturation.
+pub fn serialize_samples(
+    buf: &mut [u8],
+    offset: &mut usize,
+    samples: &[rustfft::num_complex::Complex<f32>],
+    format: SignalFormat,
+    gain: f32,
+) -> byte::Result<SignalMetadata> {
+    let mut saturated: u32 = 0;
+    // Write integers and count saturated samples.
+    // Scaling factor includes both the gain and the integer range.
+    let mut write_int = |buf: &mut [u8], offset: &mut usize, scaling: f32, max: f32| -> byte::Result<()> {
+        for v in samples.iter() {
+            let re = v.re * scaling;
+            let im = v.im * scaling;
+            if re.abs() > max || im.abs() > max {
+                saturated += 1;
+            }
+            let re = re.round().clamp(-max, max);
+            let im = im.round().clamp(-max, max);
+            if max > 127.0 {
+                buf.write_with(offset, re as i16, LE)?;
+                buf.write_with(offset, im as i16, LE)?;
+            } else {
+                buf.write_with(offset, re as i8, LE)?;
+                buf.write_with(offset, im as i8, LE)?;
+            }
+        }
+        Ok(())
+    };
+    let imax16 = i16::MAX as f32;
+    let imax8  = i8::MAX as f32;
+    let scale = match format {
+        SignalFormat::Cf32 => {
+            for v in samples.iter() {
+                buf.write_with(offset, v.re * gain, LE)?;
+                buf.write_with(offset, v.im * gain, LE)?;
+            }
+            1.0 / gain
+        },
+        SignalFormat::Cs16 => {
+            write_int(buf, offset, gain * imax16, imax16)?;
+            1.0 / (gain * imax16)
+        },
+        SignalFormat::Cs8 => {
+            write_int(buf, offset, gain * imax8, imax8)?;
+            1.0 / (gain * imax8)
+        },
+        SignalFormat::Cs16bfp => {
+            let peak = samples.iter().fold(0.0f32, |m, v| m.max(v.re.abs()).max(v.im.abs()));
+            // Avoid division by zero for an all-zero block
+            let peak = if peak > 0.0 { peak } else { 1.0 };
+            write_int(buf, offset, imax16 / peak, imax16)?;
+            peak / imax16
+        },
+    };
+    Ok(SignalMetadata {
+        scale,
+        saturated,
+        flags: 0,
+        freq_offset: 0.0,
+    })
+}
+
+
+/// Deserialize a record of signal data published by a filter.
+///
+/// Samples are appended to the output buffer, scaled back to the
+/// floating point values before output gain.
+/// Return the sequence number of the record.
+pub fn deserialize_signal_record(
+    record: &[u8],
+    format: SignalFormat,
+    samples: &mut Vec<rustfft::num_complex::Complex<f32>>,
+) -> byte::Result<u64> {
+    use rustfft::num_complex::Complex;
+    let mut offset = 0;
+    let seq: u64 = record.read_with(&mut offset, LE)?;
+    let mut offset = METADATA_SIZE;
+    let scale: f32 = record.read_with(&mut offset, LE)?;
+    let mut offset = METADATA_SIZE + SIGNAL_METADATA_SIZE;
+    while offset < record.len() {
+        let v = match format {
+            SignalFormat::Cf32 => Complex{
+                re: record.read_with::<f32>(&mut offset, LE)?,
+                im: record.read_with::<f32>(&mut offset, LE)?,
+            },
+            SignalFormat::Cs16 | SignalFormat::Cs16bfp => Complex{
+                re: record.read_with::<i16>(&mut offset, LE)? as f32,
+                im: record.read_with::<i16>(&mut offset, LE)? as f32,
+            },
+            SignalFormat::Cs8 => Complex{
+                re: record.read_with::<i8>(&mut offset, LE)? as f32,
+                im: record.read_with::<i8>(&mut offset, LE)? as f32,
+            },
+        };
+        samples.push(v * scale);
+    }
+    Ok(seq)
+}
+
+
 /// Serialize topic for signal data.
 /// The topic encodes sample rate and center frequency of the signal.
 pub fn serialize_signal_topic(
@@ -141,7 +406,7 @@ pub fn serialize_signal_topic(
 
     buf[0] = PROTOCOL_VERSION;
     buf[1] = MessageType::Waveform as u8;
-    buf[2] = DataFormat::Cf32le as u8;
+    buf[2] = info.format.code();
 
     let mut offset = 8;
     // These should always fit into the buffer, so unwrap only panics
@@ -153,19 +418,135 @@ pub fn serialize_signal_topic(
 }
 
 
+/// Serialize topic for demodulated audio.
+/// Audio samples are always 32-bit floats.
+pub fn serialize_audio_topic(
+    info:   &AudioInfo,
+) -> [u8; 24] {
+    let mut buf = [0u8; 24];
+
+    buf[0] = PROTOCOL_VERSION;
+    buf[1] = MessageType::Audio as u8;
+    buf[2] = DataFormat::F32le as u8;
+    buf[3] = info.channels;
+    buf[4] = info.mode;
+
+    let mut offset = 8;
+    buf.write_with(&mut offset, info.fs, LE).unwrap();
+    buf.write_with(&mut offset, info.fc, LE).unwrap();
+
+    buf
+}
+
+
+/// Serialize topic for squelch events of a filter.
+/// The topic encodes sample rate and center frequency of the filter.
+pub fn serialize_squelch_topic(
+    info:   &SignalInfo,
+) -> [u8; 24] {
+    let mut buf = [0u8; 24];
+
+    buf[0] = PROTOCOL_VERSION;
+    buf[1] = MessageType::Status as u8;
+    buf[2] = StatusType::Squelch as u8;
+
+    let mut offset = 8;
+    buf.write_with(&mut offset, info.fs, LE).unwrap();
+    buf.write_with(&mut offset, info.fc, LE).unwrap();
+
+    buf
+}
+
+
+/// Serialize topic for channel telemetry of all filters.
+pub fn serialize_telemetry_topic() -> [u8; 24] {
+    let mut buf = [0u8; 24];
+    buf[0] = PROTOCOL_VERSION;
+    buf[1] = MessageType::Status as u8;
+    buf[2] = StatusType::Telemetry as u8;
+    buf
+}
+
+
+/// Serialize topic for signals detected in spectrum data.
+/// The topic encodes the index of the spectrum product,
+/// since several products may have the same bins,
+/// and spacing and frequency of the first bin of the spectrum.
+pub fn serialize_detection_topic(
+    product: u8,
+    fd: f64,
+    f0: f64,
+) -> [u8; 24] {
+    let mut buf = [0u8; 24];
+
+    buf[0] = PROTOCOL_VERSION;
+    buf[1] = MessageType::Status as u8;
+    buf[2] = StatusType::Detections as u8;
+    buf[3] = product;
+
+    let mut offset = 8;
+    buf.write_with(&mut offset, fd, LE).unwrap();
+    buf.write_with(&mut offset, f0, LE).unwrap();
+
+    buf
+}
+
+
+/// Serialize topic for decoded RDS data.
+/// Frequency is that of the demodulated channel.
+pub fn serialize_rds_topic(
+    fs: f64,
+    fc: f64,
+) -> [u8; 24] {
+    let mut buf = [0u8; 24];
+
+    buf[0] = PROTOCOL_VERSION;
+    buf[1] = MessageType::Rds as u8;
+
+    let mut offset = 8;
+    buf.write_with(&mut offset, fs, LE).unwrap();
+    buf.write_with(&mut offset, fc, LE).unwrap();
+
+    buf
+}
+
+
+/// Size of the serialized spectrum topic
+pub const SPECTRUM_TOPIC_SIZE: usize = 48;
+
 /// Serialize topic for spectrum data.
+///
+/// Unlike other topics, a spectrum topic is longer than 24 bytes,
+/// so that it describes everything needed to decode the data:
+/// bytes 24-31 are FFT size and number of averages as u32,
+/// bytes 32-39 dB scale and offset as f32,
+/// bytes 40-47 full-scale power in dBm and noise bandwidth of a bin as f32.
+/// Window function is in byte 3, statistic in byte 4,
+/// percentile in byte 5, unit in byte 6 and
+/// whether a gain table has been applied in byte 7.
 pub fn serialize_spectrum_topic(
     info:   &SpectrumInfo,
-) -> [u8; 24] {
-    let mut buf = [0u8; 24];
+) -> [u8; SPECTRUM_TOPIC_SIZE] {
+    let mut buf = [0u8; SPECTRUM_TOPIC_SIZE];
 
     buf[0] = PROTOCOL_VERSION;
     buf[1] = MessageType::Spectrum as u8;
-    buf[2] = DataFormat::U8 as u8;
+    buf[2] = info.format;
+    buf[3] = info.window;
+    buf[4] = info.statistic;
+    buf[5] = info.percentile;
+    buf[6] = info.unit;
+    buf[7] = info.gain_table as u8;
 
     let mut offset = 8;
     buf.write_with(&mut offset, info.fd, LE).unwrap();
     buf.write_with(&mut offset, info.f0, LE).unwrap();
+    buf.write_with(&mut offset, info.fft_size, LE).unwrap();
+    buf.write_with(&mut offset, info.averages, LE).unwrap();
+    buf.write_with(&mut offset, info.db_scale, LE).unwrap();
+    buf.write_with(&mut offset, info.db_offset, LE).unwrap();
+    buf.write_with(&mut offset, info.fullscale_dbm, LE).unwrap();
+    buf.write_with(&mut offset, info.noise_bandwidth, LE).unwrap();
 
     buf
 }
@@ -0,0 +1,365 @@
+//! Demodulators producing audio from filter bank outputs
+//!
+//! These replace the demodulators in tools/demodulator.py,
+//! so that a large number of channels can be demodulated
+//! without running a separate process for each of them.
+
+use std::f32::consts::PI;
+use rustfft::num_complex::Complex;
+
+use super::fir::*;
+use super::output::OutputParams;
+use super::wbfm::Wbfm;
+
+arg_enum! { // needed for command line parsing
+    #[derive(Debug, Copy, Clone, PartialEq)]
+    pub enum DemodMode {
+        Am  = 1, // Envelope detection
+        Sam = 2, // Synchronous AM
+        Usb = 3,
+        Lsb = 4,
+        Fm  = 5, // Narrowband FM
+        Cw  = 6,
+        Wfm = 7, // Wideband FM broadcast with stereo and RDS
+    }
+}
+
+/// Demodulator parameters
+#[derive(Clone)]
+pub struct DemodParams {
+    pub mode: DemodMode,
+    pub fs_audio: f64, // Audio sample rate
+    pub bandwidth: Option<f32>, // Audio bandwidth, default depends on mode
+    pub bfo: f32, // BFO frequency for CW
+    pub deviation: f32, // FM deviation giving full scale audio
+    pub deemphasis: f32, // FM de-emphasis time constant in seconds, 0 to disable
+    pub agc: bool,
+    pub output: OutputParams,
+    pub rds_output: OutputParams, // Output for decoded RDS data
+}
+
+
+/// AGC for SSB and CW signals, ported from SsbAgc in tools/demodulator.py.
+pub struct SsbAgc {
+    attack: f32,
+    release: f32,
+    /// Average amplitude
+    pa: f32,
+}
+
+impl SsbAgc {
+    pub fn init(fs: f64) -> Self {
+        // The original constants were per sample at 8 kHz.
+        Self {
+            attack:  (0.002  * 8000.0 / fs) as f32,
+            release: (0.0002 * 8000.0 / fs) as f32,
+            pa: 0.0,
+        }
+    }
+
+    pub fn process(&mut self, s: Complex<f32>) -> Complex<f32> {
+        let clipthreshold = 0.9;
+        let amplitude = 0.25;
+        // Use amplitude instead of power (amplitude^2), so that short,
+        // high amplitude peaks won't affect the AGC that much.
+        let pd = s.norm() - self.pa;
+        self.pa += pd * if pd >= 0.0 { self.attack } else { self.release };
+
+        // Normalize the amplitude
+        let s = if self.pa > 0.0 { s * (amplitude / self.pa) } else { Complex{ re: 0.0, im: 0.0 } };
+
+        // Some samples may still be above 1, so clip them
+        let p = s.norm_sqr();
+        if p > clipthreshold { s * (clipthreshold / p).sqrt() } else { s }
+    }
+}
+
+
+/// AGC for AM signals, ported from AmAgc in tools/demodulator.py.
+/// Also removes the DC offset caused by the carrier.
+pub struct AmAgc {
+    speed: f32,
+    agc: bool,
+    /// Average amplitude
+    pa: f32,
+}
+
+impl AmAgc {
+    pub fn init(fs: f64, agc: bool) -> Self {
+        // The original constant was per sample at 16 kHz.
+        Self {
+            speed: (0.0002 * 16000.0 / fs) as f32,
+            agc,
+            pa: 0.0,
+        }
+    }
+
+    pub fn process(&mut self, s: f32) -> f32 {
+        let amplitude = 0.25;
+        self.pa += (s - self.pa) * self.speed;
+        if !self.agc {
+            s - self.pa
+        } else if self.pa > 0.0 {
+            // Normalize the amplitude and remove DC offset
+            s * (amplitude / self.pa) - amplitude
+        } else {
+            // this shouldn't happen often
+            0.0
+        }
+    }
+}
+
+
+/// Second order phase locked loop.
+pub struct Pll {
+    alpha: f32,
+    beta: f32,
+    /// Phase in radians
+    pub phase: f32,
+    /// Frequency in radians per sample
+    pub freq: f32,
+}
+
+impl Pll {
+    /// Loop bandwidth and initial frequency are given relative to the sample rate.
+    pub fn init(bandwidth: f32, freq: f32) -> Self {
+        let zeta = 0.707;
+        let wn = 2.0 * PI * bandwidth / (zeta + 1.0 / (4.0 * zeta));
+        Self {
+            alpha: 2.0 * zeta * wn,
+            beta: wn * wn,
+            phase: 0.0,
+            freq: 2.0 * PI * freq,
+        }
+    }
+
+    /// Update the loop with an input sample
+    /// and return the input mixed down by the current phase.
+    pub fn process(&mut self, s: Complex<f32>) -> Complex<f32> {
+        let mixed = s * Complex::from_polar(1.0, -self.phase);
+        self.update(mixed.arg());
+        mixed
+    }
+
+    /// Update the loop with a phase error from an external phase detector.
+    pub fn update(&mut self, error: f32) {
+        self.freq += self.beta * error;
+        self.phase = (self.phase + self.freq + self.alpha * error) % (2.0 * PI);
+    }
+}
+
+
+/// Single pole lowpass filter used for de-emphasis
+pub struct Deemphasis {
+    a: f32,
+    y: f32,
+}
+
+impl Deemphasis {
+    pub fn init(fs: f64, tau: f32) -> Self {
+        Self {
+            a: if tau > 0.0 { 1.0 - (-1.0 / (fs as f32 * tau)).exp() } else { 1.0 },
+            y: 0.0,
+        }
+    }
+
+    pub fn process(&mut self, x: f32) -> f32 {
+        self.y += self.a * (x - self.y);
+        self.y
+    }
+}
+
+
+/// Demodulator state
+pub struct Demodulator {
+    mode: DemodMode,
+    /// Resampler from channel sample rate to audio sample rate.
+    /// AM, SSB and CW are resampled before demodulation.
+    resampler: Option<Resampler<Complex<f32>>>,
+    /// FM is resampled after demodulation.
+    audio_resampler: Option<Resampler<f32>>,
+    /// Sideband or CW filter at the audio sample rate
+    fir: Option<ComplexFir>,
+    /// Wideband FM has a completely separate implementation
+    pub wbfm: Option<Wbfm>,
+    ssb_agc: Option<SsbAgc>,
+    am_agc: AmAgc,
+    pll: Pll,
+    deemphasis: Deemphasis,
+    /// BFO phase for CW, in radians
+    bfo_phase: f32,
+    bfo_freq: f32,
+    /// Previous sample for FM discriminator
+    prev: Complex<f32>,
+    fm_scaling: f32,
+
+    // Pre-allocated buffers
+    buf1: Vec<Complex<f32>>,
+    buf2: Vec<Complex<f32>>,
+    fbuf: Vec<f32>,
+}
+
+impl Demodulator {
+    pub fn init(
+        fs_in: f64, // Sample rate of the channel
+        p: &DemodParams,
+    ) -> Result<Self, String> {
+        let fs_audio = p.fs_audio;
+        let numtaps = (fs_audio / 1000.0 * 16.0).round() as usize | 1;
+        let fir = match p.mode {
+            DemodMode::Usb | DemodMode::Lsb => {
+                // Pass audio between 300 Hz and the given bandwidth
+                let bw = p.bandwidth.unwrap_or(2700.0) as f64;
+                let center = (bw + 300.0) / 2.0 / fs_audio;
+                let center = if p.mode == DemodMode::Usb { center } else { -center };
+                Some(ComplexFir::init(&bandpass(numtaps, (bw - 300.0) / fs_audio, center)))
+            },
+            DemodMode::Cw => {
+                let bw = p.bandwidth.unwrap_or(500.0) as f64;
+                Some(ComplexFir::init(&bandpass(numtaps, bw / fs_audio, 0.0)))
+            },
+            _ => None,
+        };
+        let (resampler, audio_resampler) = match p.mode {
+            DemodMode::Fm  => (None, Some(Resampler::init(fs_in, fs_audio)?)),
+            DemodMode::Wfm => (None, None),
+            _              => (Some(Resampler::init(fs_in, fs_audio)?), None),
+        };
+        Ok(Self {
+            mode: p.mode,
+            resampler,
+            audio_resampler,
+            fir,
+            wbfm: if p.mode == DemodMode::Wfm {
+                Some(Wbfm::init(fs_in, fs_audio, p.deemphasis)?)
+            } else {
+                None
+            },
+            ssb_agc: if p.agc { Some(SsbAgc::init(fs_audio)) } else { None },
+            am_agc: AmAgc::init(fs_audio, p.agc),
+            // Synchronous AM tracks a carrier within the channel
+            pll: Pll::init((50.0 / fs_audio) as f32, 0.0),
+            deemphasis: Deemphasis::init(fs_in, p.deemphasis),
+            bfo_phase: 0.0,
+            bfo_freq: (2.0 * std::f64::consts::PI * p.bfo as f64 / fs_audio) as f32,
+            prev: Complex{ re: 0.0, im: 0.0 },
+            fm_scaling: (fs_in / (2.0 * std::f64::consts::PI * p.deviation as f64)) as f32,
+            buf1: Vec::new(),
+            buf2: Vec::new(),
+            fbuf: Vec::new(),
+        })
+    }
+
+    /// Number of audio samples produced at most
+    /// for a given number of input samples.
+    pub fn max_output(&self, input_samples: usize) -> usize {
+        match (&self.resampler, &self.audio_resampler, &self.wbfm) {
+            (Some(r), _, _) => r.max_output(input_samples),
+            (_, Some(r), _) => r.max_output(input_samples),
+            (_, _, Some(w)) => w.max_output(input_samples),
+            _ => input_samples,
+        }
+    }
+
+    /// Number of interleaved audio channels in the output
+    pub fn channels(&self) -> u8 {
+        if self.wbfm.is_some() { 2 } else { 1 }
+    }
+
+    /// Demodulate a block of samples.
+    /// Output buffer is replaced with the resulting audio.
+    pub fn process(&mut self, input: &[Complex<f32>], output: &mut Vec<f32>) {
+        output.clear();
+        if self.mode == DemodMode::Fm {
+            self.process_fm(input, output);
+            return;
+        }
+        if let Some(wbfm) = &mut self.wbfm {
+            wbfm.process(input, output);
+            return;
+        }
+
+        self.buf1.clear();
+        if let Some(r) = &mut self.resampler {
+            r.process(input, &mut self.buf1);
+        }
+        if let Some(fir) = &mut self.fir {
+            self.buf2.clear();
+            fir.process(&self.buf1, &mut self.buf2);
+            std::mem::swap(&mut self.buf1, &mut self.buf2);
+        }
+
+        match self.mode {
+            DemodMode::Am => {
+                let agc = &mut self.am_agc;
+                output.extend(self.buf1.iter().map(|s| agc.process(s.norm())));
+            },
+            DemodMode::Sam => {
+                let (agc, pll) = (&mut self.am_agc, &mut self.pll);
+                output.extend(self.buf1.iter().map(|&s| agc.process(pll.process(s).re)));
+            },
+            DemodMode::Cw => {
+                for s in self.buf1.iter_mut() {
+                    *s *= Complex::from_polar(1.0, self.bfo_phase);
+                    self.bfo_phase = (self.bfo_phase + self.bfo_freq) % (2.0 * PI);
+                }
+                self.ssb_output(output);
+            },
+            DemodMode::Usb | DemodMode::Lsb => {
+                self.ssb_output(output);
+            },
+            DemodMode::Fm | DemodMode::Wfm => {},
+        }
+    }
+
+    /// Apply AGC to an SSB or CW signal and take the real part.
+    fn ssb_output(&mut self, output: &mut Vec<f32>) {
+        match &mut self.ssb_agc {
+            Some(agc) => output.extend(self.buf1.iter().map(|&s| agc.process(s).re)),
+            None      => output.extend(self.buf1.iter().map(|s| s.re)),
+        }
+    }
+
+    fn process_fm(&mut self, input: &[Complex<f32>], output: &mut Vec<f32>) {
+        self.fbuf.clear();
+        for &s in input.iter() {
+            let d = (s * self.prev.conj()).arg() * self.fm_scaling;
+            self.prev = s;
+            self.fbuf.push(self.deemphasis.process(d));
+        }
+        if let Some(r) = &mut self.audio_resampler {
+            r.process(&self.fbuf, output);
+        }
+    }
+}
+
+
+#[test]
+fn test_ssb_demodulator() {
+    // A tone 1 kHz above the channel center frequency
+    // should be heard in USB but not in LSB.
+    let input: Vec<Complex<f32>> = (0..16000).map(|i| {
+        Complex::from_polar(1.0, 2.0 * PI * 1000.0 * i as f32 / 16000.0)
+    }).collect();
+    let rms = |mode: DemodMode| -> f32 {
+        let mut demod = Demodulator::init(16000.0, &DemodParams {
+            mode,
+            fs_audio: 8000.0,
+            bandwidth: None,
+            bfo: 0.0,
+            deviation: 5000.0,
+            deemphasis: 0.0,
+            agc: false,
+            output: OutputParams { filename: None },
+            rds_output: OutputParams { filename: None },
+        }).unwrap();
+        let mut output = Vec::new();
+        demod.process(&input, &mut output);
+        assert!(output.len() == 8000);
+        // Skip the filter transients in the beginning
+        let audio = &output[1000..];
+        (audio.iter().map(|v| v * v).sum::<f32>() / audio.len() as f32).sqrt()
+    };
+    assert!((rms(DemodMode::Usb) - 0.5f32.sqrt()).abs() < 0.02);
+    assert!(rms(DemodMode::Lsb) < 0.01);
+}
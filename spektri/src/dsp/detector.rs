@@ -0,0 +1,240 @@
+//! Signal detection on averaged spectra
+//!
+//! The noise floor of a spectrum is estimated over regions of bins,
+//! either as the median of each region, interpolated between
+//! the centers of the regions, or as a morphological opening
+//! (a running minimum followed by a running maximum) which removes
+//! peaks narrower than a region. The opening follows the lower edge
+//! of the noise, so it gives a somewhat lower floor than the median.
+//!
+//! Bins exceeding the noise floor by a threshold are detected
+//! and adjacent detected bins are merged into one signal.
+//! Power of a signal is summed over its bins in linear scale.
+//! Each bin also contains power from its neighbours, according to
+//! the equivalent noise bandwidth (ENBW) of the window, so the sum
+//! is scaled by bin spacing / ENBW to give the total power.
+//! For spectral density, the sum is scaled by bin spacing instead.
+//! Detections are published once for each spectrum record.
+
+use byte::{BytesExt, LE};
+
+use super::data::*;
+use super::output::*;
+use super::Metadata;
+
+arg_enum! { // needed for command line parsing
+    /// Methods for estimating the noise floor
+    #[derive(Debug, Copy, Clone, PartialEq)]
+    pub enum NoiseFloorMethod { Median, Morphological }
+}
+
+/// Detector parameters
+#[derive(Clone)]
+pub struct DetectorParams {
+    pub method: NoiseFloorMethod,
+    pub region: usize, // Number of bins in each noise floor region
+    pub snr: f32, // Threshold above noise floor in dB
+    pub output: OutputParams,
+}
+
+/// A detected signal
+#[derive(Copy, Clone, Debug)]
+pub struct Detection {
+    /// Center frequency, weighted by power of the bins
+    pub fc: f64,
+    /// Bandwidth of the detected bins
+    pub bw: f64,
+    /// Total power of the detected signal in dB
+    pub power: f32,
+    /// Value of the strongest bin in dB
+    pub peak: f32,
+    /// Total noise floor power over the bandwidth of the signal in dB
+    pub noise: f32,
+    /// Ratio of signal power to noise floor power in dB
+    pub snr: f32,
+}
+
+impl Detection {
+    /// Serialize as an entry of a detection record.
+    fn serialize(
+        &self,
+        buf: &mut [u8],
+        offset: &mut usize,
+    ) -> byte::Result<()> {
+        buf.write_with(offset, self.fc, LE)?;
+        buf.write_with(offset, self.bw, LE)?;
+        buf.write_with(offset, self.power, LE)?;
+        buf.write_with(offset, self.peak, LE)?;
+        buf.write_with(offset, self.noise, LE)?;
+        buf.write_with(offset, self.snr, LE)?;
+        Ok(())
+    }
+}
+
+/// Size of the header of a detection record after the common metadata
+pub const DETECTION_HEADER_SIZE: usize = 8;
+/// Size of each entry in a detection record
+pub const DETECTION_ENTRY_SIZE: usize = 32;
+
+pub struct Detector {
+    params: DetectorParams,
+    /// Frequency of the first bin
+    f0: f64,
+    /// Spacing of bins
+    fd: f64,
+    /// Scaling from a sum of bins to total power
+    power_scale: f32,
+    output: Output,
+}
+
+impl Detector {
+    /// product is the index of the spectrum product, used in the topic.
+    /// power_scale is bin spacing / ENBW for power
+    /// and bin spacing for spectral density.
+    pub fn init(
+        params: &DetectorParams,
+        product: usize,
+        f0: f64,
+        fd: f64,
+        power_scale: f64,
+    ) -> Self {
+        Self {
+            params: params.clone(),
+            f0,
+            fd,
+            power_scale: power_scale as f32,
+            output: Output::init(&params.output, &serialize_detection_topic(product as u8, fd, f0)),
+        }
+    }
+
+    /// Detect signals in a spectrum given in dB in ascending
+    /// frequency order and write a record of the detections.
+    pub fn process(
+        &mut self,
+        db: &[f32],
+        metadata: &Metadata,
+        seq: u64,
+        sock: &zmq::Socket,
+    ) {
+        let floor = noise_floor(db, self.params.method, self.params.region);
+        let detections = detect(db, &floor, self.params.snr, self.f0, self.fd, self.power_scale);
+
+        let mut buf = vec![0u8; METADATA_SIZE + DETECTION_HEADER_SIZE + detections.len() * DETECTION_ENTRY_SIZE];
+        let mut offset = 0;
+        // Buffer is allocated for everything above, so these do not fail
+        serialize_metadata(&mut buf, &mut offset, metadata, seq).unwrap();
+        buf.write_with(&mut offset, detections.len() as u32, LE).unwrap();
+        // Reserved
+        buf.write_with(&mut offset, 0u32, LE).unwrap();
+        for detection in detections.iter() {
+            detection.serialize(&mut buf, &mut offset).unwrap();
+        }
+        if let Err(err) = self.output.write(&buf, sock) {
+            eprintln!("Error writing detector output: {}", err);
+        }
+    }
+}
+
+/// Estimate the noise floor in dB for each bin.
+/// Bins which are NaN, e.g. with no data, are ignored.
+pub fn noise_floor(
+    db: &[f32],
+    method: NoiseFloorMethod,
+    region: usize,
+) -> Vec<f32> {
+    let region = region.clamp(1, db.len().max(1));
+    match method {
+        NoiseFloorMethod::Median => {
+            let medians: Vec<f32> = db.chunks(region).map(|r| {
+                let mut r: Vec<f32> = r.iter().copied().filter(|v| !v.is_nan()).collect();
+                r.sort_by(|a, b| a.total_cmp(b));
+                r.get(r.len() / 2).copied().unwrap_or(f32::NAN)
+            }).collect();
+            // Interpolate linearly between the centers of the regions
+            let center = |i: usize| (i * region) as f32 + (region - 1) as f32 / 2.0;
+            (0..db.len()).map(|k| {
+                let x = (k as f32 - center(0)) / region as f32;
+                let i = (x.floor().max(0.0) as usize).min(medians.len() - 1);
+                let j = (i + 1).min(medians.len() - 1);
+                let t = (x - i as f32).clamp(0.0, 1.0);
+                medians[i] + (medians[j] - medians[i]) * t
+            }).collect()
+        },
+        NoiseFloorMethod::Morphological => {
+            let half = region / 2;
+            let window = |k: usize| k.saturating_sub(half) .. (k + half + 1).min(db.len());
+            let eroded: Vec<f32> = (0..db.len()).map(|k|
+                db[window(k)].iter().fold(f32::INFINITY, |a, &b| if b.is_nan() { a } else { a.min(b) })
+            ).collect();
+            (0..db.len()).map(|k|
+                eroded[window(k)].iter().fold(f32::NEG_INFINITY, |a, &b| a.max(b))
+            ).collect()
+        },
+    }
+}
+
+/// Find signals exceeding the noise floor by snr dB.
+/// Adjacent bins above the threshold are merged into one detection.
+/// Sums of bins are multiplied by power_scale.
+pub fn detect(
+    db: &[f32],
+    floor: &[f32],
+    snr: f32,
+    f0: f64,
+    fd: f64,
+    power_scale: f32,
+) -> Vec<Detection> {
+    let lin = |v: f32| 10.0f32.powf(v / 10.0);
+    // NaN compares as false, so those are never detected
+    let above = |k: usize| db[k] - floor[k] > snr;
+    let mut detections = Vec::new();
+    let mut k = 0;
+    while k < db.len() {
+        if !above(k) {
+            k += 1;
+            continue;
+        }
+        let start = k;
+        while k < db.len() && above(k) {
+            k += 1;
+        }
+        let bins = start..k;
+        let power: f32 = db[bins.clone()].iter().map(|&v| lin(v)).sum::<f32>() * power_scale;
+        let noise: f32 = floor[bins.clone()].iter().map(|&v| lin(v)).sum::<f32>() * power_scale;
+        let centroid = bins.clone().map(|i| i as f64 * lin(db[i]) as f64).sum::<f64>() / (power / power_scale) as f64;
+        detections.push(Detection {
+            fc: f0 + centroid * fd,
+            bw: bins.len() as f64 * fd,
+            power: 10.0 * power.log10(),
+            peak: db[bins].iter().fold(f32::NEG_INFINITY, |a, &b| a.max(b)),
+            noise: 10.0 * noise.log10(),
+            snr: 10.0 * (power / noise).log10(),
+        });
+    }
+    detections
+}
+
+
+#[test]
+fn test_detector() {
+    // Flat noise floor at -100 dB with two signals
+    // and a region of bins without data
+    let mut db = vec![-100.0f32; 256];
+    db[40] = -80.0;
+    db[41] = -80.0;
+    db[200] = -70.0;
+    db[100..110].iter_mut().for_each(|v| *v = f32::NAN);
+    for method in [NoiseFloorMethod::Median, NoiseFloorMethod::Morphological] {
+        let floor = noise_floor(&db, method, 32);
+        assert!(floor.iter().all(|&v| v == -100.0), "{:?}", method);
+        // Window with an ENBW of 2 bins
+        let detections = detect(&db, &floor, 10.0, 1000.0, 10.0, 0.5);
+        assert!(detections.len() == 2);
+        let d = detections[0];
+        assert!(d.fc == 1405.0 && d.bw == 20.0);
+        assert!((d.power + 80.0).abs() < 1e-3);
+        assert!((d.snr - 20.0).abs() < 1e-3 && d.peak == -80.0);
+        assert!(detections[1].fc == 3000.0);
+        assert!((detections[1].power - (-70.0 - 10.0 * 2.0f32.log10())).abs() < 1e-3);
+    }
+}
@@ -1,13 +1,24 @@
 //! Fast-convolution filter bank
 
+use std::collections::VecDeque;
 use std::error::Error;
 use rayon::prelude::*;
 use rustfft::{FftPlanner, num_complex::Complex};
 use zmq;
 
 use super::data::*;
+use super::channelizer::*;
+use super::demod::*;
 use super::fftutil::*;
+use super::history::History;
+use super::multiband::*;
 use super::output::*;
+use super::rds::RdsDecoder;
+use super::schedule::*;
+use super::squelch::*;
+use super::tracking::*;
+use super::ssb::*;
+use super::telemetry::*;
 use super::Metadata;
 
 // ------------------------------------------------------
@@ -17,50 +28,706 @@ use super::Metadata;
 /// Bank of filters
 pub struct Fcfb {
     fft_info: FftInfo,
+    ffts_per_buf: usize,
     filters: Vec<Filter>,
+    ssb_receivers: Vec<SsbReceiver>,
+    channelizers: Vec<ChannelizerOutput>,
+    multibands: Vec<MultibandOutput>,
+    telemetry: Option<TelemetryOutput>,
+    /// Filters which are created and removed according to a schedule
+    scheduled: Vec<ScheduledFilter>,
+    /// FFT results of recent processing blocks
+    history: Option<History>,
+    /// Duration of a processing block in seconds
+    block_duration: f64,
+    /// A filter has triggered a snapshot of the input signal
+    snapshot_triggered: bool,
+}
+
+/// Number of blocks from the history processed for each new block
+/// by a filter catching up with live processing
+const CATCHUP_BLOCKS: usize = 4;
+
+/// Filter waiting for its scheduled time
+struct ScheduledFilter {
+    params: FilterParams,
+    schedule: Schedule,
+    /// Time of the first processing block after the filter was added
+    first_used: Option<f64>,
+    /// Stop time of the currently running filter, if any
+    running_until: Option<f64>,
+}
+
+/// Channelizer and outputs of its channels
+struct ChannelizerOutput {
+    dsp: Channelizer,
+    format: SignalFormat,
+    gain: f32,
+    channels: Vec<ChannelOutput>,
+}
+
+/// Multi-band filter and its output
+struct MultibandOutput {
+    dsp: MultibandDsp,
+    format: SignalFormat,
+    gain: f32,
+    samples: Vec<Complex<f32>>,
+    outbuf: Vec<u8>,
+    outsize: usize,
+    output: Output,
+}
+
+struct ChannelOutput {
+    samples: Vec<Complex<f32>>,
+    outbuf: Vec<u8>,
+    outsize: usize,
+    output: Output,
+}
+
+/// Telemetry record combining all filters
+struct TelemetryOutput {
+    /// Number of processing blocks between telemetry records
+    interval: u64,
+    /// Number of processing blocks until next record
+    blocks_left: u64,
+    outbuf: Vec<u8>,
+    output: Output,
 }
 
 /// One filter
 pub struct Filter {
     dsp: FilterDsp,
+    samples: Vec<Complex<f32>>, // Filtered signal for one processing block
+    format: SignalFormat,
+    gain: f32,
     outbuf: Vec<u8>,
     outsize: usize,
     output: Output,
+    demod: Option<DemodOutput>,
+    squelch: Option<FilterSquelch>,
+    /// Should the record of this processing block be published
+    publish: bool,
+    telemetry: Option<ChannelTelemetry>,
+    /// Time in seconds since the Unix epoch when the filter is removed
+    stop_time: Option<f64>,
+    /// Name used to refer to the filter through the control interface
+    name: Option<String>,
+    tracking: Option<Tracker>,
+    /// Number of blocks to process from the history
+    /// before the first block is processed
+    history_blocks: Option<usize>,
+    /// Sequence number of the next block to process from the history
+    /// while catching up with live processing
+    history_seq: Option<u64>,
+    /// Trigger a snapshot of the input signal when squelch opens
+    snapshot: bool,
+}
+
+impl Filter {
+    /// Process one processing block and serialize its records.
+    fn process(
+        &mut self,
+        fft_results: &[&mut[Complex<f32>]],
+        metadata: &Metadata,
+        update_telemetry: bool,
+    ) {
+        let now = unix_time(metadata.systemtime);
+        let mut offset = 0;
+        // Sequence number of can be the same as metadata.seq because
+        // one record is produced for each processing block.
+        // This also results in common sequence numbering for all filters
+        // which is convenient for applications requiring
+        // synchronized signals from multiple filters.
+        //
+        // unwraps are OK here because outbuf is allocated
+        // to fit a whole processing block in start_filter,
+        // so they would only panic if there is a bug.
+        serialize_metadata(&mut self.outbuf, &mut offset, metadata, metadata.seq).unwrap();
+        let samples_per_fft = self.dsp.samples_per_fft();
+        let offsets = self.tracking.as_mut().map(|t| t.begin_block(fft_results.len(), samples_per_fft, now));
+        self.dsp.process_block(fft_results, &mut self.samples,
+            self.telemetry.as_mut().map(|t| &mut t.bin_power), offsets);
+        if let Some(tracking) = &mut self.tracking {
+            tracking.mix(&mut self.samples, samples_per_fft);
+        }
+        if let Some(telemetry) = &mut self.telemetry {
+            telemetry.accumulate(&self.samples);
+            if update_telemetry {
+                telemetry.update();
+            }
+        }
+        // Signal metadata depends on the samples,
+        // so write the samples first and then go back to write metadata.
+        let mut metadata_offset = offset;
+        offset += SIGNAL_METADATA_SIZE;
+        let mut signal_metadata = serialize_samples(
+            &mut self.outbuf, &mut offset, &self.samples, self.format, self.gain).unwrap();
+        if let Some(tracking) = &self.tracking {
+            signal_metadata.freq_offset = tracking.freq_offset() as f32;
+        }
+        serialize_signal_metadata(&mut self.outbuf, &mut metadata_offset, &signal_metadata).unwrap();
+        self.outsize = offset;
+
+        if let Some(demod) = &mut self.demod {
+            demod.process(&self.samples, metadata);
+        }
+
+        if let Some(squelch) = &mut self.squelch {
+            let audio = match &self.demod {
+                Some(demod) => &demod.outbuf[0..demod.outsize],
+                None => &[],
+            };
+            self.publish = squelch.process(
+                &self.samples, self.gain, metadata,
+                &mut self.outbuf[0..self.outsize], audio);
+        }
+    }
+
+    /// Write the records of the latest processing block.
+    fn write(&mut self, sock: &zmq::Socket) {
+        if let Some(squelch) = &mut self.squelch {
+            if squelch.eventsize > 0 {
+                if let Err(err) = squelch.output.write(&squelch.eventbuf[0..squelch.eventsize], sock) {
+                    eprintln!("Error writing squelch event: {}", err);
+                }
+            }
+            if squelch.opening {
+                for (record, audio) in squelch.preroll.drain(..) {
+                    if let Err(err) = self.output.write(&record, sock) {
+                        eprintln!("Error writing filter output: {}", err);
+                    }
+                    if let Some(demod) = &mut self.demod {
+                        if let Err(err) = demod.output.write(&audio, sock) {
+                            eprintln!("Error writing demodulator output: {}", err);
+                        }
+                    }
+                }
+            }
+        }
+        if self.publish {
+            if let Err(err) = self.output.write(&self.outbuf[0..self.outsize], sock) {
+                eprintln!("Error writing filter output: {}", err);
+            }
+        }
+        if let Some(demod) = &mut self.demod {
+            demod.write(sock, self.publish);
+        }
+    }
+
+    /// Process blocks from the history until the filter has caught up
+    /// with the current block, at most CATCHUP_BLOCKS at a time.
+    fn catch_up(&mut self, history: &mut History, sock: &zmq::Socket) {
+        let current = match history.latest_seq() {
+            Some(seq) => seq,
+            None => return,
+        };
+        if let Some(blocks) = self.history_blocks.take() {
+            self.history_seq = Some(current.saturating_sub(blocks as u64));
+        }
+        let mut processed = 0;
+        while let Some(seq) = self.history_seq {
+            if processed == CATCHUP_BLOCKS {
+                break;
+            }
+            if seq >= current {
+                // Current block is processed like in other filters
+                self.history_seq = None;
+                break;
+            }
+            match history.get(seq) {
+                Some((fft_results, metadata)) => {
+                    self.process(&fft_results, &metadata, false);
+                    self.write(sock);
+                    processed += 1;
+                },
+                None => {
+                    // Block is no longer in history.
+                    // Skip to the oldest one there is.
+                    if let Some(oldest) = history.oldest_seq() {
+                        eprintln!("Skipping blocks {}-{} not in history", seq, oldest - 1);
+                        self.history_seq = Some(oldest);
+                        continue;
+                    }
+                },
+            }
+            self.history_seq = Some(seq + 1);
+        }
+    }
+}
+
+/// SSB receiver producing real-valued audio directly from FFT results
+pub struct SsbReceiver {
+    dsp: SsbDsp,
+    audio: Vec<f32>,
+    gain: f32,
+    outbuf: Vec<u8>,
+    outsize: usize,
+    output: Output,
+}
+
+/// Squelch attached to a filter
+struct FilterSquelch {
+    squelch: Squelch,
+    /// Records of the filter and its demodulator from the latest
+    /// processing blocks while squelch was closed
+    preroll: VecDeque<(Vec<u8>, Vec<u8>)>,
+    /// Squelch opened in this processing block,
+    /// so pre-roll records should be published
+    opening: bool,
+    eventbuf: Vec<u8>,
+    eventsize: usize,
+    output: Output,
+}
+
+impl FilterSquelch {
+    /// Update squelch with the samples of a processing block
+    /// and set the flags of the serialized records.
+    /// Return whether the records should be published.
+    fn process(
+        &mut self,
+        samples: &[Complex<f32>],
+        gain: f32,
+        metadata: &Metadata,
+        record: &mut [u8],
+        audio: &[u8], // Demodulator record, empty if there is no demodulator
+    ) -> bool {
+        let power = samples.iter().map(|s| s.norm_sqr()).sum::<f32>()
+            / samples.len().max(1) as f32 * gain * gain;
+        let was_open = self.squelch.is_open();
+        let state = self.squelch.update(power);
+        let open = self.squelch.is_open();
+        self.opening = open && !was_open;
+
+        self.eventsize = 0;
+        if open != was_open {
+            let mut offset = 0;
+            self.squelch.serialize_event(&mut self.eventbuf, &mut offset, metadata).unwrap();
+            self.eventsize = offset;
+        }
+
+        let flags = SIGNAL_FLAG_SQUELCH | if state == SquelchState::Hang { SIGNAL_FLAG_HANG } else { 0 };
+        set_signal_flags(record, flags);
+
+        if !open && self.squelch.preroll_blocks > 0 {
+            // Reuse the buffers of the oldest records
+            let (mut r, mut a) = if self.preroll.len() >= self.squelch.preroll_blocks {
+                self.preroll.pop_front().unwrap()
+            } else {
+                (Vec::new(), Vec::new())
+            };
+            r.clear();
+            r.extend_from_slice(record);
+            set_signal_flags(&mut r, SIGNAL_FLAG_SQUELCH | SIGNAL_FLAG_PREROLL);
+            a.clear();
+            a.extend_from_slice(audio);
+            self.preroll.push_back((r, a));
+        }
+        open
+    }
+}
+
+/// Demodulator attached to a filter and its output
+struct DemodOutput {
+    demod: Demodulator,
+    audio: Vec<f32>,
+    outbuf: Vec<u8>,
+    outsize: usize,
+    output: Output,
+    /// Decoded RDS data, only for wideband FM
+    rds: Option<RdsOutput>,
+}
+
+struct RdsOutput {
+    outbuf: Vec<u8>,
+    outsize: usize,
+    output: Output,
+}
+
+impl DemodOutput {
+    fn process(
+        &mut self,
+        samples: &[Complex<f32>],
+        metadata: &Metadata,
+    ) {
+        self.demod.process(samples, &mut self.audio);
+        let mut offset = 0;
+        // unwraps are OK since outbuf is allocated to fit a whole block.
+        serialize_metadata(&mut self.outbuf, &mut offset, metadata, metadata.seq).unwrap();
+        use byte::*;
+        for &v in self.audio.iter() {
+            self.outbuf.write_with(&mut offset, v, LE).unwrap();
+        }
+        self.outsize = offset;
+
+        if let (Some(rds), Some(wbfm)) = (&mut self.rds, &mut self.demod.wbfm) {
+            // Only publish a record when new groups were received
+            rds.outsize = 0;
+            if !wbfm.rds.groups.is_empty() {
+                let mut offset = 0;
+                serialize_metadata(&mut rds.outbuf, &mut offset, metadata, metadata.seq).unwrap();
+                wbfm.rds.serialize(&mut rds.outbuf, &mut offset, wbfm.stereo()).unwrap();
+                rds.outsize = offset;
+                wbfm.rds.clear_groups();
+            }
+        }
+    }
+
+    fn write(
+        &mut self,
+        sock: &zmq::Socket,
+        publish: bool, // Publish audio, false if squelch is closed
+    ) {
+        if publish {
+            if let Err(err) = self.output.write(&self.outbuf[0..self.outsize], sock) {
+                eprintln!("Error writing demodulator output: {}", err);
+            }
+        }
+        if let Some(rds) = &mut self.rds {
+            if rds.outsize > 0 {
+                if let Err(err) = rds.output.write(&rds.outbuf[0..rds.outsize], sock) {
+                    eprintln!("Error writing RDS output: {}", err);
+                }
+            }
+        }
+    }
 }
 
 impl Fcfb {
     pub fn init(
         fft_info: FftInfo,
+        ffts_per_buf: usize, // Number of FFT results in each processing block
+        telemetry: Option<&TelemetryParams>,
+        history: f64, // Seconds of FFT results to keep, 0 to disable
     ) -> Self {
+        // Duration of a processing block in seconds
+        let block_duration = (fft_info.size / 4 * 3 * ffts_per_buf) as f64 / fft_info.fs;
+        let history = if history > 0.0 {
+            let h = History::init((history / block_duration).ceil() as usize);
+            let result_bins = if fft_info.complex { fft_info.size } else { fft_info.size / 2 + 1 };
+            eprintln!("Keeping {} s of FFT results in history, using {:.1} MiB of memory",
+                history, h.memory_size(result_bins * ffts_per_buf) as f64 / 1048576.0);
+            Some(h)
+        } else {
+            None
+        };
         Self {
             fft_info: fft_info,
+            ffts_per_buf,
             filters: Vec::new(),
+            ssb_receivers: Vec::new(),
+            channelizers: Vec::new(),
+            multibands: Vec::new(),
+            scheduled: Vec::new(),
+            history,
+            block_duration,
+            snapshot_triggered: false,
+            telemetry: telemetry.map(|t| {
+                let interval = ((1.0 / (t.rate * block_duration)).round() as u64).max(1);
+                TelemetryOutput {
+                    interval,
+                    blocks_left: interval,
+                    outbuf: Vec::new(),
+                    output: Output::init(&t.output, &serialize_telemetry_topic()),
+                }
+            }),
         }
     }
 
+    /// Add a filter. A filter with a schedule is only checked for errors
+    /// here and actually created when its start time comes.
     pub fn add_filter(
         &mut self,
         p: &FilterParams,
-    )
+    ) -> Result<(), Box<dyn Error>>
+    {
+        match p.schedule {
+            Some(schedule) => {
+                self.filter_bins(p)?;
+                self.scheduled.push(ScheduledFilter {
+                    params: p.clone(),
+                    schedule,
+                    first_used: None,
+                    running_until: None,
+                });
+                Ok(())
+            },
+            None => self.start_filter(p, None),
+        }
+    }
+
+    /// Find the bins used by a filter and check that it is possible.
+    fn filter_bins(
+        &self,
+        p: &FilterParams,
+    ) -> Result<BinNumbers, Box<dyn Error>>
+    {
+        if p.tracking.is_some() {
+            // Center frequency changes anyway, so only the sample rate has to be exact.
+            let bin_spacing = self.fft_info.fs / self.fft_info.size as f64;
+            let bn = freq_to_bins(self.fft_info, p.fs_out, self.fft_info.fc)
+                .filter(|bn| bins_to_freq(self.fft_info, *bn).0 == p.fs_out)
+                .ok_or_else(|| format!("Filter fs={} is not possible", p.fs_out))?;
+            let center = ((p.fc_out - self.fft_info.fc) / bin_spacing).round() as isize;
+            return Ok(BinNumbers { bins: bn.bins, first: center - bn.bins as isize / 2 });
+        }
+        let bn = freq_to_bins_exact(self.fft_info, p.fs_out, p.fc_out).ok_or_else(||
+            match self.nearest_freq(p.fs_out, p.fc_out) {
+                Some((fs, fc)) => format!(
+                    "Filter fs={} fc={} is not possible, nearest possible is fs={} fc={}",
+                    p.fs_out, p.fc_out, fs, fc),
+                None => format!(
+                    "Filter fs={} fc={} is not possible",
+                    p.fs_out, p.fc_out),
+            }
+        )?;
+        if bn.bins > self.fft_info.size {
+            return Err(format!(
+                "Filter fs={} is wider than input bandwidth of {} Hz",
+                p.fs_out, self.fft_info.fs).into());
+        }
+        // Check FIR coefficients too
+        filter_weights(p.taps.as_deref(), bn.bins)?;
+        Ok(bn)
+    }
+
+    fn start_filter(
+        &mut self,
+        p: &FilterParams,
+        stop_time: Option<f64>,
+    ) -> Result<(), Box<dyn Error>>
     {
-        if let Some(bn) = freq_to_bins_exact(self.fft_info, p.fs_out, p.fc_out) {
-            match FilterDsp::init(self.fft_info.size, bn) {
-                Ok(filter) => self.filters.push(Filter {
-                    dsp: filter,
-                    // TODO: calculate sufficient size for the output buffer
-                    outbuf: vec![0; 100000],
+        let bn = self.filter_bins(p)?;
+        if p.history.is_some() && self.history.is_none() {
+            return Err("Filter starting from history requires history to be enabled".into());
+        }
+        let filter = FilterDsp::init(self.fft_info.size, bn, p.taps.as_deref())?;
+        if p.taps.is_some() {
+            eprintln!("FIR filter fs={} fc={}: {}", p.fs_out, p.fc_out,
+                filter.response_summary(p.fs_out));
+        }
+
+        let samples = filter.samples_per_fft() * self.ffts_per_buf;
+        let demod = p.demod.as_ref().map(|d| -> Result<DemodOutput, String> {
+            let demod = Demodulator::init(p.fs_out, d)?;
+            let rds = demod.wbfm.as_ref().map(|_| {
+                // RDS is 1187.5 bit/s and each group is 104 bits.
+                // Leave some margin for rounding.
+                let max_groups = (samples as f64 / p.fs_out * 1187.5 / 104.0).ceil() as usize + 1;
+                RdsOutput {
+                    outbuf: vec![0; METADATA_SIZE + RdsDecoder::serialized_size(max_groups)],
                     outsize: 0,
-                    output: Output::init(&p.output, &serialize_signal_topic(&SignalInfo {
+                    output: Output::init(&d.rds_output, &serialize_rds_topic(p.fs_out, p.fc_out)),
+                }
+            });
+            Ok(DemodOutput {
+                outbuf: vec![0; METADATA_SIZE + demod.max_output(samples) * 4],
+                audio: Vec::new(),
+                outsize: 0,
+                output: Output::init(&d.output, &serialize_audio_topic(&AudioInfo {
+                    fs: d.fs_audio,
+                    fc: p.fc_out,
+                    channels: demod.channels(),
+                    mode: d.mode as u8,
+                })),
+                demod,
+                rds,
+            })
+        }).transpose()?;
+        let signal_info = SignalInfo {
+            fs: p.fs_out,
+            fc: p.fc_out,
+            format: p.format,
+        };
+        let squelch = p.squelch.as_ref().map(|sq| {
+            FilterSquelch {
+                squelch: Squelch::init(sq, samples as f64 / p.fs_out),
+                preroll: VecDeque::new(),
+                opening: false,
+                eventbuf: vec![0; SQUELCH_EVENT_SIZE],
+                eventsize: 0,
+                output: Output::init(&OutputParams { filename: None }, &serialize_squelch_topic(&signal_info)),
+            }
+        });
+        self.filters.push(Filter {
+            samples: Vec::with_capacity(samples),
+            format: p.format,
+            gain: 10.0f32.powf(p.gain / 20.0),
+            outbuf: vec![0; METADATA_SIZE + SIGNAL_METADATA_SIZE + samples * p.format.bytes_per_sample()],
+            outsize: 0,
+            output: Output::init(&p.output, &serialize_signal_topic(&signal_info)),
+            demod,
+            squelch,
+            publish: true,
+            telemetry: self.telemetry.as_ref().map(|_|
+                ChannelTelemetry::init(p.fs_out, p.fc_out, filter.bin_weights())),
+            stop_time,
+            name: p.name.clone(),
+            history_blocks: p.history.map(|h| (h / self.block_duration).ceil() as usize),
+            history_seq: None,
+            snapshot: p.snapshot,
+            tracking: p.tracking.as_ref().map(|mode|
+                Tracker::init(self.fft_info, p.fs_out, p.fc_out, bn.bins, mode.clone())),
+            dsp: filter,
+        });
+        Ok(())
+    }
+
+    /// Return whether a snapshot of the input signal has been triggered
+    /// since the previous call.
+    pub fn take_snapshot_trigger(&mut self) -> bool {
+        std::mem::take(&mut self.snapshot_triggered)
+    }
+
+    /// Remove a filter.
+    pub fn remove_filter(&mut self, name: &str) -> Result<(), Box<dyn Error>> {
+        let filter = self.filters.iter_mut()
+            .find(|f| f.name.as_deref() == Some(name))
+            .ok_or_else(|| format!("No filter named {}", name))?;
+        filter.dsp.done = true;
+        Ok(())
+    }
+
+    /// Change the center frequency of a tracking filter.
+    pub fn set_filter_frequency(
+        &mut self,
+        name: &str,
+        freq: f64,
+    ) -> Result<(), Box<dyn Error>>
+    {
+        let filter = self.filters.iter_mut()
+            .find(|f| f.name.as_deref() == Some(name))
+            .ok_or_else(|| format!("No filter named {}", name))?;
+        let tracking = filter.tracking.as_mut()
+            .ok_or_else(|| format!("Filter {} is not a tracking filter", name))?;
+        tracking.set_frequency(freq);
+        Ok(())
+    }
+
+    /// Start and stop scheduled filters.
+    ///
+    /// This is done at the start of each processing block,
+    /// so times are accurate to the length of a processing block.
+    fn update_schedules(&mut self, now: f64) {
+        for filter in self.filters.iter_mut() {
+            if filter.stop_time.map(|t| now >= t).unwrap_or(false) {
+                filter.dsp.done = true;
+            }
+        }
+
+        let mut starting = Vec::new();
+        for s in self.scheduled.iter_mut() {
+            let first_used = *s.first_used.get_or_insert(now);
+            if let Some(until) = s.running_until {
+                if now < until {
+                    continue;
+                }
+                eprintln!("Stopped scheduled filter fs={} fc={}", s.params.fs_out, s.params.fc_out);
+                s.running_until = None;
+            }
+            if let Some((start, stop)) = s.schedule.next_window(now, first_used) {
+                if now >= start {
+                    s.running_until = Some(stop);
+                    starting.push((s.params.with_start_time(start), stop));
+                }
+            }
+        }
+        self.scheduled.retain(|s| s.running_until.is_some() ||
+            s.schedule.next_window(now, s.first_used.unwrap_or(now)).is_some());
+
+        for (p, stop) in starting.iter() {
+            eprintln!("Starting scheduled filter fs={} fc={} until {}", p.fs_out, p.fc_out,
+                if stop.is_finite() { format_time(*stop) } else { "stopped".to_string() });
+            if let Err(error) = self.start_filter(p, Some(*stop)) {
+                eprintln!("Error creating scheduled filter: {}", error);
+            }
+        }
+    }
+
+    pub fn add_ssb_receiver(
+        &mut self,
+        p: &SsbParams,
+    ) -> Result<(), Box<dyn Error>>
+    {
+        let dsp = SsbDsp::init(self.fft_info, p)?;
+        let samples = dsp.samples_per_fft() * self.ffts_per_buf;
+        self.ssb_receivers.push(SsbReceiver {
+            dsp,
+            audio: Vec::with_capacity(samples),
+            gain: 10.0f32.powf(p.gain / 20.0),
+            outbuf: vec![0; METADATA_SIZE + samples * 4],
+            outsize: 0,
+            output: Output::init(&p.output, &serialize_audio_topic(&AudioInfo {
+                fs: p.fs_audio,
+                fc: p.fc,
+                channels: 1,
+                mode: p.mode as u8,
+            })),
+        });
+        Ok(())
+    }
+
+    pub fn add_channelizer(
+        &mut self,
+        p: &ChannelizerParams,
+    ) -> Result<(), Box<dyn Error>>
+    {
+        let dsp = Channelizer::init(self.fft_info, p)?;
+        let samples = dsp.samples_per_fft() * self.ffts_per_buf;
+        let bin_spacing = self.fft_info.fs / self.fft_info.size as f64;
+        let channels = dsp.centers().iter().map(|&center| {
+            let fc = self.fft_info.fc + center as f64 * bin_spacing;
+            ChannelOutput {
+                samples: Vec::with_capacity(samples),
+                outbuf: vec![0; METADATA_SIZE + SIGNAL_METADATA_SIZE + samples * p.format.bytes_per_sample()],
+                outsize: 0,
+                output: Output::init(
+                    &OutputParams {
+                        filename: p.output.filename.as_ref().map(|f| f.replace("{}", &fc.to_string())),
+                    },
+                    &serialize_signal_topic(&SignalInfo {
                         fs: p.fs_out,
-                        fc: p.fc_out,
+                        fc,
+                        format: p.format,
                     })),
-                }),
-                Err(error) => eprintln!("Error creating filter: {:?}", error),
             }
-        } else {
-            eprintln!("Invalid filter parameters");
-            // TODO: return errors
+        }).collect::<Vec<ChannelOutput>>();
+        eprintln!("Channelizer with {} channels from {} to {} Hz",
+            channels.len(), p.start, p.start + (channels.len() - 1) as f64 * p.spacing);
+        self.channelizers.push(ChannelizerOutput {
+            dsp,
+            format: p.format,
+            gain: 10.0f32.powf(p.gain / 20.0),
+            channels,
+        });
+        Ok(())
+    }
+
+    pub fn add_multiband(
+        &mut self,
+        p: &MultibandParams,
+    ) -> Result<(), Box<dyn Error>>
+    {
+        let dsp = MultibandDsp::init(self.fft_info, p)?;
+        let samples = dsp.samples_per_fft() * self.ffts_per_buf;
+        let fs_out = dsp.fs_out(self.fft_info);
+        eprintln!("Multi-band filter with fs={} Hz:", fs_out);
+        for (band, offset) in p.bands.iter().zip(dsp.band_offsets(self.fft_info)) {
+            eprintln!("    fc={} bw={} Hz at output frequency {} Hz", band.fc, band.bw, offset);
         }
+        self.multibands.push(MultibandOutput {
+            dsp,
+            format: p.format,
+            gain: 10.0f32.powf(p.gain / 20.0),
+            samples: Vec::with_capacity(samples),
+            outbuf: vec![0; METADATA_SIZE + SIGNAL_METADATA_SIZE + samples * p.format.bytes_per_sample()],
+            outsize: 0,
+            output: Output::init(&p.output, &serialize_signal_topic(&SignalInfo {
+                fs: fs_out,
+                fc: 0.0,
+                format: p.format,
+            })),
+        });
+        Ok(())
     }
 
     pub fn process(
@@ -70,32 +737,132 @@ impl Fcfb {
         sock: &zmq::Socket, // ZeroMQ socket used to publish all results
     )
     {
+        let now = unix_time(metadata.systemtime);
+        if !self.scheduled.is_empty() {
+            self.update_schedules(now);
+        }
+
+        let update_telemetry = match &mut self.telemetry {
+            Some(t) => {
+                if t.blocks_left <= 1 {
+                    t.blocks_left = t.interval;
+                    true
+                } else {
+                    t.blocks_left -= 1;
+                    false
+                }
+            },
+            None => false,
+        };
+
+        // Filters catching up from the history process older blocks first.
+        // This is done outside of the parallel part since
+        // the record of each block is written before the next one.
+        if let Some(history) = &mut self.history {
+            history.push(fft_results, metadata);
+            for filter in self.filters.iter_mut().filter(|f| !f.dsp.done) {
+                filter.catch_up(history, sock);
+            }
+        }
+
         // Process multiple filters in parallel
         self.filters.par_iter_mut().for_each( |filter| {
+            if filter.dsp.done || filter.history_seq.is_some() {
+                return;
+            }
+            filter.process(fft_results, metadata, update_telemetry);
+        });
+
+        self.ssb_receivers.par_iter_mut().for_each( |receiver| {
             let mut offset = 0;
-            // Sequence number of can be the same as metadata.seq because
-            // one record is produced for each processing block.
-            // This also results in common sequence numbering for all filters
-            // which is convenient for applications requiring
-            // synchronized signals from multiple filters.
-            //
-            // unwrap is OK here because it would only panic if outbuf
-            // is too small for metadata. That would clearly be a bug.
-            serialize_metadata(&mut filter.outbuf, &mut offset, &metadata, metadata.seq).unwrap();
-            for fft_result in fft_results.iter() {
-                if filter.dsp.done { break; }
-                filter.dsp.process(fft_result, &mut filter.outbuf, &mut offset);
+            serialize_metadata(&mut receiver.outbuf, &mut offset, metadata, metadata.seq).unwrap();
+            receiver.dsp.process_block(fft_results, &mut receiver.audio);
+            use byte::*;
+            for &v in receiver.audio.iter() {
+                receiver.outbuf.write_with(&mut offset, v * receiver.gain, LE).unwrap();
             }
-            filter.outsize = offset;
+            receiver.outsize = offset;
+        });
+
+        for channelizer in self.channelizers.iter_mut() {
+            channelizer.dsp.process_block(fft_results);
+            let dsp = &channelizer.dsp;
+            let (format, gain) = (channelizer.format, channelizer.gain);
+            channelizer.channels.par_iter_mut().enumerate().for_each( |(c, channel)| {
+                channel.samples.clear();
+                for i in 0..fft_results.len() {
+                    channel.samples.extend_from_slice(dsp.channel_samples(i, c));
+                }
+                let mut offset = 0;
+                serialize_metadata(&mut channel.outbuf, &mut offset, metadata, metadata.seq).unwrap();
+                let mut metadata_offset = offset;
+                offset += SIGNAL_METADATA_SIZE;
+                let signal_metadata = serialize_samples(
+                    &mut channel.outbuf, &mut offset, &channel.samples, format, gain).unwrap();
+                serialize_signal_metadata(&mut channel.outbuf, &mut metadata_offset, &signal_metadata).unwrap();
+                channel.outsize = offset;
+            });
+        }
+
+        self.multibands.par_iter_mut().for_each( |multiband| {
+            multiband.dsp.process_block(fft_results, &mut multiband.samples);
+            let mut offset = 0;
+            serialize_metadata(&mut multiband.outbuf, &mut offset, metadata, metadata.seq).unwrap();
+            let mut metadata_offset = offset;
+            offset += SIGNAL_METADATA_SIZE;
+            let signal_metadata = serialize_samples(
+                &mut multiband.outbuf, &mut offset, &multiband.samples, multiband.format, multiband.gain).unwrap();
+            serialize_signal_metadata(&mut multiband.outbuf, &mut metadata_offset, &signal_metadata).unwrap();
+            multiband.outsize = offset;
         });
 
         // Do I/O outside of the parallel part.
-        self.filters.iter_mut().for_each( |filter| {
-            filter.output.write(&filter.outbuf[0..filter.outsize], sock);
+        self.filters.iter_mut()
+            .filter(|f| !f.dsp.done && f.history_seq.is_none())
+            .for_each(|filter| filter.write(sock));
+        if self.filters.iter().any(|f| f.snapshot && f.squelch.as_ref().map(|s| s.opening).unwrap_or(false)) {
+            self.snapshot_triggered = true;
+        }
+
+        self.ssb_receivers.iter_mut().for_each( |receiver| {
+            if let Err(err) = receiver.output.write(&receiver.outbuf[0..receiver.outsize], sock) {
+                eprintln!("Error writing SSB receiver output: {}", err);
+            }
         });
 
-        // Remove filters that are done
-        // (not actually used for anything at the moment)
+        for channel in self.channelizers.iter_mut().flat_map(|c| c.channels.iter_mut()) {
+            if let Err(err) = channel.output.write(&channel.outbuf[0..channel.outsize], sock) {
+                eprintln!("Error writing channelizer output: {}", err);
+            }
+        }
+
+        for multiband in self.multibands.iter_mut() {
+            if let Err(err) = multiband.output.write(&multiband.outbuf[0..multiband.outsize], sock) {
+                eprintln!("Error writing multi-band filter output: {}", err);
+            }
+        }
+
+        if let (Some(telemetry), true) = (&mut self.telemetry, update_telemetry) {
+            let entries: Vec<&ChannelTelemetry> = self.filters.iter()
+                .filter(|f| !f.dsp.done).filter_map(|f| f.telemetry.as_ref()).collect();
+            telemetry.outbuf.resize(METADATA_SIZE + TELEMETRY_HEADER_SIZE + entries.len() * TELEMETRY_ENTRY_SIZE, 0);
+            let buf = &mut telemetry.outbuf;
+            let mut offset = 0;
+            serialize_metadata(buf, &mut offset, metadata, metadata.seq).unwrap();
+            use byte::*;
+            buf.write_with(&mut offset, entries.len() as u32, LE).unwrap();
+            // Reserved
+            buf.write_with(&mut offset, 0u32, LE).unwrap();
+            for entry in entries.iter() {
+                entry.serialize(buf, &mut offset).unwrap();
+            }
+            if let Err(err) = telemetry.output.write(&telemetry.outbuf, sock) {
+                eprintln!("Error writing telemetry output: {}", err);
+            }
+        }
+
+        // Remove filters that are done.
+        // This also closes their output files.
         self.filters.retain(|f| !f.dsp.done);
     }
 
@@ -121,70 +888,153 @@ impl Fcfb {
 // Signal processing for a single filter instance
 // ----------------------------------------------
 
+/// Filters with an IFFT at least this large process
+/// the FFT results of a processing block in parallel.
+const PARALLEL_IFFT_SIZE: usize = 4096;
+
 /// DSP state of a filter.
 ///
 /// This struct does not contain any I/O related things. */
 pub struct FilterDsp {
-    // TODO: is done useful anymore?
+    /// Set when the filter should be removed
     done: bool,
     fft_size: usize,
     freq: isize,
-    weights: Vec<f32>, // Frequency response
+    weights: Vec<Complex<f32>>, // Frequency response
     ifft: std::sync::Arc<dyn rustfft::Fft<f32>>, // RustFFT plan
+    ffts: u64, // Number of FFT results processed so far
 }
 
 impl FilterDsp {
     pub fn init(
         fft_size: usize,
         bn:       BinNumbers,
+        taps:     Option<&[f32]>, // FIR coefficients, raised cosine if None
     ) -> Result<Self, Box<dyn Error>>
-    // TODO: Box<dyn Error> is not used here anymore, so use something else
     {
+        let weights = filter_weights(taps, bn.bins)?;
         // TODO: reuse the planner
         let mut planner = FftPlanner::new();
         Ok(Self {
             done: false,
             fft_size: fft_size,
             freq: bn.first,
-            weights: raised_cosine_weights(bn.bins),
+            weights,
             ifft: planner.plan_fft_inverse(bn.bins),
+            ffts: 0,
         })
     }
 
-    pub fn process(
+    /// Describe the effective frequency response of the filter.
+    ///
+    /// The response is only defined at the IFFT bins,
+    /// so the gain at the band edge tells how much aliasing
+    /// there is from frequencies outside of the output band.
+    pub fn response_summary(&self, fs_out: f64) -> String {
+        let n = self.weights.len();
+        let db: Vec<f32> = self.weights.iter().map(|w| 10.0 * w.norm_sqr().log10()).collect();
+        let center = db[n / 2];
+        // Width of the contiguous region around the center
+        // where gain stays above a given level relative to the center.
+        let width = |level: f32| -> f64 {
+            let above = |j: &usize| db[*j] >= center + level;
+            let hi = (n / 2 .. n).take_while(above).count();
+            let lo = (0 .. n / 2).rev().take_while(above).count();
+            (hi + lo) as f64 * fs_out / n as f64
+        };
+        format!(
+            "center gain {:.2} dB, -3 dB bandwidth {:.0} Hz, -60 dB bandwidth {:.0} Hz, gain at band edge {:.1} dB",
+            center, width(-3.0), width(-60.0), db[0],
+        )
+    }
+
+    /// Number of output samples produced from each FFT result
+    pub fn samples_per_fft(&self) -> usize {
+        // fixed 25% overlap
+        self.ifft.len() / 4 * 3
+    }
+
+    /// Filter the FFT results of a processing block.
+    /// Output buffer is replaced with the resulting samples.
+    ///
+    /// If bin_power is given, it is replaced with the powers
+    /// of the weighted bins of each FFT result.
+    ///
+    /// If offsets are given, the bins used for each FFT result
+    /// are shifted by the corresponding number of bins.
+    pub fn process_block(
         &mut self,
+        fft_results: &[&mut[Complex<f32>]],
+        output: &mut Vec<Complex<f32>>,
+        bin_power: Option<&mut Vec<f32>>,
+        offsets: Option<&[isize]>,
+    ) {
+        let n = self.samples_per_fft();
+        output.resize(n * fft_results.len(), Complex{ re: 0.0, im: 0.0 });
+        let mut bin_powers: Vec<&mut [f32]> = match bin_power {
+            Some(p) => {
+                p.resize(self.ifft.len() * fft_results.len(), 0.0);
+                p.chunks_mut(self.ifft.len()).collect()
+            },
+            None => fft_results.iter().map(|_| Default::default()).collect(),
+        };
+        let offset = |i: usize| offsets.map(|o| o[i]).unwrap_or(0);
+        let ffts = self.ffts;
+        if self.ifft.len() >= PARALLEL_IFFT_SIZE {
+            // A single wide filter would otherwise keep only
+            // one CPU core busy, so process its FFT results in parallel.
+            output.par_chunks_mut(n).zip(fft_results.par_iter()).zip(bin_powers.par_iter_mut()).enumerate().for_each(
+                |(i, ((out, fft_result), p))| self.process(fft_result, ffts + i as u64, offset(i), out, p));
+        } else {
+            output.chunks_mut(n).zip(fft_results.iter()).zip(bin_powers.iter_mut()).enumerate().for_each(
+                |(i, ((out, fft_result), p))| self.process(fft_result, ffts + i as u64, offset(i), out, p));
+        }
+        self.ffts += fft_results.len() as u64;
+    }
+
+    /// Squared magnitudes of the weights in the same order
+    /// as the bin powers returned by process_block.
+    pub fn bin_weights(&self) -> Vec<f32> {
+        let n = self.weights.len();
+        (0..n).map(|i| self.weights[(i + n / 2) % n].norm_sqr()).collect()
+    }
+
+    /// Filter one FFT result and write the resulting samples to output.
+    fn process(
+        &self,
         fft_result: &[Complex<f32>],
-        outbuf: &mut [u8],
-        outbuf_offset: &mut usize,
+        fft_number: u64,
+        offset: isize, // Shift of the bins used
+        output: &mut [Complex<f32>],
+        bin_power: &mut [f32], // Empty if not needed
     ) {
         let fft_size = self.fft_size;
         let ifft_size = self.ifft.len();
-        let freq = self.freq;
+        let freq = self.freq + offset;
 
         let ifft_shift = ifft_size / 2;
 
+        // Center bin of a normal filter is a multiple of 4,
+        // so phase correction is only needed for tracking filters,
+        // which may use any bins like the channelizer.
+        let center = freq + ifft_shift as isize;
+        let rotation = quarter_turn_rotation(center, fft_number, -1.0);
+
         let mut buf: Vec<Complex<f32>> =
         (0..ifft_size).map(|i|
             (i + ifft_shift) % ifft_size
         ).map(|i|
-            self.weights[i] *
+            self.weights[i] * rotation *
             get_bin(fft_result, fft_size, freq + (i as isize))
         ).collect();
 
-        self.ifft.process(&mut buf);
+        bin_power.iter_mut().zip(buf.iter()).for_each(|(p, b)| *p = b.norm_sqr());
 
-        // fixed 25% overlap
-        let result = &buf[ifft_size / 8 .. ifft_size / 8 * 7];
+        self.ifft.process(&mut buf);
 
-        // Write result to output buffer
-        use byte::*;
-        for v in result.into_iter() {
-            // unwrap will panic if outbuf is too small.
-            // This may actually happen because the buffer size
-            // is not properly calculated yet.
-            outbuf.write_with(outbuf_offset, v.re, LE).unwrap();
-            outbuf.write_with(outbuf_offset, v.im, LE).unwrap();
-        }
+        // fixed 25% overlap, discard 1/8 from each end
+        let discard = ifft_size / 8;
+        output.copy_from_slice(&buf[discard .. discard + output.len()]);
     }
 }
 
@@ -194,21 +1044,100 @@ impl FilterDsp {
 // Filter design
 // -------------
 
-fn raised_cosine_weights(size: usize) -> Vec<f32> {
+/// Frequency domain weights for a filter with a given IFFT size,
+/// from FIR coefficients if given or a raised cosine response otherwise.
+pub(super) fn filter_weights(
+    taps: Option<&[f32]>,
+    size: usize,
+) -> Result<Vec<Complex<f32>>, Box<dyn Error>> {
+    match taps {
+        Some(taps) => fir_weights(taps, size),
+        None => Ok(raised_cosine_weights(size)),
+    }
+}
+
+fn raised_cosine_weights(size: usize) -> Vec<Complex<f32>> {
     use std::f32::consts::PI;
     let f = (2.0 * PI) / size as f32;
     (0..size).map(|i| {
-        0.5 - 0.5 * ((i as f32) * f).cos()
+        Complex{ re: 0.5 - 0.5 * ((i as f32) * f).cos(), im: 0.0 }
     }).collect()
 }
 
+/// Convert FIR filter coefficients to frequency domain weights.
+///
+/// Coefficients are given at the output sample rate.
+/// The middle tap is placed at time zero, so the delay of a linear phase
+/// filter is removed and its weights become real-valued.
+/// For the result to equal a linear convolution with the taps,
+/// the impulse response has to fit within the overlapping part
+/// of the IFFT which is discarded from each end, i.e. size/8 samples.
+fn fir_weights(taps: &[f32], size: usize) -> Result<Vec<Complex<f32>>, Box<dyn Error>> {
+    use std::f64::consts::PI;
+    if taps.is_empty() {
+        return Err("FIR filter has no coefficients".into());
+    }
+    let delay = (taps.len() - 1) / 2;
+    let max_len = (size / 8) * 2 + 1;
+    if taps.len() - 1 - delay > size / 8 {
+        return Err(format!(
+            "FIR filter has {} taps but at most {} fit in the overlap of a {}-point IFFT",
+            taps.len(), max_len, size).into());
+    }
+    Ok((0..size).map(|j| {
+        // Frequency of the bin relative to the output center frequency,
+        // in cycles per sample
+        let f = (j as f64 - (size / 2) as f64) / size as f64;
+        taps.iter().enumerate().fold(Complex{ re: 0.0, im: 0.0 }, |acc, (n, &h)| {
+            let phase = -2.0 * PI * f * (n as f64 - delay as f64);
+            acc + Complex{ re: phase.cos(), im: phase.sin() } * h as f64
+        })
+    }).map(|w: Complex<f64>| Complex{ re: w.re as f32, im: w.im as f32 }).collect())
+}
+
 /// Filter design and configuration parameters
+#[derive(Clone)]
 pub struct FilterParams {
     pub fs_out: f64, // Output sample rate
     pub fc_out: f64, // Output center frequency
+    pub taps: Option<Vec<f32>>, // FIR coefficients at output sample rate
+    pub format: SignalFormat, // Output sample format
+    pub gain: f32, // Output gain in dB
+    pub demod: Option<DemodParams>, // Demodulator attached to the filter
+    pub squelch: Option<SquelchParams>, // Publish only when squelch is open
+    /// Make the center frequency change over time.
+    /// fc_out is then the nominal center frequency given in the topic.
+    pub tracking: Option<TrackingMode>,
+    /// Name for referring to the filter through the control interface
+    pub name: Option<String>,
+    /// Start the output this many seconds in the past
+    /// using FFT results from the history
+    pub history: Option<f64>,
+    /// Trigger a snapshot of the input signal when squelch opens
+    pub snapshot: bool,
+    /// Run the filter only at scheduled times.
+    /// {time} in output file names is replaced with the start time.
+    pub schedule: Option<Schedule>,
     pub output: OutputParams,
 }
 
+impl FilterParams {
+    /// Parameters for a scheduled run of a filter starting at a given time.
+    fn with_start_time(&self, start: f64) -> Self {
+        let time = format_time(start);
+        let replace = |o: &OutputParams| OutputParams {
+            filename: o.filename.as_ref().map(|f| f.replace("{time}", &time)),
+        };
+        let mut p = self.clone();
+        p.output = replace(&self.output);
+        if let Some(d) = &mut p.demod {
+            d.output = replace(&d.output);
+            d.rds_output = replace(&d.rds_output);
+        }
+        p
+    }
+}
+
 #[derive(Copy, Clone)]
 pub struct BinNumbers {
     pub bins:  usize, // IFFT size, number of bins to use
@@ -310,3 +1239,21 @@ fn test_freq_to_bins() {
     // Test with some values that were used before
     test(16384, 128.0e6, 0.0, 500000.0, 50.250e6, 64, 6400, true);
 }
+
+#[test]
+fn test_fir_weights() {
+    // A single tap passes everything through unchanged
+    for w in fir_weights(&[1.0], 64).unwrap().iter() {
+        assert!((w.re - 1.0).abs() < 1e-6);
+        assert!(w.im.abs() < 1e-6);
+    }
+    // A symmetric filter has a real-valued response
+    // and its DC gain is the sum of the taps
+    let w = fir_weights(&[0.25, 0.5, 0.25], 64).unwrap();
+    assert!((w[32].re - 1.0).abs() < 1e-6);
+    assert!(w[0].norm() < 1e-6);
+    assert!(w.iter().all(|v| v.im.abs() < 1e-6));
+    // Impulse response must fit in the overlap of 64/8 samples on each side
+    assert!(fir_weights(&[0.1; 17], 64).is_ok());
+    assert!(fir_weights(&[0.1; 18], 64).is_err());
+}
@@ -20,3 +20,105 @@ pub fn get_bin(fft_result: &[Complex<f32>], fft_size: usize, i: isize) -> Comple
         fft_result[fft_size - m].conj()
     }
 }
+
+
+/// Phase correction for a spectrum shifted by a number of bins.
+///
+/// FFTs are taken every 3/4 FFT size, so shifting the spectrum
+/// by a number of bins which is not a multiple of 4 rotates
+/// the phase of each IFFT result by a multiple of 90 degrees.
+/// Return the factor which rotates it back for a given FFT.
+/// sign is -1 when bins are shifted down to baseband
+/// and 1 when they are shifted up, as in synthesis.
+pub fn quarter_turn_rotation(shift: isize, fft_number: u64, sign: f32) -> Complex<f32> {
+    let quarter_turns = (shift.rem_euclid(4) as u64 * 3 * (fft_number % 4)) % 4;
+    [
+        Complex{ re:  1.0, im:  0.0  },
+        Complex{ re:  0.0, im:  sign },
+        Complex{ re: -1.0, im:  0.0  },
+        Complex{ re:  0.0, im: -sign },
+    ][quarter_turns as usize]
+}
+
+
+/// Inverse FFT producing a real-valued signal.
+///
+/// Input is the non-negative frequency half of a conjugate symmetric
+/// spectrum, i.e. size/2+1 bins. The transform is computed
+/// using a complex IFFT of half the size, so that even-indexed
+/// output samples end up in the real part and odd-indexed ones
+/// in the imaginary part.
+/// Like RustFFT, the result is not normalized.
+///
+/// Size of the output must be even.
+pub struct RealIfft {
+    ifft: std::sync::Arc<dyn rustfft::Fft<f32>>,
+    twiddles: Vec<Complex<f32>>,
+}
+
+impl RealIfft {
+    pub fn init(size: usize) -> Self {
+        use std::f64::consts::PI;
+        let half = size / 2;
+        let mut planner = rustfft::FftPlanner::new();
+        Self {
+            ifft: planner.plan_fft_inverse(half),
+            twiddles: (0..half).map(|k| {
+                let phase = 2.0 * PI * k as f64 / size as f64;
+                Complex{ re: phase.cos() as f32, im: phase.sin() as f32 }
+            }).collect(),
+        }
+    }
+
+    /// Size of the real output
+    pub fn size(&self) -> usize {
+        self.ifft.len() * 2
+    }
+
+    /// Transform size/2+1 bins to size real samples.
+    /// Imaginary parts of the DC and Nyquist bins are ignored.
+    pub fn process(&self, input: &[Complex<f32>], output: &mut [f32]) {
+        let half = self.ifft.len();
+        let mut buf: Vec<Complex<f32>> = (0..half).map(|k| {
+            // Bins k and k+size/2 of the full spectrum
+            let a = input[k];
+            let b = input[half - k].conj();
+            // Spectra of the even and odd samples
+            let even = a + b;
+            let odd = (a - b) * self.twiddles[k];
+            even + odd * Complex{ re: 0.0, im: 1.0 }
+        }).collect();
+        self.ifft.process(&mut buf);
+        for (o, s) in output.chunks_exact_mut(2).zip(buf.iter()) {
+            o[0] = s.re;
+            o[1] = s.im;
+        }
+    }
+}
+
+
+#[test]
+fn test_real_ifft() {
+    // Compare to a direct evaluation of the inverse DFT
+    let size = 24;
+    let mut seed: u32 = 1;
+    let mut random = || {
+        seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+        (seed >> 16) as f32 / 65536.0 - 0.5
+    };
+    let mut input: Vec<Complex<f32>> = (0..=size/2).map(|_| Complex{ re: random(), im: random() }).collect();
+    input[0].im = 0.0;
+    input[size/2].im = 0.0;
+
+    let mut output = vec![0.0; size];
+    RealIfft::init(size).process(&input, &mut output);
+
+    for (n, &x) in output.iter().enumerate() {
+        let expected: f32 = (0..size).map(|m| {
+            let bin = if m <= size/2 { input[m] } else { input[size - m].conj() };
+            let phase = 2.0 * std::f32::consts::PI * (m * n) as f32 / size as f32;
+            (bin * Complex::from_polar(1.0, phase)).re
+        }).sum();
+        assert!((x - expected).abs() < 1e-4, "sample {}: {} != {}", n, x, expected);
+    }
+}
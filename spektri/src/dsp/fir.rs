@@ -0,0 +1,239 @@
+//! FIR filter design, filtering and resampling
+//!
+//! These are used for further processing of filter bank outputs
+//! which are already at a low sample rate, so simple time domain
+//! implementations are good enough here.
+
+use std::ops::{Add, Mul};
+use rustfft::num_complex::Complex;
+
+/// Design a lowpass filter by the window method.
+///
+/// Cutoff frequency is given relative to the sample rate,
+/// i.e. 0.5 would be the Nyquist frequency.
+/// A Blackman window is used and gain at DC is normalized to 1.
+pub fn lowpass(numtaps: usize, cutoff: f64) -> Vec<f32> {
+    use std::f64::consts::PI;
+    let m = (numtaps - 1) as f64;
+    let taps: Vec<f64> = (0..numtaps).map(|i| {
+        let t = i as f64 - m / 2.0;
+        let sinc = if t == 0.0 { 2.0 * cutoff } else { (2.0 * PI * cutoff * t).sin() / (PI * t) };
+        let w = if numtaps > 1 {
+            0.42 - 0.5 * (2.0 * PI * i as f64 / m).cos() + 0.08 * (4.0 * PI * i as f64 / m).cos()
+        } else {
+            1.0
+        };
+        sinc * w
+    }).collect();
+    let sum: f64 = taps.iter().sum();
+    taps.iter().map(|h| (h / sum) as f32).collect()
+}
+
+/// Design a complex bandpass filter by shifting a lowpass filter
+/// to a center frequency given relative to the sample rate.
+pub fn bandpass(numtaps: usize, bandwidth: f64, center: f64) -> Vec<Complex<f32>> {
+    use std::f64::consts::PI;
+    let m = (numtaps - 1) as f64;
+    lowpass(numtaps, bandwidth / 2.0).iter().enumerate().map(|(i, &h)| {
+        let phase = 2.0 * PI * center * (i as f64 - m / 2.0);
+        Complex{ re: (phase.cos() as f32) * h, im: (phase.sin() as f32) * h }
+    }).collect()
+}
+
+
+/// Streaming FIR filter for complex signals with complex coefficients.
+pub struct ComplexFir {
+    taps: Vec<Complex<f32>>, // In reversed order
+    buf: Vec<Complex<f32>>, // Stored twice for "fake circular buffering"
+    i: usize,
+}
+
+impl ComplexFir {
+    pub fn init(taps: &[Complex<f32>]) -> Self {
+        let n = taps.len();
+        Self {
+            taps: taps.iter().rev().copied().collect(),
+            buf: vec![Complex{ re: 0.0, im: 0.0 }; 2 * n],
+            i: 0,
+        }
+    }
+
+    pub fn process(&mut self, input: &[Complex<f32>], output: &mut Vec<Complex<f32>>) {
+        let n = self.taps.len();
+        for &s in input.iter() {
+            self.buf[self.i] = s;
+            self.buf[self.i + n] = s;
+            self.i = (self.i + 1) % n;
+            // Oldest sample is now at i and the newest one at i+n-1
+            output.push(dot(&self.taps, &self.buf[self.i .. self.i + n]));
+        }
+    }
+}
+
+fn dot<T, C>(taps: &[C], samples: &[T]) -> T
+where T: Copy + Default + Add<Output=T> + Mul<C, Output=T>, C: Copy {
+    taps.iter().zip(samples.iter()).fold(T::default(), |acc, (&h, &s)| acc + s * h)
+}
+
+
+/// Rational resampler implemented as a polyphase FIR filter.
+///
+/// Works for both real and complex signals.
+pub struct Resampler<T> {
+    /// Polyphase branches of the filter, each in reversed order
+    branches: Vec<Vec<f32>>,
+    interpolation: usize,
+    decimation: usize,
+    /// Stored input samples, twice for "fake circular buffering"
+    buf: Vec<T>,
+    i: usize,
+    /// Time of the next output sample relative to the newest input sample,
+    /// in units of the interpolated sample rate
+    phase: usize,
+}
+
+/// Largest number of taps in the filter of a resampler, about 512 kB
+const MAX_RESAMPLER_TAPS: usize = 1 << 17;
+
+/// Largest interpolation or decimation factor tried
+/// when looking for the resampling ratio
+const MAX_RATIO_TERM: u64 = 100000;
+
+/// Find the exact resampling ratio between two sample rates
+/// as interpolation and decimation factors.
+///
+/// Sample rates of filter outputs are multiples of the FFT bin spacing,
+/// so they are often not integers but still have a rational ratio,
+/// which is found from the continued fraction of fs_out / fs_in.
+pub fn resampling_ratio(fs_in: f64, fs_out: f64) -> Result<(usize, usize), String> {
+    let fail = || format!("Cannot resample from {} Hz to {} Hz", fs_in, fs_out);
+    let x = fs_out / fs_in;
+    if !(x.is_finite() && x > 0.0) {
+        return Err(fail());
+    }
+    // Convergents h/k of the continued fraction
+    let (mut h, mut h_prev) = (1u64, 0u64);
+    let (mut k, mut k_prev) = (0u64, 1u64);
+    let mut y = x;
+    loop {
+        let a = y.floor();
+        if a > MAX_RATIO_TERM as f64 {
+            return Err(fail());
+        }
+        (h, h_prev) = (a as u64 * h + h_prev, h);
+        (k, k_prev) = (a as u64 * k + k_prev, k);
+        if h > MAX_RATIO_TERM || k > MAX_RATIO_TERM {
+            return Err(fail());
+        }
+        if (h as f64 / k as f64 - x).abs() <= 1e-12 * x {
+            return Ok((h as usize, k as usize));
+        }
+        y = 1.0 / (y - a);
+    }
+}
+
+impl<T> Resampler<T>
+where T: Copy + Default + Add<Output=T> + Mul<f32, Output=T> {
+    /// Design a resampler between given sample rates.
+    ///
+    /// Lowpass filter cuts off at the lower of the two Nyquist frequencies.
+    pub fn init(fs_in: f64, fs_out: f64) -> Result<Self, String> {
+        let (interpolation, decimation) = resampling_ratio(fs_in, fs_out)?;
+        let rate = interpolation.max(decimation);
+
+        let firlen = 16 * rate / interpolation + 1;
+        Self::with_filter(fs_in, fs_out, interpolation, decimation, firlen, 0.5 / rate as f64)
+    }
+
+    /// Design a resampler with a given lowpass cutoff frequency
+    /// and width of the transition band, both in Hz.
+    pub fn init_lowpass(fs_in: f64, fs_out: f64, cutoff: f64, transition: f64) -> Result<Self, String> {
+        let (interpolation, decimation) = resampling_ratio(fs_in, fs_out)?;
+
+        // Rule of thumb for the length of a Blackman windowed filter
+        let firlen = (5.5 * fs_in / transition).ceil() as usize;
+        Self::with_filter(fs_in, fs_out, interpolation, decimation, firlen, cutoff / (fs_in * interpolation as f64))
+    }
+
+    fn with_filter(
+        fs_in: f64,
+        fs_out: f64,
+        interpolation: usize,
+        decimation: usize,
+        firlen: usize, // Length of each polyphase branch
+        cutoff: f64, // Relative to the interpolated sample rate
+    ) -> Result<Self, String> {
+        if firlen * interpolation > MAX_RESAMPLER_TAPS {
+            return Err(format!(
+                "Cannot resample from {} Hz to {} Hz, ratio {}/{} needs too long a filter",
+                fs_in, fs_out, interpolation, decimation));
+        }
+        let taps = lowpass(firlen * interpolation, cutoff);
+        let branches = (0..interpolation).map(|p| {
+            (0..firlen).rev().map(|j| taps[p + j * interpolation] * interpolation as f32).collect()
+        }).collect();
+
+        Ok(Self {
+            branches,
+            interpolation,
+            decimation,
+            buf: vec![T::default(); 2 * firlen],
+            i: 0,
+            phase: interpolation,
+        })
+    }
+
+    /// Number of output samples produced at most for a given number of input samples
+    pub fn max_output(&self, input_samples: usize) -> usize {
+        input_samples * self.interpolation / self.decimation + 1
+    }
+
+    pub fn process(&mut self, input: &[T], output: &mut Vec<T>) {
+        let firlen = self.buf.len() / 2;
+        for &s in input.iter() {
+            self.buf[self.i] = s;
+            self.buf[self.i + firlen] = s;
+            self.i = (self.i + 1) % firlen;
+            self.phase -= self.interpolation;
+            while self.phase < self.interpolation {
+                output.push(dot(&self.branches[self.phase], &self.buf[self.i .. self.i + firlen]));
+                self.phase += self.decimation;
+            }
+        }
+    }
+}
+
+#[test]
+fn test_resampler() {
+    // Resample a tone and check that its frequency and amplitude are kept
+    let (fs_in, fs_out, f) = (48000.0, 44100.0, 1000.0);
+    let mut r: Resampler<Complex<f32>> = Resampler::init(fs_in, fs_out).unwrap();
+    let input: Vec<Complex<f32>> = (0..4800).map(|i| {
+        Complex::from_polar(1.0, (2.0 * std::f64::consts::PI * f * i as f64 / fs_in) as f32)
+    }).collect();
+    let mut output = Vec::new();
+    r.process(&input, &mut output);
+    assert!(output.len() == 4410);
+    // Skip the transient in the beginning
+    for (i, w) in output.windows(2).enumerate().skip(500) {
+        assert!((w[0].norm() - 1.0).abs() < 0.01, "amplitude at {}: {}", i, w[0].norm());
+        let expected = 2.0 * std::f32::consts::PI * (f / fs_out) as f32;
+        assert!(((w[1] * w[0].conj()).arg() - expected).abs() < 0.01);
+    }
+}
+
+#[test]
+fn test_resampling_ratio() {
+    assert!(resampling_ratio(48000.0, 44100.0) == Ok((147, 160)));
+    // 2.4 MHz with a 16384-point FFT has a bin spacing of 146.484375 Hz,
+    // so a filter of 80 bins has a sample rate of 11718.75 Hz
+    assert!(resampling_ratio(11718.75, 8000.0) == Ok((256, 375)));
+    let mut r: Resampler<f32> = Resampler::init(11718.75, 8000.0).unwrap();
+    let mut output = Vec::new();
+    r.process(&vec![1.0; 46875], &mut output);
+    assert!(output.len() == 32000);
+    assert!((output[31999] - 1.0).abs() < 1e-3);
+    // Nearly coprime rates would need a huge filter
+    assert!(Resampler::<f32>::init(11719.0, 8000.0).is_err());
+    assert!(resampling_ratio(0.0, 8000.0).is_err());
+}
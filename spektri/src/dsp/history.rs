@@ -0,0 +1,110 @@
+//! History of FFT results
+//!
+//! FFT results of the latest processing blocks are kept in memory,
+//! so that a newly added filter can start its output from a moment
+//! in the past and then catch up with live processing.
+//! The number of blocks is fixed, so memory use is bounded.
+
+use std::collections::VecDeque;
+use rustfft::num_complex::Complex;
+
+use super::data::Metadata;
+
+struct HistoryBlock {
+    seq: u64,
+    systemtime: std::time::SystemTime,
+    /// FFT results of the block, one after another
+    results: Vec<Complex<f32>>,
+    /// Number of bins in each FFT result
+    result_size: usize,
+}
+
+pub struct History {
+    /// Maximum number of processing blocks kept
+    capacity: usize,
+    blocks: VecDeque<HistoryBlock>,
+}
+
+impl History {
+    pub fn init(capacity: usize) -> Self {
+        Self {
+            capacity,
+            blocks: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Memory used for FFT results when the history is full, in bytes
+    pub fn memory_size(
+        &self,
+        block_size: usize, // Number of bins in all FFT results of a block
+    ) -> usize {
+        self.capacity * block_size * std::mem::size_of::<Complex<f32>>()
+    }
+
+    /// Add the FFT results of a processing block,
+    /// replacing the oldest one if the history is full.
+    pub fn push(
+        &mut self,
+        fft_results: &[&mut[Complex<f32>]],
+        metadata: &Metadata,
+    ) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut results = if self.blocks.len() >= self.capacity {
+            // Reuse the buffer to avoid allocating memory all the time
+            self.blocks.pop_front().map(|b| b.results).unwrap_or_default()
+        } else {
+            Vec::new()
+        };
+        results.clear();
+        for r in fft_results.iter() {
+            results.extend_from_slice(r);
+        }
+        self.blocks.push_back(HistoryBlock {
+            seq: metadata.seq,
+            systemtime: metadata.systemtime,
+            results,
+            result_size: fft_results.first().map(|r| r.len()).unwrap_or(0),
+        });
+    }
+
+    pub fn oldest_seq(&self) -> Option<u64> {
+        self.blocks.front().map(|b| b.seq)
+    }
+
+    pub fn latest_seq(&self) -> Option<u64> {
+        self.blocks.back().map(|b| b.seq)
+    }
+
+    /// FFT results and metadata of a block with a given sequence number,
+    /// if it is still in the history.
+    pub fn get(&mut self, seq: u64) -> Option<(Vec<&mut [Complex<f32>]>, Metadata)> {
+        let index = seq.checked_sub(self.oldest_seq()?)? as usize;
+        let block = self.blocks.get_mut(index).filter(|b| b.seq == seq)?;
+        let metadata = Metadata {
+            seq: block.seq,
+            systemtime: block.systemtime,
+        };
+        Some((block.results.chunks_mut(block.result_size).collect(), metadata))
+    }
+}
+
+
+#[test]
+fn test_history() {
+    let mut history = History::init(3);
+    let time = std::time::SystemTime::now();
+    for seq in 10..15 {
+        let mut results = vec![vec![Complex{ re: seq as f32, im: 0.0 }; 4]; 2];
+        let r: Vec<&mut [Complex<f32>]> = results.iter_mut().map(|r| &mut r[..]).collect();
+        history.push(&r, &Metadata { seq, systemtime: time });
+    }
+    assert!(history.oldest_seq() == Some(12));
+    assert!(history.latest_seq() == Some(14));
+    assert!(history.get(11).is_none());
+    let (results, metadata) = history.get(13).unwrap();
+    assert!(metadata.seq == 13);
+    assert!(results.len() == 2 && results[1] == [Complex{ re: 13.0, im: 0.0 }; 4]);
+    assert!(history.memory_size(8) == 3 * 8 * 8);
+}
@@ -0,0 +1,254 @@
+//! Multi-band filter
+//!
+//! Selects several separate frequency bands and packs them side by side
+//! into one complex output signal, which has a lower sample rate than
+//! a single filter covering all the bands would need.
+//!
+//! Frequency mapping: bands are packed in the order they are given,
+//! starting from the lowest output frequency -fs_out/2.
+//! The n:th band occupies output frequencies from
+//! -fs_out/2 + (sum of bandwidths of bands before it)
+//! to that plus its own bandwidth, so an input frequency f
+//! in band n appears in the output at f - fc_n + offset_n, where
+//! offset_n = -fs_out/2 + (sum of previous bandwidths) + bw_n/2.
+//! Output sample rate is the total bandwidth rounded up
+//! to a multiple of 4 FFT bins, unless a higher one is given.
+//! Center frequency in the topic is 0, since bands come from
+//! different frequencies.
+
+use std::error::Error;
+use rustfft::{FftPlanner, num_complex::Complex};
+
+use super::data::*;
+use super::fftutil::*;
+use super::output::OutputParams;
+
+/// Width of the raised cosine transitions at the edges of each band, in bins.
+/// Sharp edges would make the impulse response
+/// longer than the overlap between consecutive IFFTs.
+const TRANSITION_BINS: usize = 8;
+
+/// One frequency band of a multi-band filter
+#[derive(Clone, Debug)]
+pub struct Band {
+    pub fc: f64, // Center frequency
+    pub bw: f64, // Bandwidth
+    pub gain: f32, // Gain of this band in dB
+}
+
+/// Multi-band filter parameters
+pub struct MultibandParams {
+    pub bands: Vec<Band>,
+    pub fs_out: Option<f64>, // Output sample rate, minimum possible if None
+    pub format: SignalFormat, // Output sample format
+    pub gain: f32, // Output gain in dB
+    pub output: OutputParams,
+}
+
+/// Bins of one band
+struct Segment {
+    /// First input bin
+    first: isize,
+    /// Output bin of the first input bin, counted from -fs_out/2
+    out_first: usize,
+    weights: Vec<f32>,
+}
+
+pub struct MultibandDsp {
+    fft_size: usize,
+    segments: Vec<Segment>,
+    ifft: std::sync::Arc<dyn rustfft::Fft<f32>>,
+    /// Number of FFT results processed so far
+    ffts: u64,
+}
+
+impl MultibandDsp {
+    pub fn init(
+        fft_info: FftInfo,
+        p: &MultibandParams,
+    ) -> Result<Self, Box<dyn Error>>
+    {
+        let bin_spacing = fft_info.fs / fft_info.size as f64;
+        if p.bands.is_empty() {
+            return Err("Multi-band filter has no bands".into());
+        }
+
+        let mut segments = Vec::new();
+        let mut out_first = 0;
+        for band in p.bands.iter() {
+            let first = ((band.fc - band.bw / 2.0 - fft_info.fc) / bin_spacing).round() as isize;
+            let width = (band.bw / bin_spacing).round() as usize;
+            let exact_bw = width as f64 * bin_spacing;
+            let exact_fc = fft_info.fc + first as f64 * bin_spacing + exact_bw / 2.0;
+            if width == 0 || exact_bw != band.bw || exact_fc != band.fc {
+                return Err(format!(
+                    "Band fc={} bw={} is not possible, nearest possible is fc={} bw={}",
+                    band.fc, band.bw, exact_fc, exact_bw).into());
+            }
+            // Flat passband with raised cosine transitions at the edges
+            let transition = TRANSITION_BINS.min(width / 2);
+            let gain = 10.0f32.powf(band.gain / 20.0);
+            let weights = (0..width).map(|j| {
+                let edge = j.min(width - 1 - j);
+                if edge < transition {
+                    let x = (edge as f32 + 0.5) / transition as f32;
+                    gain * (0.5 - 0.5 * (std::f32::consts::PI * x).cos())
+                } else {
+                    gain
+                }
+            }).collect();
+            segments.push(Segment { first, out_first, weights });
+            out_first += width;
+        }
+
+        // IFFT size must be a multiple of 4 for the 25% overlap
+        let min_size = out_first.div_ceil(4) * 4;
+        let size = match p.fs_out {
+            Some(fs) => {
+                let size = ((fs / bin_spacing / 4.0).round() * 4.0) as usize;
+                if size as f64 * bin_spacing != fs || size < min_size {
+                    return Err(format!(
+                        "Multi-band filter fs={} is not possible, nearest possible is fs={}",
+                        fs, min_size as f64 * bin_spacing).into());
+                }
+                size
+            },
+            None => min_size,
+        };
+        if size > fft_info.size {
+            return Err(format!(
+                "Multi-band filter fs={} is wider than input bandwidth of {} Hz",
+                size as f64 * bin_spacing, fft_info.fs).into());
+        }
+
+        let mut planner = FftPlanner::new();
+        Ok(Self {
+            fft_size: fft_info.size,
+            segments,
+            ifft: planner.plan_fft_inverse(size),
+            ffts: 0,
+        })
+    }
+
+    /// Output sample rate
+    pub fn fs_out(&self, fft_info: FftInfo) -> f64 {
+        self.ifft.len() as f64 * fft_info.fs / fft_info.size as f64
+    }
+
+    /// Output frequencies of the centers of the bands
+    pub fn band_offsets(&self, fft_info: FftInfo) -> Vec<f64> {
+        let bin_spacing = fft_info.fs / fft_info.size as f64;
+        let half = (self.ifft.len() / 2) as f64;
+        self.segments.iter().map(|s|
+            (s.out_first as f64 + s.weights.len() as f64 / 2.0 - half) * bin_spacing
+        ).collect()
+    }
+
+    /// Number of output samples produced from each FFT result
+    pub fn samples_per_fft(&self) -> usize {
+        // fixed 25% overlap
+        self.ifft.len() / 4 * 3
+    }
+
+    /// Process the FFT results of a processing block.
+    /// Output buffer is replaced with the resulting samples.
+    pub fn process_block(
+        &mut self,
+        fft_results: &[&mut[Complex<f32>]],
+        output: &mut Vec<Complex<f32>>,
+    ) {
+        let n = self.samples_per_fft();
+        output.resize(n * fft_results.len(), Complex{ re: 0.0, im: 0.0 });
+        for (i, (out, fft_result)) in output.chunks_mut(n).zip(fft_results.iter()).enumerate() {
+            self.process(fft_result, self.ffts + i as u64, out);
+        }
+        self.ffts += fft_results.len() as u64;
+    }
+
+    /// Process one FFT result and write the resulting samples to output.
+    fn process(
+        &self,
+        fft_result: &[Complex<f32>],
+        fft_number: u64,
+        output: &mut [Complex<f32>],
+    ) {
+        let size = self.ifft.len();
+        let half = size / 2;
+        let mut buf = vec![Complex{ re: 0.0, im: 0.0 }; size];
+        for segment in self.segments.iter() {
+            // Each band is shifted by a different number of bins,
+            // so correct the phase rotation separately for each,
+            // like in the channelizer.
+            let shift = segment.first - segment.out_first as isize + half as isize;
+            let rotation = quarter_turn_rotation(shift, fft_number, -1.0);
+            for (j, &w) in segment.weights.iter().enumerate() {
+                let i = (segment.out_first + j + half) % size;
+                buf[i] = get_bin(fft_result, self.fft_size, segment.first + j as isize) * rotation * w;
+            }
+        }
+        self.ifft.process(&mut buf);
+
+        // fixed 25% overlap, discard 1/8 from each end
+        let discard = size / 8;
+        output.copy_from_slice(&buf[discard .. discard + output.len()]);
+    }
+}
+
+
+#[test]
+fn test_multiband() {
+    // Tones in two separate bands should come out at the
+    // frequencies given by the mapping, with continuous phase.
+    let fft_info = FftInfo { fs: 102400.0, fc: 0.0, size: 1024, complex: true };
+    let fft_interval = fft_info.size / 4 * 3;
+    let mut mb = MultibandDsp::init(fft_info, &MultibandParams {
+        bands: vec![
+            Band { fc: -20000.0, bw: 3200.0, gain: 0.0 },
+            Band { fc: 31000.0, bw: 4000.0, gain: -6.0 },
+        ],
+        fs_out: None,
+        format: SignalFormat::Cf32,
+        gain: 0.0,
+        output: OutputParams { filename: None },
+    }).unwrap();
+    assert!(mb.fs_out(fft_info) == 7200.0);
+    let offsets = mb.band_offsets(fft_info);
+    assert!(offsets == [-3600.0 + 1600.0, -3600.0 + 3200.0 + 2000.0]);
+
+    let tones = [(-20000.0 + 130.0, 1.0), (31000.0 - 210.0, 10.0f64.powf(-6.0 / 20.0))];
+    let mut planner = FftPlanner::new();
+    let fft = planner.plan_fft_forward(fft_info.size);
+    let mut fft_results: Vec<Vec<Complex<f32>>> = (0..16).map(|b| {
+        let mut buf: Vec<Complex<f32>> = (0..fft_info.size).map(|i| {
+            let t = (b * fft_interval + i) as f64 / fft_info.fs;
+            tones.iter().map(|&(f, _)| {
+                let phase = 2.0 * std::f64::consts::PI * f * t;
+                Complex{ re: phase.cos() as f32, im: phase.sin() as f32 }
+            }).sum::<Complex<f32>>() / fft_info.size as f32
+        }).collect();
+        fft.process(&mut buf);
+        buf
+    }).collect();
+    let results: Vec<&mut [Complex<f32>]> = fft_results.iter_mut().map(|r| &mut r[..]).collect();
+    let mut output = Vec::new();
+    let mut samples = Vec::new();
+    for block in results.chunks(4) {
+        mb.process_block(block, &mut output);
+        samples.extend_from_slice(&output);
+    }
+
+    // Output sample n is at input time (n + size/8) / fs_out
+    let delay = 72 / 8;
+    let band_fc = [-20000.0, 31000.0];
+    for (n, s) in samples.iter().enumerate() {
+        let t = (n + delay) as f64 / 7200.0;
+        let expected: Complex<f32> = tones.iter().zip(band_fc.iter()).zip(offsets.iter())
+            .map(|((&(f, gain), &fc), &offset)| {
+                // Phase of the tone is kept from the input,
+                // frequency is mapped to the output
+                let phase = 2.0 * std::f64::consts::PI * (f * t - (fc - offset) * t);
+                Complex{ re: phase.cos() as f32, im: phase.sin() as f32 } * gain as f32
+            }).sum();
+        assert!((s - expected).norm() < 0.03, "sample {}: {} != {}", n, s, expected);
+    }
+}
@@ -11,6 +11,7 @@ use std::io::prelude::*;
 //use zmq;
 
 
+#[derive(Clone)]
 pub struct OutputParams {
     pub filename: Option<String>,
 }
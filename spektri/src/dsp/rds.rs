@@ -0,0 +1,290 @@
+//! RDS (Radio Data System) decoder
+//!
+//! Input is the RDS subcarrier mixed down to baseband and resampled
+//! to 8 samples per bit, i.e. 9500 Hz. Symbol timing is recovered
+//! by choosing the sampling offset that gives the most energy.
+//! Differential decoding makes the carrier phase irrelevant,
+//! so no carrier recovery is needed.
+
+use rustfft::num_complex::Complex;
+use byte::{BytesExt, LE};
+
+/// Sample rate of the input to the decoder
+pub const RDS_SAMPLE_RATE: f64 = 9500.0;
+/// Number of samples per bit
+const SAMPLES_PER_BIT: usize = 8;
+
+/// Offset words added to the check bits of each block type
+const OFFSET_A:  u16 = 0x0FC;
+const OFFSET_B:  u16 = 0x198;
+const OFFSET_C:  u16 = 0x168;
+const OFFSET_CP: u16 = 0x350;
+const OFFSET_D:  u16 = 0x1B4;
+
+/// Number of consecutive bad blocks after which
+/// block synchronization is considered lost
+const MAX_BAD_BLOCKS: u32 = 10;
+
+/// Calculate the 10 check bits for 16 information bits.
+pub fn rds_checkword(info: u16) -> u16 {
+    // Generator polynomial x^10 + x^8 + x^7 + x^5 + x^4 + x^3 + 1
+    let poly: u32 = 0x5B9;
+    let mut reg = (info as u32) << 10;
+    for i in (10..26).rev() {
+        if reg & (1 << i) != 0 {
+            reg ^= poly << (i - 10);
+        }
+    }
+    (reg & 0x3FF) as u16
+}
+
+/// Return the block number (0 to 3) of a received 26-bit block,
+/// or None if the check bits do not match any offset word.
+fn block_number(block: u32) -> Option<usize> {
+    match rds_checkword((block >> 10) as u16) ^ (block & 0x3FF) as u16 {
+        OFFSET_A => Some(0),
+        OFFSET_B => Some(1),
+        OFFSET_C | OFFSET_CP => Some(2),
+        OFFSET_D => Some(3),
+        _ => None,
+    }
+}
+
+
+/// Information decoded about the station
+pub struct RdsStation {
+    pub pi: u16,
+    pub pty: u8,
+    pub tp: bool,
+    pub ta: bool,
+    pub ms: bool,
+    /// Program service name
+    pub ps: [u8; 8],
+    /// RadioText
+    pub rt: [u8; 64],
+    /// Text A/B flag of the RadioText being received
+    rt_ab: bool,
+}
+
+impl RdsStation {
+    fn init() -> Self {
+        Self {
+            pi: 0,
+            pty: 0,
+            tp: false,
+            ta: false,
+            ms: false,
+            ps: [b' '; 8],
+            rt: [b' '; 64],
+            rt_ab: false,
+        }
+    }
+
+    fn decode_group(&mut self, g: &[u16; 4]) {
+        let group_type = g[1] >> 12;
+        let version_b = g[1] & 0x800 != 0;
+        self.pi = g[0];
+        self.tp = g[1] & 0x400 != 0;
+        self.pty = ((g[1] >> 5) & 0x1F) as u8;
+        let chars = |w: u16| [(w >> 8) as u8, (w & 0xFF) as u8];
+        match group_type {
+            0 => {
+                self.ta = g[1] & 0x10 != 0;
+                self.ms = g[1] & 0x08 != 0;
+                let segment = (g[1] & 3) as usize;
+                self.ps[segment * 2 .. segment * 2 + 2].copy_from_slice(&chars(g[3]));
+            },
+            2 => {
+                let ab = g[1] & 0x10 != 0;
+                if ab != self.rt_ab {
+                    // New text is being sent
+                    self.rt = [b' '; 64];
+                    self.rt_ab = ab;
+                }
+                let segment = (g[1] & 0xF) as usize;
+                if version_b {
+                    self.rt[segment * 2 .. segment * 2 + 2].copy_from_slice(&chars(g[3]));
+                } else {
+                    self.rt[segment * 4 .. segment * 4 + 2].copy_from_slice(&chars(g[2]));
+                    self.rt[segment * 4 + 2 .. segment * 4 + 4].copy_from_slice(&chars(g[3]));
+                }
+            },
+            _ => {},
+        }
+    }
+}
+
+
+pub struct RdsDecoder {
+    // Symbol timing recovery
+    /// Last 2 bits worth of samples, twice for "fake circular buffering"
+    buf: [Complex<f32>; 4 * SAMPLES_PER_BIT],
+    buf_i: usize,
+    /// Average symbol energy for each sampling offset
+    energy: [f32; SAMPLES_PER_BIT],
+    offset: usize,
+    prev_symbol: Complex<f32>,
+
+    // Block synchronization
+    /// Last 26 received bits
+    shiftreg: u32,
+    /// Number of bits received since the last block
+    bits: usize,
+    synchronized: bool,
+    /// Expected number of the next block
+    next_block: usize,
+    bad_blocks: u32,
+    /// Group being received and which of its blocks were good
+    group: [u16; 4],
+    group_ok: [bool; 4],
+
+    pub station: RdsStation,
+    /// Groups decoded since last call to clear_groups
+    pub groups: Vec<[u16; 4]>,
+}
+
+impl RdsDecoder {
+    pub fn init() -> Self {
+        Self {
+            buf: [Complex{ re: 0.0, im: 0.0 }; 4 * SAMPLES_PER_BIT],
+            buf_i: 0,
+            energy: [0.0; SAMPLES_PER_BIT],
+            offset: 0,
+            prev_symbol: Complex{ re: 0.0, im: 0.0 },
+            shiftreg: 0,
+            bits: 0,
+            synchronized: false,
+            next_block: 0,
+            bad_blocks: 0,
+            group: [0; 4],
+            group_ok: [false; 4],
+            station: RdsStation::init(),
+            groups: Vec::new(),
+        }
+    }
+
+    pub fn clear_groups(&mut self) {
+        self.groups.clear();
+    }
+
+    /// Process baseband samples at RDS_SAMPLE_RATE.
+    pub fn process(&mut self, input: &[Complex<f32>]) {
+        let n = 2 * SAMPLES_PER_BIT;
+        for &s in input.iter() {
+            self.buf[self.buf_i] = s;
+            self.buf[self.buf_i + n] = s;
+            self.buf_i = (self.buf_i + 1) % n;
+            let offset = self.buf_i % SAMPLES_PER_BIT;
+
+            // Correlate the last bit period with a biphase symbol
+            let bit = &self.buf[self.buf_i + SAMPLES_PER_BIT .. self.buf_i + n];
+            let half = SAMPLES_PER_BIT / 2;
+            let first:  Complex<f32> = bit[.. half].iter().sum();
+            let second: Complex<f32> = bit[half ..].iter().sum();
+            let symbol = first - second;
+
+            self.energy[offset] += (symbol.norm_sqr() - self.energy[offset]) * 0.01;
+            if offset == self.offset {
+                // Differential decoding
+                let bit = (symbol * self.prev_symbol.conj()).re < 0.0;
+                self.prev_symbol = symbol;
+                self.receive_bit(bit);
+                // Choose the best sampling offset once per bit
+                self.offset = (0..SAMPLES_PER_BIT).fold(0, |best, i|
+                    if self.energy[i] > self.energy[best] { i } else { best });
+            }
+        }
+    }
+
+    fn receive_bit(&mut self, bit: bool) {
+        self.shiftreg = ((self.shiftreg << 1) | bit as u32) & 0x3FF_FFFF;
+        self.bits += 1;
+        if !self.synchronized {
+            // Search for any valid block at any bit position.
+            // Synchronize when the next block is found right after it.
+            if let Some(block) = block_number(self.shiftreg) {
+                if self.bits == 26 && block == self.next_block {
+                    self.synchronized = true;
+                    self.bad_blocks = 0;
+                    self.group_ok = [false; 4];
+                    self.receive_block(block, true);
+                } else {
+                    self.next_block = (block + 1) % 4;
+                }
+                self.bits = 0;
+            }
+        } else if self.bits == 26 {
+            self.bits = 0;
+            let block = self.next_block;
+            let ok = block_number(self.shiftreg) == Some(block);
+            if ok {
+                self.bad_blocks = 0;
+            } else {
+                self.bad_blocks += 1;
+                if self.bad_blocks > MAX_BAD_BLOCKS {
+                    self.synchronized = false;
+                }
+            }
+            self.receive_block(block, ok);
+        }
+    }
+
+    fn receive_block(&mut self, block: usize, ok: bool) {
+        self.group[block] = (self.shiftreg >> 10) as u16;
+        self.group_ok[block] = ok;
+        self.next_block = (block + 1) % 4;
+        if block == 3 {
+            if self.group_ok.iter().all(|&ok| ok) {
+                self.station.decode_group(&self.group);
+                self.groups.push(self.group);
+            }
+            self.group_ok = [false; 4];
+        }
+    }
+
+    /// Number of bytes needed to serialize a given number of groups
+    pub fn serialized_size(groups: usize) -> usize {
+        84 + 8 * groups
+    }
+
+    /// Serialize the station information and groups decoded
+    /// since the last call to clear_groups.
+    pub fn serialize(
+        &self,
+        buf: &mut [u8],
+        offset: &mut usize,
+        stereo: bool, // Stereo pilot detected
+    ) -> byte::Result<()> {
+        let st = &self.station;
+        buf.write_with(offset, st.pi, LE)?;
+        buf.write_with(offset, st.pty, LE)?;
+        let flags: u8 =
+            (st.tp as u8) |
+            (st.ta as u8) << 1 |
+            (st.ms as u8) << 2 |
+            (stereo as u8) << 3;
+        buf.write_with(offset, flags, LE)?;
+        buf[*offset .. *offset + 8].copy_from_slice(&st.ps);
+        *offset += 8;
+        buf[*offset .. *offset + 64].copy_from_slice(&st.rt);
+        *offset += 64;
+        buf.write_with(offset, self.groups.len() as u32, LE)?;
+        // Reserved
+        buf.write_with(offset, 0u32, LE)?;
+        for group in self.groups.iter() {
+            for &block in group.iter() {
+                buf.write_with(offset, block, LE)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+
+#[test]
+fn test_checkword() {
+    // Block A of a group with PI code 0x6201 as an example
+    let block = ((0x6201u32) << 10) | (rds_checkword(0x6201) ^ OFFSET_A) as u32;
+    assert!(block_number(block) == Some(0));
+    assert!(block_number(block ^ 0x100).is_none());
+}
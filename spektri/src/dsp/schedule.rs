@@ -0,0 +1,196 @@
+//! Time schedules for filters
+//!
+//! Times are given in UTC using the ISO 8601 basic format,
+//! since the : character separates filter parameters on the command line:
+//! either a date and time as YYYYMMDDTHHMMSS (optionally followed by Z)
+//! or only a time of day as HHMM or HHMMSS.
+//! A time of day refers to the current day when the schedule is first used.
+//!
+//! All times are handled as seconds since the Unix epoch.
+
+use std::str::FromStr;
+
+const DAY: f64 = 86400.0;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ScheduleTime {
+    /// Seconds since the Unix epoch
+    Absolute(f64),
+    /// Seconds since midnight
+    TimeOfDay(f64),
+}
+
+impl FromStr for ScheduleTime {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || format!("Invalid time {}, use YYYYMMDDTHHMMSS, HHMMSS or HHMM in UTC", s);
+        let digits = |s: &str| -> Result<i64, String> {
+            if !s.is_empty() && s.bytes().all(|c| c.is_ascii_digit()) { s.parse().map_err(|_| err()) } else { Err(err()) }
+        };
+        let time_of_day = |s: &str| -> Result<f64, String> {
+            let (h, m, sec) = match s.len() {
+                4 => (digits(&s[0..2])?, digits(&s[2..4])?, 0),
+                6 => (digits(&s[0..2])?, digits(&s[2..4])?, digits(&s[4..6])?),
+                _ => return Err(err()),
+            };
+            if h > 23 || m > 59 || sec > 59 { return Err(err()); }
+            Ok((h * 3600 + m * 60 + sec) as f64)
+        };
+        let s = s.strip_suffix('Z').unwrap_or(s);
+        match s.split_once('T') {
+            Some((date, time)) => {
+                if date.len() != 8 { return Err(err()); }
+                let (y, m, d) = (digits(&date[0..4])?, digits(&date[4..6])?, digits(&date[6..8])?);
+                if !(1..=12).contains(&m) || !(1..=31).contains(&d) { return Err(err()); }
+                Ok(ScheduleTime::Absolute(days_from_civil(y, m, d) as f64 * DAY + time_of_day(time)?))
+            },
+            None => Ok(ScheduleTime::TimeOfDay(time_of_day(s)?)),
+        }
+    }
+}
+
+/// When a filter is running.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Schedule {
+    /// Start immediately if None
+    pub start: Option<ScheduleTime>,
+    /// Time to stop. If both stop and duration are None, run forever.
+    pub stop: Option<ScheduleTime>,
+    /// Duration in seconds, used if stop is None
+    pub duration: Option<f64>,
+    /// Repeat with this period in seconds
+    pub repeat: Option<f64>,
+}
+
+impl Schedule {
+    /// Return the current or next time window (start, stop) in which
+    /// a filter should run, or None if the schedule is over.
+    pub fn next_window(
+        &self,
+        now: f64,
+        first_used: f64, // Time when the schedule was first used
+    ) -> Option<(f64, f64)> {
+        let today = (first_used / DAY).floor() * DAY;
+        let start = match self.start {
+            None => first_used,
+            Some(ScheduleTime::Absolute(t)) => t,
+            Some(ScheduleTime::TimeOfDay(t)) => today + t,
+        };
+        let length = match (self.stop, self.duration) {
+            (Some(ScheduleTime::Absolute(t)), _) => t - start,
+            (Some(ScheduleTime::TimeOfDay(t)), _) => {
+                // First time of day after start
+                let l = (t - start).rem_euclid(DAY);
+                if l == 0.0 { DAY } else { l }
+            },
+            (None, Some(d)) => d,
+            (None, None) => f64::INFINITY,
+        };
+        if length <= 0.0 {
+            return None;
+        }
+        let start = match self.repeat {
+            Some(period) if now >= start + length => {
+                // First repetition which has not ended yet
+                start + (((now - start - length) / period).floor() + 1.0) * period
+            },
+            Some(_) => start,
+            // Without repeating, a time of day which has already passed
+            // refers to the next day.
+            None => match self.start {
+                Some(ScheduleTime::TimeOfDay(_)) if first_used >= start + length => start + DAY,
+                _ => start,
+            },
+        };
+        if now >= start + length {
+            None
+        } else {
+            Some((start, start + length))
+        }
+    }
+}
+
+
+/// Number of days since 1970-01-01 for a date in the proleptic Gregorian calendar.
+fn days_from_civil(y: i64, m: i64, d: i64) -> i64 {
+    // Algorithm from http://howardhinnant.github.io/date_algorithms.html
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Date (year, month, day) for a number of days since 1970-01-01.
+fn civil_from_days(z: i64) -> (i64, i64, i64) {
+    let z = z + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    (yoe + era * 400 + if m <= 2 { 1 } else { 0 }, m, d)
+}
+
+/// Format a time given in seconds since the Unix epoch
+/// as YYYYMMDDTHHMMSSZ, suitable for file names.
+pub fn format_time(t: f64) -> String {
+    let secs = t.floor() as i64;
+    let (y, m, d) = civil_from_days(secs.div_euclid(86400));
+    let s = secs.rem_euclid(86400);
+    format!("{:04}{:02}{:02}T{:02}{:02}{:02}Z", y, m, d, s / 3600, s / 60 % 60, s % 60)
+}
+
+/// Format a time given in seconds since the Unix epoch
+/// as an ISO 8601 date and time with microseconds, e.g. for SigMF.
+pub fn format_datetime(t: f64) -> String {
+    let micros = (t * 1e6).round() as i64;
+    let secs = micros.div_euclid(1000000);
+    let (y, m, d) = civil_from_days(secs.div_euclid(86400));
+    let s = secs.rem_euclid(86400);
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:06}Z",
+        y, m, d, s / 3600, s / 60 % 60, s % 60, micros.rem_euclid(1000000))
+}
+
+/// Convert a system time to seconds since the Unix epoch.
+pub fn unix_time(t: std::time::SystemTime) -> f64 {
+    match t.duration_since(std::time::UNIX_EPOCH) {
+        Ok(d) => d.as_secs_f64(),
+        Err(_) => 0.0,
+    }
+}
+
+
+#[test]
+fn test_schedule() {
+    let t: ScheduleTime = "20261018T063000Z".parse().unwrap();
+    assert!(t == ScheduleTime::Absolute(1792305000.0));
+    assert!(format_time(1792305000.0) == "20261018T063000Z");
+    assert!(format_datetime(1792305000.25) == "2026-10-18T06:30:00.250000Z");
+    assert!("0630".parse::<ScheduleTime>() == Ok(ScheduleTime::TimeOfDay(6.5 * 3600.0)));
+    assert!("06:30".parse::<ScheduleTime>().is_err());
+
+    // Daily 10 minute recording at 06:30, program started at 07:00
+    let daily = Schedule {
+        start: Some(ScheduleTime::TimeOfDay(6.5 * 3600.0)),
+        duration: Some(600.0),
+        repeat: Some(DAY),
+        ..Default::default()
+    };
+    let started = 1792305000.0 + 1800.0;
+    assert!(daily.next_window(started, started) == Some((1792305000.0 + DAY, 1792305600.0 + DAY)));
+    assert!(daily.next_window(1792305300.0 + 3.0 * DAY, started) == Some((1792305000.0 + 3.0 * DAY, 1792305600.0 + 3.0 * DAY)));
+
+    // Stop time of day before start time of day means the next day
+    let overnight = Schedule {
+        start: Some(ScheduleTime::TimeOfDay(22.0 * 3600.0)),
+        stop: Some(ScheduleTime::TimeOfDay(2.0 * 3600.0)),
+        ..Default::default()
+    };
+    let midnight = 1792281600.0;
+    assert!(overnight.next_window(midnight, midnight) == Some((midnight + 22.0 * 3600.0, midnight + 26.0 * 3600.0)));
+    assert!(overnight.next_window(midnight + 27.0 * 3600.0, midnight).is_none());
+}
@@ -18,6 +18,44 @@
 //! Here, however, it lets us use a rectangular FFT window, which works
 //! better for a fast-convolution filter-bank, allowing the use of the same
 //! FFT results for both a filter bank and spectrum analysis.
+//!
+//! For complex input, bins are output in ascending frequency order
+//! starting from -fs/2. The bin at -fs/2 (or fs/2 for real input)
+//! covers both edges of the band, so it can be left out.
+//!
+//! Several spectrum products with different parameters can be made
+//! from the same FFT results. Each has its own accumulator.
+//! Output of a product can be cropped to a frequency range and
+//! adjacent bins merged to reduce the number of bins, e.g. for display.
+//! Values can be calibrated to dBFS, dBm or dBm/Hz,
+//! see the calibration module.
+//!
+//! Spectral kurtosis (SK) of each bin can be computed to tell Gaussian
+//! noise apart from impulsive interference. It is estimated from the sums
+//! of power and squared power over M FFTs as
+//! SK = (M+1)/(M-1) * (M * S2 / S1^2 - 1),
+//! which is 1 for Gaussian noise, and given in dB so that
+//! Gaussian noise is at 0 dB. SK is not calibrated.
+//! SK can also be used to flag interference: power is then summed
+//! over blocks of FFTs, and a block is left out of the mean of a bin
+//! if its SK deviates from 1 by more than a threshold.
+//! Note that steady signals such as CW carriers have an SK near 0,
+//! so flagging rejects them as well. Where every block of a bin is
+//! flagged, the mean of all FFTs is output instead, and with
+//! FlagMode::Ffts, carriers in many bins may make every block flagged.
+//!
+//! Signals can be detected from the mean of each record,
+//! see the detector module.
+//!
+//! The averaging period can be given as a number of FFTs or in seconds.
+//! Records can also be aligned to multiples of the period in UTC,
+//! so that records from different receivers cover the same intervals.
+//! Time of each FFT is then estimated from the system time
+//! of the processing block and the sample rate.
+//!
+//! Instead of averaging over each record, the mean power can also be
+//! computed as an exponential moving average, which keeps running
+//! across records. Its time constant is independent of the record interval.
 
 use rustfft::num_complex::Complex;
 use zmq;
@@ -25,58 +63,411 @@ use super::fftutil::*;
 use super::Metadata;
 use super::output::*;
 use super::data::*;
+use super::calibration::*;
+use super::detector::*;
 
 arg_enum! { // needed for command line parsing
+    /// Output formats of spectrum data.
+    /// U16 is big endian for compatibility with old files,
+    /// U16le should be preferred.
     #[derive(Debug, Copy, Clone)]
-    pub enum SpectrumFormat { U8, U16 }
+    pub enum SpectrumFormat { U8, U16, U16le, F32 }
+}
+
+impl SpectrumFormat {
+    /// Code used for the format in the topic
+    pub fn code(self) -> u8 {
+        match self {
+            SpectrumFormat::U8    => DataFormat::U8 as u8,
+            SpectrumFormat::U16   => 0x29, // real unsigned 16-bit, big endian
+            SpectrumFormat::U16le => 0x28, // real unsigned 16-bit, little endian
+            SpectrumFormat::F32   => DataFormat::F32le as u8,
+        }
+    }
+
+    pub fn bytes_per_bin(self) -> usize {
+        match self {
+            SpectrumFormat::U8    => 1,
+            SpectrumFormat::U16   => 2,
+            SpectrumFormat::U16le => 2,
+            SpectrumFormat::F32   => 4,
+        }
+    }
+
+    /// Scale and offset such that a value v corresponds to
+    /// v * scale + offset dB
+    pub fn db_scale_offset(self) -> (f32, f32) {
+        match self {
+            // 0.5 dB per LSB, full scale at 250
+            SpectrumFormat::U8    => (0.5, -125.0),
+            // 0.05 dB per LSB, full scale at 4000, clamped to 12 bits
+            SpectrumFormat::U16   |
+            SpectrumFormat::U16le => (0.05, -200.0),
+            SpectrumFormat::F32   => (1.0, 0.0),
+        }
+    }
+}
+
+arg_enum! { // needed for command line parsing
+    /// Window functions for spectrum analysis
+    #[derive(Debug, Copy, Clone, PartialEq)]
+    pub enum SpectrumWindow { Rectangular, Hann, BlackmanHarris }
+}
+
+impl SpectrumWindow {
+    /// Code used for the window in the topic
+    fn code(self) -> u8 {
+        match self {
+            SpectrumWindow::Rectangular    => SPECTRUM_WINDOW_RECTANGULAR,
+            SpectrumWindow::Hann           => SPECTRUM_WINDOW_HANN,
+            SpectrumWindow::BlackmanHarris => SPECTRUM_WINDOW_BLACKMAN_HARRIS,
+        }
+    }
+
+    /// Kernel of the convolution in frequency domain equivalent to
+    /// multiplying with the window function before the FFT.
+    /// Scaled so that the center tap is 1.
+    fn kernel(self) -> Vec<f32> {
+        match self {
+            SpectrumWindow::Rectangular => vec![1.0],
+            SpectrumWindow::Hann => vec![-0.5, 1.0, -0.5],
+            SpectrumWindow::BlackmanHarris => {
+                // 4-term Blackman-Harris window
+                let a = [0.35875, 0.48829, 0.14128, 0.01168];
+                let c: Vec<f32> = a.iter().map(|v| v / a[0]).collect();
+                vec![-c[3] / 2.0, c[2] / 2.0, -c[1] / 2.0, 1.0, -c[1] / 2.0, c[2] / 2.0, -c[3] / 2.0]
+            },
+        }
+    }
+}
+
+arg_enum! { // needed for command line parsing
+    /// How adjacent bins are combined when they are merged
+    #[derive(Debug, Copy, Clone, PartialEq)]
+    pub enum SpectrumReduction { Mean, Max, Min }
+}
+
+/// Statistic of bin power computed over each averaging interval
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SpectrumStatistic {
+    Mean,
+    Max,
+    Min,
+    /// Approximate percentile, found from a histogram
+    /// with the resolution of the U8 format
+    Percentile(u8),
+    /// Exponential moving average
+    Ema,
+    /// Spectral kurtosis estimator in dB
+    Kurtosis,
+}
+
+impl std::str::FromStr for SpectrumStatistic {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mean" => Ok(SpectrumStatistic::Mean),
+            "max" => Ok(SpectrumStatistic::Max),
+            "min" => Ok(SpectrumStatistic::Min),
+            "median" => Ok(SpectrumStatistic::Percentile(50)),
+            "ema" => Ok(SpectrumStatistic::Ema),
+            "sk" => Ok(SpectrumStatistic::Kurtosis),
+            _ => s.strip_prefix('p')
+                .and_then(|p| p.parse().ok())
+                .filter(|&p| p <= 100)
+                .map(SpectrumStatistic::Percentile)
+                .ok_or_else(|| format!("Unknown spectrum statistic {}, should be mean, max, min, median, p0-p100, ema or sk", s)),
+        }
+    }
+}
+
+impl SpectrumStatistic {
+    /// Codes used for the statistic and percentile in the topic
+    fn code(self) -> (u8, u8) {
+        match self {
+            SpectrumStatistic::Mean          => (SPECTRUM_STATISTIC_MEAN, 0),
+            SpectrumStatistic::Max           => (SPECTRUM_STATISTIC_MAX, 0),
+            SpectrumStatistic::Min           => (SPECTRUM_STATISTIC_MIN, 0),
+            SpectrumStatistic::Percentile(p) => (SPECTRUM_STATISTIC_PERCENTILE, p),
+            SpectrumStatistic::Ema           => (SPECTRUM_STATISTIC_EMA, 0),
+            SpectrumStatistic::Kurtosis      => (SPECTRUM_STATISTIC_KURTOSIS, 0),
+        }
+    }
+}
+
+/// Parameters of a spectrum statistic output
+pub struct SpectrumStatParams {
+    pub statistic: SpectrumStatistic,
+    pub output: OutputParams,
+}
+
+arg_enum! { // needed for command line parsing
+    /// What is left out of the mean when spectral kurtosis flags interference
+    #[derive(Debug, Copy, Clone, PartialEq)]
+    pub enum FlagMode {
+        // Flagged bins of a block of FFTs
+        Bins,
+        // All bins of a block of FFTs with more than FLAGGED_FRACTION of bins flagged
+        Ffts
+    }
+}
+
+/// Parameters for flagging interference using spectral kurtosis
+#[derive(Copy, Clone, Debug)]
+pub struct SpectrumFlagParams {
+    pub mode: FlagMode,
+    pub ffts: u32, // Number of FFTs in each block
+    pub threshold: f32, // Flag if SK deviates from 1 by more than this many standard deviations
+}
+
+/// Blocks of FFTs with more than this fraction of bins flagged
+/// are left out completely in FlagMode::Ffts
+const FLAGGED_FRACTION: f32 = 0.1;
+
+/// Parameters of a spectrum product
+pub struct SpectrumParams {
+    pub averages: u32, // Number of FFTs averaged
+    pub period: Option<f64>, // Averaging period in seconds, overrides averages
+    pub align: bool, // Align records to multiples of the period in UTC
+    pub time_constant: f64, // Time constant of the exponential moving average in seconds
+    pub format: SpectrumFormat, // Output format for spectrum data
+    pub window: SpectrumWindow,
+    pub merge: usize, // Number of adjacent bins merged into one
+    pub range: Option<(f64, f64)>, // Lowest and highest frequency output
+    pub max_bins: Option<usize>, // Merge more bins if needed to output at most this many
+    pub reduction: Option<SpectrumReduction>, // How bins are merged, depends on statistic if None
+    pub unit: PowerUnit,
+    pub flag: Option<SpectrumFlagParams>, // Leave out interference flagged by spectral kurtosis from the mean
+    pub detector: Option<DetectorParams>, // Detect signals from the mean
+    pub drop_edge: bool, // Leave out the bin at the Nyquist frequency
+    pub outputs: Vec<SpectrumStatParams>, // Statistics and where they are written
+}
+
+/// Defaults used for parameters which are not given.
+/// No statistics are output by default.
+impl Default for SpectrumParams {
+    fn default() -> Self {
+        Self {
+            averages: 2000,
+            period: None,
+            align: false,
+            time_constant: 1.0,
+            format: SpectrumFormat::U8,
+            window: SpectrumWindow::Hann,
+            merge: 1,
+            range: None,
+            max_bins: None,
+            reduction: None,
+            unit: PowerUnit::Dbfs,
+            flag: None,
+            detector: None,
+            drop_edge: false,
+            outputs: Vec::new(),
+        }
+    }
+}
+
+/// Number of levels in the histogram of each bin used for percentiles
+const HISTOGRAM_LEVELS: usize = 256;
+
+struct StatOutput {
+    statistic: SpectrumStatistic,
+    output: Output,
 }
 
 pub struct SpectrumAccumulator {
     /// Sequence number, number of results produced
     seq: u64,
+    /// Windowed power of each bin in the latest FFT result
+    power: Vec<f32>,
     /// Accumulator for FFT averaging
     acc: Vec<f32>,
     /// Counter for number of FFTs averaged
     accn: u32,
+    /// Maximum and minimum power of each bin, empty if not needed
+    max: Vec<f32>,
+    min: Vec<f32>,
+    /// Histogram of power in dB for each bin, empty if not needed
+    histogram: Vec<u32>,
+    /// Exponential moving average of power, empty if not needed.
+    /// Not reset between records.
+    ema: Vec<f32>,
+    /// Weight of the latest FFT in the moving average
+    ema_weight: f32,
+    /// Whether the moving average has been started
+    ema_started: bool,
+    /// Sums of power and squared power over the record
+    /// for spectral kurtosis, empty if not needed
+    sk_sum: Vec<[f32; 2]>,
+    /// Parameter: Flagging of interference
+    flag: Option<SpectrumFlagParams>,
+    /// Sums of power and squared power over the current block
+    /// of FFTs for flagging, empty if not needed
+    block_sum: Vec<[f32; 2]>,
+    /// Number of FFTs in the current block
+    block_n: u32,
+    /// Number of FFTs in the mean of each bin, used with flagging
+    bin_count: Vec<u32>,
+    /// Power of blocks left out by flagging
+    flagged_acc: Vec<f32>,
 
     /// Parameter: information about FFT results
     fft_info: FftInfo,
     /// Parameter: Number of FFTs averaged
     averages: u32,
+    /// Parameter: Period to align records to, in seconds
+    align_period: Option<f64>,
+    /// Index of the aligned period of the latest FFT,
+    /// counted from the Unix epoch
+    period_index: Option<u64>,
+    /// Whether the current aligned period started before the first FFT
+    partial: bool,
     /// Parameter: Output format for spectrum data
     outfmt: SpectrumFormat,
-    output: Output,
+    /// Parameter: Leave out the bin at the Nyquist frequency
+    drop_edge: bool,
+    /// Parameter: Number of adjacent bins merged into one
+    merge: usize,
+    /// Parameter: How bins are merged, depends on statistic if None
+    reduction: Option<SpectrumReduction>,
+    /// Range of bins output, in output order before merging
+    crop: std::ops::Range<usize>,
+    /// Calibration correction in dB for each bin in the range
+    correction: Vec<f32>,
+    /// Convolution kernel for the window function
+    kernel: Vec<f32>,
+    /// Statistics which are output
+    outputs: Vec<StatOutput>,
+    /// Detector of signals in the mean
+    detector: Option<Detector>,
 }
 
 impl SpectrumAccumulator {
+    /// product is the index of the spectrum product.
     pub fn init(
         fft_info: FftInfo,
-        averages: u32, // Number of FFTs averaged
-        outfmt: SpectrumFormat, // Output format for spectrum data
-    ) -> Self {
-        let spectrum_info = SpectrumInfo {
-            // TODO: implement "FFT shifting" when the input signal is complex.
-            // Fix f0 for that case.
-            f0: fft_info.fc,
-            // TODO: consider calculating spacing of FFT bins somewhere in one place.
-            fd: fft_info.fs / (fft_info.size as f64),
+        product: usize,
+        p: &SpectrumParams,
+        calibration: &Calibration,
+    ) -> Result<Self, String> {
+        let (db_scale, db_offset) = p.format.db_scale_offset();
+        // TODO: consider calculating spacing and number of FFT bins somewhere in one place.
+        let fd = fft_info.fs / (fft_info.size as f64);
+        let bins = if fft_info.complex { fft_info.size } else { fft_info.size/2+1 };
+
+        // Crop to bins whose center frequencies are within the range
+        let f0 = first_bin_frequency(fft_info, p.drop_edge);
+        let output_len = bins - if p.drop_edge { 1 } else { 0 };
+        let crop = match p.range {
+            Some((low, high)) => {
+                let start = ((low - f0) / fd).ceil().clamp(0.0, output_len as f64) as usize;
+                let end = (((high - f0) / fd).floor() + 1.0).clamp(start as f64, output_len as f64) as usize;
+                if start == end {
+                    return Err(format!("Spectrum frequency range {} to {} Hz has no bins", low, high));
+                }
+                start .. end
+            },
+            None => 0 .. output_len,
         };
+        let merge = match p.max_bins {
+            Some(n) => p.merge.max(crop.len().div_ceil(n.max(1))),
+            None => p.merge,
+        }.max(1);
+        // Extend the range to a whole number of merged bins,
+        // first upward and then downward if the spectrum ends.
+        let missing = crop.len().next_multiple_of(merge) - crop.len();
+        let end = (crop.end + missing).min(output_len);
+        let crop = crop.start.saturating_sub(missing - (end - crop.end)) .. end;
+        // Nominal number of FFTs averaged is also given for aligned periods,
+        // where the actual number may vary a bit due to timing jitter.
+        let ffts_per_second = fft_info.fs / fft_interval(fft_info) as f64;
+        let averages = match p.period {
+            Some(period) => ((period * ffts_per_second).round() as u32).max(1),
+            None => p.averages,
+        };
+        let ema_ffts = (p.time_constant * ffts_per_second).max(1.0);
 
-        Self {
+        // Window kernel is scaled to a coherent gain of 1,
+        // so its equivalent noise bandwidth is the sum of squares
+        let kernel = p.window.kernel();
+        let noise_bandwidth = fd * kernel.iter().map(|k| (k * k) as f64).sum::<f64>();
+        let correction = crop.clone().map(|k|
+            calibration.correction_db(p.unit, fft_info.complex, f0 + k as f64 * fd, noise_bandwidth)
+        ).collect();
+        // Detector sums merged bins, which are averaged over merge bins
+        let power_scale = match p.unit {
+            PowerUnit::DbmHz => fd * merge as f64,
+            _ => fd * merge as f64 / noise_bandwidth,
+        };
+        // Merged bin is centered at the middle of the bins merged
+        let out_f0 = f0 + (crop.start as f64 + (merge - 1) as f64 / 2.0) * fd;
+        let topic = |statistic: SpectrumStatistic| {
+            let averages = if statistic == SpectrumStatistic::Ema { ema_ffts.round() as u32 } else { averages };
+            let kurtosis = statistic == SpectrumStatistic::Kurtosis;
+            let (statistic, percentile) = statistic.code();
+            serialize_spectrum_topic(&SpectrumInfo {
+                f0: out_f0,
+                fd: fd * merge as f64,
+                format: p.format.code(),
+                db_scale,
+                db_offset,
+                fft_size: fft_info.size as u32,
+                averages,
+                window: p.window.code(),
+                statistic,
+                percentile,
+                unit: if kurtosis { SPECTRUM_UNIT_DBFS } else { p.unit.code() },
+                gain_table: !kurtosis && p.unit != PowerUnit::Dbfs && !calibration.gains.is_empty(),
+                fullscale_dbm: calibration.fullscale_dbm,
+                noise_bandwidth: noise_bandwidth as f32,
+            })
+        };
+        let outputs: Vec<StatOutput> = p.outputs.iter().map(|o| StatOutput {
+            statistic: o.statistic,
+            output: Output::init(&o.output, &topic(o.statistic)),
+        }).collect();
+
+        let needs = |f: fn(&SpectrumStatistic) -> bool| outputs.iter().any(|o| f(&o.statistic));
+        let histogram_size = if needs(|s| matches!(s, SpectrumStatistic::Percentile(_))) {
+            eprintln!("Using {:.1} MiB of memory for spectrum percentiles",
+                (bins * HISTOGRAM_LEVELS * std::mem::size_of::<u32>()) as f64 / 1048576.0);
+            bins * HISTOGRAM_LEVELS
+        } else {
+            0
+        };
+
+        Ok(Self {
             seq: 0,
-            // TODO: consider calculating number of FFT bins somewhere in one place.
-            acc: vec![0.0; if fft_info.complex { fft_info.size } else { fft_info.size/2+1 }],
+            power: vec![0.0; bins],
+            acc: vec![0.0; bins],
             accn: 0,
+            max: vec![0.0; if needs(|s| *s == SpectrumStatistic::Max) { bins } else { 0 }],
+            min: vec![f32::INFINITY; if needs(|s| *s == SpectrumStatistic::Min) { bins } else { 0 }],
+            histogram: vec![0; histogram_size],
+            ema: vec![0.0; if needs(|s| *s == SpectrumStatistic::Ema) { bins } else { 0 }],
+            ema_weight: (1.0 - (-1.0 / ema_ffts).exp()) as f32,
+            ema_started: false,
+            sk_sum: vec![[0.0; 2]; if needs(|s| *s == SpectrumStatistic::Kurtosis) { bins } else { 0 }],
+            flag: p.flag,
+            block_sum: vec![[0.0; 2]; if p.flag.is_some() { bins } else { 0 }],
+            block_n: 0,
+            bin_count: vec![0; if p.flag.is_some() { bins } else { 0 }],
+            flagged_acc: vec![0.0; if p.flag.is_some() { bins } else { 0 }],
             fft_info: fft_info,
-            averages: averages,
-            outfmt: outfmt,
-            output: Output::init(
-                &OutputParams {
-                    // Temporary hack for compatibility with old test scripts:
-                    filename: Some("/dev/stdout".to_string()),
-                },
-                &serialize_spectrum_topic(&spectrum_info)),
-        }
+            averages,
+            align_period: p.period.filter(|_| p.align),
+            period_index: None,
+            partial: true,
+            outfmt: p.format,
+            drop_edge: p.drop_edge,
+            merge,
+            reduction: p.reduction,
+            crop,
+            correction,
+            kernel,
+            outputs,
+            detector: p.detector.as_ref().map(|d| Detector::init(d, product, out_f0, fd * merge as f64, power_scale)),
+        })
     }
 
     pub fn accumulate(
@@ -86,80 +477,488 @@ impl SpectrumAccumulator {
         sock: &zmq::Socket, // ZeroMQ socket used to publish all results
         ) -> std::io::Result<()>
     {
-        for fft_result in fft_results.iter() {
-            // Perform convolution in frequency domain with -0.5, 1, -0.5,
-            // equivalent to applying a Hann window before the FFT.
+        for (i, fft_result) in fft_results.iter().enumerate() {
+            if let Some(period) = self.align_period {
+                // System time is taken when the block was received,
+                // so it is approximately the time of the last FFT.
+                let before = (fft_results.len() - 1 - i) * fft_interval(self.fft_info);
+                let time = metadata.systemtime - std::time::Duration::from_secs_f64(before as f64 / self.fft_info.fs);
+                self.next_period(time, period, metadata, sock);
+            }
+
+            // Perform convolution in frequency domain with the window kernel,
+            // e.g. -0.5, 1, -0.5 for a Hann window.
             // As an optimization, call getbin only for the first and last bins
             // where modulo indexing needs to be handled in a special way.
 
             // Special cases of first and last bins
             let fft_size = self.fft_info.size;
             let getbin = |i: isize| get_bin(fft_result, fft_size, i);
-            for &i in [ 0, self.acc.len()-1 ].iter() {
-                let c = getbin(i as isize)
-                      -(getbin(i as isize - 1) +
-                        getbin(i as isize + 1)) * 0.5;
-                self.acc[i] += c.re * c.re + c.im * c.im;
+            let kernel = &self.kernel;
+            let half = kernel.len() / 2;
+            let len = self.power.len();
+            for i in (0 .. half).chain(len - half .. len) {
+                let c = kernel.iter().enumerate().fold(Complex{ re: 0.0, im: 0.0 }, |c, (k, &w)|
+                    c + getbin(i as isize + k as isize - half as isize) * w);
+                self.power[i] = c.re * c.re + c.im * c.im;
             }
 
             // Faster way to process the rest of the bins
-            self.acc[1..].iter_mut().zip(fft_result.windows(3)).for_each(
-                |(acc_bin, w)|
+            self.power[half .. len - half].iter_mut().zip(fft_result.windows(kernel.len())).for_each(
+                |(power_bin, w)|
             {
-                let c = w[1] - (w[0] + w[2]) * 0.5;
-                *acc_bin += c.re * c.re + c.im * c.im;
+                let c = w.iter().zip(kernel.iter()).fold(Complex{ re: 0.0, im: 0.0 }, |c, (v, &k)| c + v * k);
+                *power_bin = c.re * c.re + c.im * c.im;
             });
 
-            // Count the number of FFTs accumulated
-            self.accn += 1;
-            let averages = self.averages;
-            if self.accn >= averages {
-                let outfmt = self.outfmt;
-                let mut outbuf: Vec<u8> = vec![
-                    0;
-                    100 + // TODO correct size of sample metadata
-                    self.acc.len() * match outfmt { SpectrumFormat::U16=>2, SpectrumFormat::U8=>1 }];
-                let mut offset = 0;
-
-                // unwrap is OK here because it would only panic if outbuf
-                // is too small for metadata. That would clearly be a bug.
-                serialize_metadata(&mut outbuf, &mut offset, &metadata, self.seq).unwrap();
+            self.add_power();
+            if self.align_period.is_none() && self.accn >= self.averages {
+                self.write(metadata, sock);
+            }
+        }
+        Ok(())
+    }
+
+    /// Write a record at the boundary between aligned periods.
+    /// Record of the period during which the program started
+    /// is left out, since it only covers part of the period.
+    fn next_period(
+        &mut self,
+        time: std::time::SystemTime,
+        period: f64,
+        metadata: &Metadata,
+        sock: &zmq::Socket,
+    ) {
+        let since_epoch = time.duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_secs_f64();
+        let index = (since_epoch / period).floor() as u64;
+        // Jitter of the system clock may move the time slightly backward,
+        // so only move forward to avoid writing spurious records.
+        if self.period_index.is_some_and(|previous| index <= previous) {
+            return;
+        }
+        if self.period_index.is_some() {
+            if self.partial || self.accn == 0 {
+                self.reset();
+            } else {
+                // Timestamp of the record is the end of the period
+                let end = std::time::UNIX_EPOCH + std::time::Duration::from_secs_f64(index as f64 * period);
+                self.write(&Metadata { seq: metadata.seq, systemtime: end }, sock);
+            }
+            self.partial = false;
+        }
+        self.period_index = Some(index);
+    }
 
+    /// Write the statistics accumulated so far and reset accumulators.
+    fn write(&mut self, metadata: &Metadata, sock: &zmq::Socket) {
+        if self.block_n > 0 {
+            self.end_block();
+        }
+        for i in 0..self.outputs.len() {
+            let statistic = self.outputs[i].statistic;
+            let db = self.statistic_db(statistic);
+            let outbuf = self.serialize(&self.output_values(statistic, &db), metadata);
+            if let Err(err) = self.outputs[i].output.write(&outbuf, sock) {
+                eprintln!("Error writing spectrum output: {}", err);
+            }
+        }
+        if self.detector.is_some() {
+            let db = self.statistic_db(SpectrumStatistic::Mean);
+            let values = self.output_values(SpectrumStatistic::Mean, &db);
+            if let Some(detector) = &mut self.detector {
+                detector.process(&values, metadata, self.seq, sock);
+            }
+        }
+        self.reset();
+        self.seq += 1;
+    }
+
+    fn reset(&mut self) {
+        self.acc.iter_mut().for_each(|v| *v = 0.0);
+        self.max.iter_mut().for_each(|v| *v = 0.0);
+        self.min.iter_mut().for_each(|v| *v = f32::INFINITY);
+        self.histogram.iter_mut().for_each(|v| *v = 0);
+        self.sk_sum.iter_mut().for_each(|v| *v = [0.0; 2]);
+        self.block_sum.iter_mut().for_each(|v| *v = [0.0; 2]);
+        self.block_n = 0;
+        self.bin_count.iter_mut().for_each(|v| *v = 0);
+        self.flagged_acc.iter_mut().for_each(|v| *v = 0.0);
+        self.accn = 0;
+    }
+
+    /// Update the statistics with the power of the latest FFT result.
+    fn add_power(&mut self) {
+        match self.flag {
+            Some(flag) => {
+                self.block_sum.iter_mut().zip(self.power.iter()).for_each(|(s, &p)| { s[0] += p; s[1] += p * p; });
+                self.block_n += 1;
+                if self.block_n >= flag.ffts {
+                    self.end_block();
+                }
+            },
+            None => self.acc.iter_mut().zip(self.power.iter()).for_each(|(a, p)| *a += p),
+        }
+        self.sk_sum.iter_mut().zip(self.power.iter()).for_each(|(s, &p)| { s[0] += p; s[1] += p * p; });
+        self.max.iter_mut().zip(self.power.iter()).for_each(|(m, &p)| *m = m.max(p));
+        self.min.iter_mut().zip(self.power.iter()).for_each(|(m, &p)| *m = m.min(p));
+        if !self.histogram.is_empty() {
+            let (scale, offset) = SpectrumFormat::U8.db_scale_offset();
+            for (h, p) in self.histogram.chunks_mut(HISTOGRAM_LEVELS).zip(self.power.iter()) {
+                let level = ((p.log10() * 10.0 - offset) / scale).clamp(0.0, (HISTOGRAM_LEVELS - 1) as f32) as usize;
+                h[level] += 1;
+            }
+        }
+        if !self.ema.is_empty() {
+            // Start from the first FFT instead of zero
+            let weight = if self.ema_started { self.ema_weight } else { 1.0 };
+            self.ema.iter_mut().zip(self.power.iter()).for_each(|(e, &p)| *e += (p - *e) * weight);
+            self.ema_started = true;
+        }
+
+        // Count the number of FFTs accumulated
+        self.accn += 1;
+    }
+
+    /// Add the unflagged bins of a block of FFTs to the mean.
+    fn end_block(&mut self) {
+        let flag = match self.flag {
+            Some(flag) => flag,
+            None => return,
+        };
+        let n = self.block_n;
+        // Too short blocks for flagging are always added
+        let limit = if n >= 2 { flag.threshold * spectral_kurtosis_std(n as f32) } else { f32::INFINITY };
+        let flagged: Vec<bool> = self.block_sum.iter().map(|s|
+            (spectral_kurtosis(s[0], s[1], n as f32) - 1.0).abs() > limit
+        ).collect();
+        let drop_all = flag.mode == FlagMode::Ffts &&
+            flagged.iter().filter(|&&f| f).count() as f32 > FLAGGED_FRACTION * flagged.len() as f32;
+        for (k, f) in flagged.into_iter().enumerate() {
+            let excluded = match flag.mode {
+                FlagMode::Bins => f,
+                FlagMode::Ffts => drop_all,
+            };
+            if excluded {
+                self.flagged_acc[k] += self.block_sum[k][0];
+            } else {
+                self.acc[k] += self.block_sum[k][0];
+                self.bin_count[k] += n;
+            }
+        }
+        self.block_sum.iter_mut().for_each(|v| *v = [0.0; 2]);
+        self.block_n = 0;
+    }
+
+    /// Power of each bin in dB for a statistic, in FFT order
+    fn statistic_db(&self, statistic: SpectrumStatistic) -> Vec<f32> {
+        let db = |p: &f32| p.log10() * 10.0;
+        match statistic {
+            // With flagging, the number of FFTs varies by bin.
+            // Bins where everything was flagged use all FFTs.
+            SpectrumStatistic::Mean if self.flag.is_some() =>
+                self.acc.iter().zip(self.bin_count.iter()).zip(self.flagged_acc.iter()).map(|((p, &c), f)|
+                    if c > 0 { db(&(p / c as f32)) } else { db(&(f / self.accn as f32)) }
+                ).collect(),
+            SpectrumStatistic::Mean => {
                 // divide accumulator bins by self.accn,
                 // but do it as an addition after conversion to dB scale
                 let db_plus = (self.accn as f32).log10() * -10.0;
+                self.acc.iter().map(|p| db(p) + db_plus).collect()
+            },
+            SpectrumStatistic::Kurtosis =>
+                self.sk_sum.iter().map(|s| db(&spectral_kurtosis(s[0], s[1], self.accn as f32))).collect(),
+            SpectrumStatistic::Max => self.max.iter().map(db).collect(),
+            SpectrumStatistic::Min => self.min.iter().map(db).collect(),
+            SpectrumStatistic::Ema => self.ema.iter().map(db).collect(),
+            SpectrumStatistic::Percentile(p) => {
+                let (scale, offset) = SpectrumFormat::U8.db_scale_offset();
+                // Number of values at or below the percentile
+                let target = ((p as f32 / 100.0 * self.accn as f32).ceil() as u32).max(1);
+                self.histogram.chunks(HISTOGRAM_LEVELS).map(|h| {
+                    let mut count = 0;
+                    let level = h.iter().position(|&n| {
+                        count += n;
+                        count >= target
+                    }).unwrap_or(HISTOGRAM_LEVELS - 1);
+                    // Middle of the histogram level
+                    (level as f32 + 0.5) * scale + offset
+                }).collect()
+            },
+        }
+    }
 
-                match outfmt {
-                SpectrumFormat::U16 => {
-                    for (acc_bin, out) in self.acc.iter().zip(outbuf[offset..].chunks_mut(2)) {
-                        let db = acc_bin.log10() * 10.0 + db_plus;
-                        // quantize to 0.05 dB per LSB, full scale at 4000, clamp to 12 bits
-                        let o = (db * 20.0 + 4000.0).max(0.0).min(4095.0) as u16;
-                        // This could be changed to little endian for consistency
-                        // but for now fixing the 16-bit format isn't a high priority.
-                        out[0] = (o >> 8) as u8;
-                        out[1] = (o & 0xFF) as u8;
-                        offset += 2; // TODO maybe just use byte crate here as well
-                    }
-                },
-                SpectrumFormat::U8 => {
-                    for (acc_bin, out) in self.acc.iter().zip(outbuf[offset..].iter_mut()) {
-                        let db = acc_bin.log10() * 10.0 + db_plus;
-                        // quantize to 0.5 dB per LSB, full scale at 250
-                        *out = (db * 2.0 + 250.0).max(0.0).min(255.0) as u8;
-                        offset += 1; // TODO maybe just use byte crate here as well
-                    }
-                }}
-                self.output.write(&outbuf[0..offset], &sock);
-
-                // Reset accumulator
-                for acc_bin in self.acc.iter_mut() {
-                    *acc_bin = 0.0;
-                }
-                self.accn = 0;
-                self.seq += 1;
+    /// Values of a statistic in output order, cropped, calibrated and merged
+    fn output_values(&self, statistic: SpectrumStatistic, db: &[f32]) -> Vec<f32> {
+        let (upper, lower) = output_bins(db, self.fft_info.complex, self.drop_edge);
+        let reduction = self.reduction.unwrap_or(match statistic {
+            SpectrumStatistic::Max => SpectrumReduction::Max,
+            SpectrumStatistic::Min => SpectrumReduction::Min,
+            _ => SpectrumReduction::Mean,
+        });
+        // Spectral kurtosis is not calibrated
+        let calibrated = statistic != SpectrumStatistic::Kurtosis;
+        merge_bins(
+            upper.iter().chain(lower.iter()).skip(self.crop.start).zip(self.correction.iter())
+                .map(|(v, c)| if calibrated { v + c } else { *v }),
+            reduction,
+            self.merge)
+    }
+
+    /// Serialize a record of spectrum data in output format
+    fn serialize(&self, db: &[f32], metadata: &Metadata) -> Vec<u8> {
+        let outfmt = self.outfmt;
+        let mut outbuf: Vec<u8> = vec![
+            0;
+            METADATA_SIZE +
+            db.len() * outfmt.bytes_per_bin()];
+        let mut offset = 0;
+
+        // unwrap is OK here because it would only panic if outbuf
+        // is too small for metadata. That would clearly be a bug.
+        serialize_metadata(&mut outbuf, &mut offset, metadata, self.seq).unwrap();
+
+        let (db_scale, db_offset) = outfmt.db_scale_offset();
+        use byte::*;
+        for &db in db {
+            let v = (db - db_offset) / db_scale;
+            // Buffer is allocated for all bins above, so these do not fail
+            match outfmt {
+                SpectrumFormat::U8 =>
+                    outbuf.write_with(&mut offset, v.clamp(0.0, 255.0) as u8, LE).unwrap(),
+                SpectrumFormat::U16 =>
+                    outbuf.write_with(&mut offset, v.clamp(0.0, 4095.0) as u16, BE).unwrap(),
+                SpectrumFormat::U16le =>
+                    outbuf.write_with(&mut offset, v.clamp(0.0, 4095.0) as u16, LE).unwrap(),
+                SpectrumFormat::F32 =>
+                    outbuf.write_with(&mut offset, db, LE).unwrap(),
             }
         }
-        Ok(())
+        outbuf
+    }
+}
+
+/// Number of input samples between consecutive FFTs
+fn fft_interval(fft_info: FftInfo) -> usize {
+    // fixed 25% overlap
+    fft_info.size - fft_info.size / 4
+}
+
+/// Frequency of the first output bin
+fn first_bin_frequency(fft_info: FftInfo, drop_edge: bool) -> f64 {
+    let fd = fft_info.fs / (fft_info.size as f64);
+    if fft_info.complex {
+        fft_info.fc - fft_info.fs / 2.0 + if drop_edge { fd } else { 0.0 }
+    } else {
+        fft_info.fc
+    }
+}
+
+/// Spectral kurtosis estimator from sums of power and squared power over m FFTs
+fn spectral_kurtosis(s1: f32, s2: f32, m: f32) -> f32 {
+    (m + 1.0) / (m - 1.0) * (m * s2 / (s1 * s1) - 1.0)
+}
+
+/// Standard deviation of the spectral kurtosis estimator for Gaussian noise
+fn spectral_kurtosis_std(m: f32) -> f32 {
+    (4.0 * m * m / ((m - 1.0) * (m + 2.0) * (m + 3.0))).sqrt()
+}
+
+/// Merge groups of adjacent bins given in dB.
+/// Mean is averaged as power.
+/// Bins left over at the end are merged into a smaller group.
+fn merge_bins(
+    db: impl Iterator<Item = f32>,
+    reduction: SpectrumReduction,
+    merge: usize,
+) -> Vec<f32> {
+    let db: Vec<f32> = db.collect();
+    if merge <= 1 {
+        return db;
+    }
+    db.chunks(merge).map(|group| match reduction {
+        SpectrumReduction::Max => group.iter().fold(f32::NEG_INFINITY, |a, &b| a.max(b)),
+        SpectrumReduction::Min => group.iter().fold(f32::INFINITY, |a, &b| a.min(b)),
+        SpectrumReduction::Mean => (group.iter().map(|v| 10.0f32.powf(v / 10.0)).sum::<f32>() / group.len() as f32).log10() * 10.0,
+    }).collect()
+}
+
+/// Bins in output order, as two parts output one after another.
+/// For complex input, the FFT result is reordered
+/// to go from -fs/2 to fs/2 instead of starting from 0 Hz.
+fn output_bins(
+    acc: &[f32],
+    complex: bool,
+    drop_edge: bool,
+) -> (&[f32], &[f32]) {
+    let edge = if drop_edge { 1 } else { 0 };
+    if complex {
+        let half = acc.len() / 2;
+        (&acc[half + edge ..], &acc[.. half])
+    } else {
+        (&acc[.. acc.len() - edge], &[])
+    }
+}
+
+
+#[test]
+fn test_output_bins() {
+    let fft_info = FftInfo { fs: 8000.0, fc: 1e6, size: 8, complex: true };
+    let acc: Vec<f32> = (0..8).map(|i| i as f32).collect();
+    assert!(output_bins(&acc, true, false) == (&[4.0, 5.0, 6.0, 7.0][..], &[0.0, 1.0, 2.0, 3.0][..]));
+    assert!(output_bins(&acc, true, true).0 == [5.0, 6.0, 7.0]);
+    assert!(first_bin_frequency(fft_info, false) == 1e6 - 4000.0);
+    assert!(first_bin_frequency(fft_info, true) == 1e6 - 3000.0);
+    // Real input has bins from 0 to fs/2
+    let acc = &acc[..5];
+    assert!(output_bins(acc, false, true) == (&[0.0, 1.0, 2.0, 3.0][..], &[][..]));
+    assert!(first_bin_frequency(FftInfo { complex: false, ..fft_info }, true) == 1e6);
+}
+
+
+#[test]
+fn test_statistics() {
+    let fft_info = FftInfo { fs: 8000.0, fc: 0.0, size: 8, complex: true };
+    let mut accu = SpectrumAccumulator::init(fft_info, 0, &SpectrumParams {
+        averages: 5,
+        format: SpectrumFormat::F32,
+        outputs: ["max", "min", "p60"].iter().map(|s| SpectrumStatParams {
+            statistic: s.parse().unwrap(),
+            output: OutputParams { filename: None },
+        }).collect(),
+        ..Default::default()
+    }, &Calibration::default()).unwrap();
+    // Powers of 1e-3 to 1e-7, i.e. -30 to -70 dB
+    for i in 0..5 {
+        accu.power.iter_mut().for_each(|p| *p = 10.0f32.powi(-3 - i));
+        accu.add_power();
+    }
+    let close = |a: &[f32], b: f32, tolerance: f32| a.iter().all(|v| (v - b).abs() < tolerance);
+    assert!(close(&accu.statistic_db(SpectrumStatistic::Max), -30.0, 0.01));
+    assert!(close(&accu.statistic_db(SpectrumStatistic::Min), -70.0, 0.01));
+    assert!(close(&accu.statistic_db(SpectrumStatistic::Mean), 10.0 * (0.0011111f32 / 5.0).log10(), 0.01));
+    // 3 of 5 values are at or below -50 dB
+    assert!(close(&accu.statistic_db(SpectrumStatistic::Percentile(60)), -50.0, 0.5));
+    assert!("p101".parse::<SpectrumStatistic>().is_err());
+
+    // Moving average starts from the first value and is not reset
+    let mut accu = SpectrumAccumulator::init(fft_info, 0, &SpectrumParams {
+        averages: 1,
+        // 2 FFTs, since one FFT is taken every 0.75 ms
+        time_constant: 0.0015,
+        format: SpectrumFormat::F32,
+        outputs: vec![SpectrumStatParams { statistic: SpectrumStatistic::Ema, output: OutputParams { filename: None } }],
+        ..Default::default()
+    }, &Calibration::default()).unwrap();
+    accu.power.iter_mut().for_each(|p| *p = 1.0);
+    accu.add_power();
+    accu.reset();
+    accu.power.iter_mut().for_each(|p| *p = 0.0);
+    accu.add_power();
+    assert!(close(&accu.statistic_db(SpectrumStatistic::Ema), 10.0 * (-0.5f32).exp().log10(), 0.01));
+
+    // Merging averages power, but keeps the largest maximum
+    let db = [-10.0, -20.0, -30.0, -40.0, -50.0];
+    let mean = merge_bins(db.iter().copied(), SpectrumReduction::Mean, 2);
+    assert!(mean.len() == 3 && (mean[0] - 10.0 * 0.055f32.log10()).abs() < 0.01);
+    assert!(mean[2] == -50.0);
+    assert!(merge_bins(db.iter().copied(), SpectrumReduction::Max, 2) == [-10.0, -30.0, -50.0]);
+
+    // Crop to bins at 1000-3000 Hz and merge into at most 2 bins.
+    // The spectrum ends at 3000 Hz, so the crop is extended
+    // downward to 0 Hz to make 2 whole merged bins.
+    let accu = SpectrumAccumulator::init(fft_info, 0, &SpectrumParams {
+        averages: 1,
+        format: SpectrumFormat::F32,
+        range: Some((900.0, 3000.0)),
+        max_bins: Some(2),
+        ..Default::default()
+    }, &Calibration::default()).unwrap();
+    assert!(accu.crop == (4..8) && accu.merge == 2);
+    // Highest bin output is at 3000 Hz
+    let f0 = first_bin_frequency(fft_info, false);
+    assert!(f0 + (accu.crop.end - 1) as f64 * 1000.0 == 3000.0);
+    let db = accu.statistic_db(SpectrumStatistic::Mean);
+    assert!(accu.output_values(SpectrumStatistic::Mean, &db).len() == 2);
+
+    // Range between bins or outside the spectrum has no bins
+    for range in [(1100.0, 1900.0), (5000.0, 6000.0)] {
+        assert!(SpectrumAccumulator::init(fft_info, 0, &SpectrumParams {
+            range: Some(range),
+            ..Default::default()
+        }, &Calibration::default()).is_err());
+    }
+}
+
+
+#[test]
+fn test_aligned_periods() {
+    // 6 samples between FFTs at 8 kHz, so a period of 3 ms is 4 FFTs
+    let fft_info = FftInfo { fs: 8000.0, fc: 0.0, size: 8, complex: true };
+    let mut accu = SpectrumAccumulator::init(fft_info, 0, &SpectrumParams {
+        averages: 1,
+        period: Some(0.003),
+        align: true,
+        format: SpectrumFormat::F32,
+        window: SpectrumWindow::Rectangular,
+        ..Default::default()
+    }, &Calibration::default()).unwrap();
+    assert!(accu.averages == 4);
+    let sock = zmq::Context::new().socket(zmq::PUB).unwrap();
+    let mut fft_results = vec![vec![Complex{ re: 1.0, im: 0.0 }; 8]; 2];
+    let results: Vec<&mut [Complex<f32>]> = fft_results.iter_mut().map(|r| &mut r[..]).collect();
+    // Start in the middle of a period, blocks of 2 FFTs last 1.5 ms
+    let start = std::time::UNIX_EPOCH + std::time::Duration::from_millis(1_000_002);
+    for block in 0..8 {
+        let metadata = Metadata {
+            seq: block,
+            systemtime: start + std::time::Duration::from_micros(1500 * block),
+        };
+        accu.accumulate(&results, &metadata, &sock).unwrap();
+    }
+    // FFTs go from 1000.00125 s to 1000.0125 s. The partial first period
+    // is dropped, then the three full periods ending by then are written.
+    assert!(accu.seq == 3);
+    assert!(!accu.partial);
+    // Clock jumping back to the previous period does not write a record
+    let metadata = Metadata {
+        seq: 8,
+        systemtime: start + std::time::Duration::from_micros(1500 * 7 - 2000),
+    };
+    accu.accumulate(&results, &metadata, &sock).unwrap();
+    assert!(accu.seq == 3);
+}
+
+
+#[test]
+fn test_spectral_kurtosis() {
+    let fft_info = FftInfo { fs: 8000.0, fc: 0.0, size: 8, complex: true };
+    let mut accu = SpectrumAccumulator::init(fft_info, 0, &SpectrumParams {
+        averages: 1000,
+        format: SpectrumFormat::F32,
+        flag: Some(SpectrumFlagParams { mode: FlagMode::Bins, ffts: 100, threshold: 3.0 }),
+        outputs: vec![SpectrumStatParams { statistic: SpectrumStatistic::Kurtosis, output: OutputParams { filename: None } }],
+        ..Default::default()
+    }, &Calibration::default()).unwrap();
+    // Power of Gaussian noise is exponentially distributed,
+    // so use its quantiles in a scrambled order as noise.
+    // Bin 1 has a constant carrier and bin 2 impulsive interference.
+    for i in 0..1000 {
+        let noise = -(1.0 - ((i * 37) % 100) as f32 / 100.0 - 0.005).ln();
+        accu.power.iter_mut().for_each(|p| *p = noise);
+        accu.power[1] = 1.0;
+        accu.power[2] = if i % 50 == 0 { 50.0 } else { noise };
+        accu.add_power();
     }
+    let sk = accu.statistic_db(SpectrumStatistic::Kurtosis);
+    assert!(sk[0].abs() < 0.2, "{}", sk[0]);
+    assert!(sk[1] == f32::NEG_INFINITY);
+    assert!(sk[2] > 3.0, "{}", sk[2]);
+    // Flagged blocks are left out from the mean. Both the carrier
+    // and the interference are flagged in every block, so their mean
+    // is taken over all FFTs.
+    let mean = accu.statistic_db(SpectrumStatistic::Mean);
+    assert!(mean[0].abs() < 0.1 && mean[1].abs() < 1e-3 && mean[2] > 2.0, "{:?}", mean);
+    assert!(accu.bin_count[0] == 1000 && accu.bin_count[1] == 0);
+    accu.reset();
+    assert!(accu.block_n == 0 && accu.flagged_acc[1] == 0.0);
 }
@@ -0,0 +1,160 @@
+//! Squelch based on channel power relative to an estimated noise floor
+//!
+//! Squelch decisions are made once per processing block,
+//! since that is the granularity of output records.
+
+use byte::{BytesExt, LE};
+
+use super::data::*;
+
+/// Rate at which the noise floor estimate is allowed to rise, in dB per second.
+/// The estimate follows decreasing power immediately, so it tracks
+/// the minimum channel power while slowly adapting to a rising noise level.
+const NOISE_FLOOR_RISE: f32 = 1.0;
+/// Rate of rise while squelch is open, in dB per second.
+/// During a transmission the channel power is the signal rather than noise,
+/// so the estimate barely rises to keep squelch open for long transmissions.
+/// It still rises a little so that squelch eventually closes
+/// if the noise level itself has risen above the threshold.
+const NOISE_FLOOR_RISE_OPEN: f32 = 0.01;
+
+/// Squelch parameters
+#[derive(Clone)]
+pub struct SquelchParams {
+    pub threshold: f32, // SNR in dB at which squelch opens
+    pub hysteresis: f32, // Squelch closes when SNR drops this many dB below threshold
+    pub hang: f64, // Time in seconds to stay open after SNR has dropped
+    pub preroll: f64, // Time in seconds to publish before squelch opened
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SquelchState {
+    Closed,
+    Open,
+    /// Signal has dropped but squelch is kept open for the hang time
+    Hang,
+}
+
+pub struct Squelch {
+    threshold: f32,
+    hysteresis: f32,
+    /// Noise floor rise per processing block, dB
+    rise: f32,
+    /// Noise floor rise per processing block while open, dB
+    rise_open: f32,
+    hang_blocks: u32,
+    hang_left: u32,
+    /// Number of processing blocks to publish before squelch opened
+    pub preroll_blocks: usize,
+    pub state: SquelchState,
+    /// Noise floor estimate in dB, None before first block
+    pub noise_floor: Option<f32>,
+    /// SNR of the last block in dB
+    pub snr: f32,
+}
+
+impl Squelch {
+    pub fn init(
+        p: &SquelchParams,
+        block_duration: f64, // Duration of a processing block in seconds
+    ) -> Self {
+        Self {
+            threshold: p.threshold,
+            hysteresis: p.hysteresis,
+            rise: NOISE_FLOOR_RISE * block_duration as f32,
+            rise_open: NOISE_FLOOR_RISE_OPEN * block_duration as f32,
+            hang_blocks: (p.hang / block_duration).ceil() as u32,
+            hang_left: 0,
+            preroll_blocks: (p.preroll / block_duration).ceil() as usize,
+            state: SquelchState::Closed,
+            noise_floor: None,
+            snr: 0.0,
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.state != SquelchState::Closed
+    }
+
+    /// Update the squelch state with the mean power of a processing block.
+    pub fn update(&mut self, power: f32) -> SquelchState {
+        let db = 10.0 * power.max(1e-30).log10();
+        let rise = if self.is_open() { self.rise_open } else { self.rise };
+        let floor = match self.noise_floor {
+            Some(floor) => (floor + rise).min(db),
+            None => db,
+        };
+        self.noise_floor = Some(floor);
+        self.snr = db - floor;
+
+        let above = if self.is_open() {
+            self.snr >= self.threshold - self.hysteresis
+        } else {
+            self.snr >= self.threshold
+        };
+        self.state = if above {
+            self.hang_left = self.hang_blocks;
+            SquelchState::Open
+        } else if self.is_open() && self.hang_left > 0 {
+            self.hang_left -= 1;
+            SquelchState::Hang
+        } else {
+            SquelchState::Closed
+        };
+        self.state
+    }
+
+    /// Serialize a squelch open or close event.
+    pub fn serialize_event(
+        &self,
+        buf: &mut [u8],
+        offset: &mut usize,
+        metadata: &Metadata,
+    ) -> byte::Result<()> {
+        serialize_metadata(buf, offset, metadata, metadata.seq)?;
+        buf.write_with(offset, self.is_open() as u32, LE)?;
+        buf.write_with(offset, self.snr, LE)?;
+        buf.write_with(offset, self.noise_floor.unwrap_or(0.0), LE)?;
+        // Reserved
+        buf.write_with(offset, 0u32, LE)?;
+        Ok(())
+    }
+}
+
+/// Size of a serialized squelch event
+pub const SQUELCH_EVENT_SIZE: usize = METADATA_SIZE + 16;
+
+
+#[test]
+fn test_squelch() {
+    use SquelchState::*;
+    let mut squelch = Squelch::init(&SquelchParams {
+        threshold: 10.0,
+        hysteresis: 3.0,
+        hang: 0.3,
+        preroll: 0.2,
+    }, 0.1);
+    assert!(squelch.preroll_blocks == 2);
+
+    let db = |v: f32| 10.0f32.powf(v / 10.0);
+    for _ in 0..10 {
+        assert!(squelch.update(db(0.0)) == Closed);
+    }
+    // Opens above threshold and stays open above threshold - hysteresis
+    assert!(squelch.update(db(12.0)) == Open);
+    assert!(squelch.update(db(8.0)) == Open);
+    // Then hangs for 3 blocks
+    for _ in 0..3 {
+        assert!(squelch.update(db(0.0)) == Hang);
+    }
+    assert!(squelch.update(db(0.0)) == Closed);
+    // Does not open between the thresholds
+    assert!(squelch.update(db(8.0)) == Closed);
+    assert!(squelch.noise_floor.unwrap() < 0.5);
+
+    // Stays open during a long strong transmission
+    for _ in 0..400 {
+        assert!(squelch.update(db(30.0)) == Open);
+    }
+    assert!(squelch.noise_floor.unwrap() < 1.0);
+}
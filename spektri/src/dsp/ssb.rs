@@ -0,0 +1,220 @@
+//! Frequency domain SSB receiver
+//!
+//! Works on the same FFT results as the fast-convolution filter bank,
+//! but instead of producing a complex signal, only the bins of the
+//! wanted sideband are taken and shifted so that the carrier
+//! ends up at 0 Hz. These are converted directly to real-valued audio
+//! by a half-size real IFFT, so no time domain demodulator is needed.
+
+use std::error::Error;
+use rustfft::num_complex::Complex;
+
+use super::data::FftInfo;
+use super::demod::DemodMode;
+use super::fftutil::*;
+use super::output::OutputParams;
+
+/// Width of the transitions at the passband edges, in FFT bins.
+/// Much sharper transitions would make the impulse response
+/// longer than the overlap between consecutive IFFTs.
+const TRANSITION_BINS: f64 = 8.0;
+
+/// SSB receiver parameters
+pub struct SsbParams {
+    pub mode: DemodMode, // Usb or Lsb
+    pub fs_audio: f64, // Audio sample rate
+    pub fc: f64, // Carrier frequency
+    pub bfo: f64, // Audio frequency where the carrier ends up
+    pub low: f64, // Lower edge of audio passband
+    pub high: f64, // Upper edge of audio passband
+    pub gain: f32, // Output gain in dB
+    pub output: OutputParams,
+}
+
+/// DSP state of an SSB receiver.
+pub struct SsbDsp {
+    fft_size: usize,
+    lsb: bool,
+    /// FFT bin which is shifted to 0 Hz in audio
+    zero_bin: isize,
+    /// Weights for the audio bins from 0 to Nyquist frequency
+    weights: Vec<f32>,
+    ifft: RealIfft,
+    /// Number of FFT results processed so far
+    ffts: u64,
+}
+
+impl SsbDsp {
+    pub fn init(
+        fft_info: FftInfo,
+        p: &SsbParams,
+    ) -> Result<Self, Box<dyn Error>>
+    {
+        let lsb = match p.mode {
+            DemodMode::Usb => false,
+            DemodMode::Lsb => true,
+            _ => return Err(format!("SSB receiver mode must be usb or lsb, not {:?}", p.mode).into()),
+        };
+        let bin_spacing = fft_info.fs / fft_info.size as f64;
+
+        // IFFT size must be a multiple of 4 for the 25% overlap.
+        // Any bin can be shifted to 0 Hz though, thanks to phase correction.
+        let size = ((p.fs_audio / bin_spacing / 4.0).round() * 4.0) as usize;
+        let bfo = if lsb { p.bfo } else { -p.bfo };
+        let zero_bin = ((p.fc + bfo - fft_info.fc) / bin_spacing).round() as isize;
+
+        let exact_fs = bin_spacing * size as f64;
+        let exact_fc = fft_info.fc + bin_spacing * zero_bin as f64 - bfo;
+        // Allow for rounding errors from adding the BFO frequency
+        if size == 0 || exact_fs != p.fs_audio || (exact_fc - p.fc).abs() > 1e-6 {
+            return Err(format!(
+                "SSB receiver fs={} fc={} bfo={} is not possible, nearest possible is fs={} fc={}",
+                p.fs_audio, p.fc, p.bfo, exact_fs, exact_fc).into());
+        }
+        if size / 2 > fft_info.size {
+            return Err(format!(
+                "SSB receiver fs={} is wider than input bandwidth of {} Hz",
+                p.fs_audio, fft_info.fs).into());
+        }
+        if !(0.0 <= p.low && p.low < p.high && p.high <= p.fs_audio / 2.0) {
+            return Err(format!(
+                "SSB receiver passband {}-{} Hz does not fit in audio bandwidth of {} Hz",
+                p.low, p.high, p.fs_audio / 2.0).into());
+        }
+
+        // Raised cosine transitions centered at the passband edges
+        let transition = TRANSITION_BINS * bin_spacing;
+        let edge = |f: f64| -> f64 {
+            if f <= -transition / 2.0 {
+                0.0
+            } else if f >= transition / 2.0 {
+                1.0
+            } else {
+                0.5 + 0.5 * (std::f64::consts::PI * f / transition).sin()
+            }
+        };
+        let weights = (0..=size/2).map(|m| {
+            let f = m as f64 * bin_spacing;
+            if m == 0 || m == size/2 {
+                // DC and Nyquist bins should be real, so leave them out
+                0.0
+            } else {
+                // Real part of the analytic signal is half of it
+                (0.5 * edge(f - p.low) * edge(p.high - f)) as f32
+            }
+        }).collect();
+
+        Ok(Self {
+            fft_size: fft_info.size,
+            lsb,
+            zero_bin,
+            weights,
+            ifft: RealIfft::init(size),
+            ffts: 0,
+        })
+    }
+
+    /// Number of output samples produced from each FFT result
+    pub fn samples_per_fft(&self) -> usize {
+        // fixed 25% overlap
+        self.ifft.size() / 4 * 3
+    }
+
+    /// Process the FFT results of a processing block.
+    /// Output buffer is replaced with the resulting audio.
+    pub fn process_block(
+        &mut self,
+        fft_results: &[&mut[Complex<f32>]],
+        output: &mut Vec<f32>,
+    ) {
+        let n = self.samples_per_fft();
+        output.resize(n * fft_results.len(), 0.0);
+        for (i, (out, fft_result)) in output.chunks_mut(n).zip(fft_results.iter()).enumerate() {
+            self.process(fft_result, self.ffts + i as u64, out);
+        }
+        self.ffts += fft_results.len() as u64;
+    }
+
+    /// Process one FFT result and write the resulting audio to output.
+    fn process(
+        &self,
+        fft_result: &[Complex<f32>],
+        fft_number: u64,
+        output: &mut [f32],
+    ) {
+        let size = self.ifft.size();
+
+        // Correct the phase rotation caused by shifting the spectrum
+        // to keep the phase continuous.
+        let rotation = quarter_turn_rotation(self.zero_bin, fft_number, -1.0);
+
+        let bins: Vec<Complex<f32>> = self.weights.iter().enumerate().map(|(m, &w)| {
+            let m = m as isize;
+            if self.lsb {
+                // Lower sideband is mirrored by taking the conjugate,
+                // which also reverses the direction of the phase rotation.
+                (get_bin(fft_result, self.fft_size, self.zero_bin - m) * rotation).conj() * w
+            } else {
+                get_bin(fft_result, self.fft_size, self.zero_bin + m) * rotation * w
+            }
+        }).collect();
+
+        let mut buf = vec![0.0; size];
+        self.ifft.process(&bins, &mut buf);
+
+        // fixed 25% overlap, discard 1/8 from each end
+        let discard = size / 8;
+        output.copy_from_slice(&buf[discard .. discard + output.len()]);
+    }
+}
+
+
+#[test]
+fn test_ssb() {
+    // Receive a tone on both sidebands with a carrier frequency
+    // which is not a multiple of 4 bins and check that the audio
+    // is a continuous sinusoid at the right frequency.
+    let fft_info = FftInfo { fs: 102400.0, fc: 0.0, size: 1024, complex: true };
+    let fft_interval = fft_info.size / 4 * 3;
+    let fc = 1300.0;
+    let audio_freq = 1030.0;
+    for &(mode, tone_freq) in [(DemodMode::Usb, fc + audio_freq), (DemodMode::Lsb, fc - audio_freq)].iter() {
+        let mut ssb = SsbDsp::init(fft_info, &SsbParams {
+            mode,
+            fs_audio: 8000.0,
+            fc,
+            bfo: 0.0,
+            low: 300.0,
+            high: 3000.0,
+            gain: 0.0,
+            output: OutputParams { filename: None },
+        }).unwrap();
+
+        let mut planner = rustfft::FftPlanner::new();
+        let fft = planner.plan_fft_forward(fft_info.size);
+        let mut fft_results: Vec<Vec<Complex<f32>>> = (0..20).map(|b| {
+            let mut buf: Vec<Complex<f32>> = (0..fft_info.size).map(|i| {
+                let t = (b * fft_interval + i) as f64 / fft_info.fs;
+                let phase = 2.0 * std::f64::consts::PI * tone_freq * t;
+                Complex{ re: phase.cos() as f32, im: phase.sin() as f32 } / fft_info.size as f32
+            }).collect();
+            fft.process(&mut buf);
+            buf
+        }).collect();
+
+        let mut output = Vec::new();
+        let results: Vec<&mut [Complex<f32>]> = fft_results.iter_mut().map(|r| &mut r[..]).collect();
+        ssb.process_block(&results[0..10], &mut output);
+        let mut audio = output.clone();
+        ssb.process_block(&results[10..20], &mut output);
+        audio.extend_from_slice(&output);
+
+        // Output starts 1/8 IFFT size after the start of the first FFT
+        let delay = ssb.ifft.size() / 8;
+        for (n, &x) in audio.iter().enumerate() {
+            let t = (n + delay) as f64 / 8000.0;
+            let expected = (2.0 * std::f64::consts::PI * audio_freq * t).cos() as f32;
+            assert!((x - expected).abs() < 0.02, "{:?} sample {}: {} != {}", mode, n, x, expected);
+        }
+    }
+}
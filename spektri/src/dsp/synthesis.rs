@@ -0,0 +1,206 @@
+//! Synthesis filter bank
+//!
+//! Inverse of the fast-convolution filter bank: several narrowband
+//! complex signals are combined into one wideband signal.
+//!
+//! Each channel is cut into blocks with 25% overlap, like the input
+//! of the analysis filter bank, and transformed by a small FFT.
+//! The weighted bins are added at the center frequency of the channel
+//! into the spectrum of a large IFFT, from which 1/8 is discarded
+//! from each end, just like in the filters of the analysis side.
+//! The output is delayed by 1/8 of the IFFT size.
+
+use std::error::Error;
+use rustfft::{FftPlanner, num_complex::Complex};
+
+use super::fcfb::filter_weights;
+use super::fftutil::quarter_turn_rotation;
+
+/// Parameters of one narrowband input of the synthesis filter bank
+#[derive(Clone)]
+pub struct SynthesisChannelParams {
+    pub fs: f64, // Sample rate of the channel
+    pub fc: f64, // Center frequency of the channel
+    pub taps: Option<Vec<f32>>, // FIR coefficients at channel sample rate
+    pub gain: f32, // Gain in dB
+}
+
+struct SynthesisChannel {
+    /// Bin of the wideband IFFT where 0 Hz of the channel ends up
+    center: isize,
+    /// Weights in FFT order, including gain and FFT scaling
+    weights: Vec<Complex<f32>>,
+    fft: std::sync::Arc<dyn rustfft::Fft<f32>>,
+    /// Last samples of the previous block, overlapping with the next one
+    overlap: Vec<Complex<f32>>,
+}
+
+pub struct Synthesizer {
+    channels: Vec<SynthesisChannel>,
+    ifft: std::sync::Arc<dyn rustfft::Fft<f32>>,
+    /// Number of IFFTs done so far
+    ffts: u64,
+}
+
+impl Synthesizer {
+    pub fn init(
+        fs: f64, // Output sample rate
+        fc: f64, // Output center frequency
+        size: usize, // IFFT size
+        params: &[SynthesisChannelParams],
+    ) -> Result<Self, Box<dyn Error>>
+    {
+        let bin_spacing = fs / size as f64;
+        let mut planner = FftPlanner::new();
+        let channels = params.iter().map(|p| {
+            // Same rules as for filters: sample rate must be
+            // a multiple of 4 bins and center frequency a whole bin.
+            let bins = ((p.fs / bin_spacing / 4.0).round() * 4.0) as usize;
+            let center = ((p.fc - fc) / bin_spacing).round() as isize;
+            let exact_fs = bins as f64 * bin_spacing;
+            let exact_fc = fc + center as f64 * bin_spacing;
+            if bins == 0 || exact_fs != p.fs || exact_fc != p.fc {
+                return Err(format!(
+                    "Synthesis channel fs={} fc={} is not possible, nearest possible is fs={} fc={}",
+                    p.fs, p.fc, exact_fs, exact_fc).into());
+            }
+            if bins > size {
+                return Err(format!(
+                    "Synthesis channel fs={} is wider than output bandwidth of {} Hz",
+                    p.fs, fs).into());
+            }
+
+            // Shifting by center bins rotates the phase by center/size cycles
+            // per sample, counted from the start of the IFFT. An input sample
+            // ends up at IFFT sample m + size/8 but belongs to the delayed
+            // output time m - size/8, so rotate back by center/4 cycles to make
+            // the result equal to the delayed input shifted by the center frequency.
+            let phase = -2.0 * std::f64::consts::PI * (center.rem_euclid(4) as f64 / 4.0);
+            let rotation = Complex{ re: phase.cos() as f32, im: phase.sin() as f32 };
+            let scaling = 10.0f32.powf(p.gain / 20.0) / bins as f32;
+            let weights = filter_weights(p.taps.as_deref(), bins)?;
+            Ok(SynthesisChannel {
+                center,
+                // Reorder from frequency order to FFT order
+                weights: (0..bins).map(|k| weights[(k + bins / 2) % bins] * rotation * scaling).collect(),
+                fft: planner.plan_fft_forward(bins),
+                // Start with zeros, so output is delayed by size/8 samples
+                overlap: vec![Complex{ re: 0.0, im: 0.0 }; bins / 4],
+            })
+        }).collect::<Result<Vec<SynthesisChannel>, Box<dyn Error>>>()?;
+
+        Ok(Self {
+            channels,
+            ifft: planner.plan_fft_inverse(size),
+            ffts: 0,
+        })
+    }
+
+    /// Number of output samples produced by each IFFT
+    pub fn samples_per_fft(&self) -> usize {
+        // fixed 25% overlap
+        self.ifft.len() / 4 * 3
+    }
+
+    /// Number of input samples of a channel used by each IFFT
+    pub fn channel_samples_per_fft(&self, channel: usize) -> usize {
+        self.channels[channel].fft.len() / 4 * 3
+    }
+
+    /// Synthesize a block of output samples.
+    ///
+    /// Each input must have the same number of IFFTs worth of samples
+    /// as given by channel_samples_per_fft.
+    /// Output buffer is replaced with the resulting samples.
+    pub fn process(
+        &mut self,
+        inputs: &[&[Complex<f32>]],
+        output: &mut Vec<Complex<f32>>,
+    ) {
+        let size = self.ifft.len();
+        let n = self.samples_per_fft();
+        let ffts = match inputs.first() {
+            Some(input) => input.len() / self.channel_samples_per_fft(0),
+            None => 0,
+        };
+        output.resize(n * ffts, Complex{ re: 0.0, im: 0.0 });
+        let mut buf = vec![Complex{ re: 0.0, im: 0.0 }; size];
+        let mut segment = Vec::new();
+        for (k, out) in output.chunks_mut(n).enumerate() {
+            let fft_number = self.ffts + k as u64;
+            buf.iter_mut().for_each(|v| *v = Complex{ re: 0.0, im: 0.0 });
+            for (channel, input) in self.channels.iter_mut().zip(inputs.iter()) {
+                let bins = channel.fft.len();
+                let new = &input[k * bins / 4 * 3 .. (k + 1) * bins / 4 * 3];
+                segment.clear();
+                segment.extend_from_slice(&channel.overlap);
+                segment.extend_from_slice(new);
+                channel.overlap.copy_from_slice(&new[new.len() - bins / 4 ..]);
+                channel.fft.process(&mut segment);
+
+                // Consecutive IFFTs start 3/4 of the size apart,
+                // so a shift by center bins would make the phase jump
+                // by center * 3/4 cycles between them. Rotate it back.
+                let rotation = quarter_turn_rotation(channel.center, fft_number, 1.0);
+                for (j, (v, w)) in segment.iter().zip(channel.weights.iter()).enumerate() {
+                    // Frequency of the bin relative to the channel center
+                    let f = if j < bins / 2 { j as isize } else { j as isize - bins as isize };
+                    let i = (channel.center + f).rem_euclid(size as isize) as usize;
+                    buf[i] += v * w * rotation;
+                }
+            }
+            self.ifft.process(&mut buf);
+
+            // fixed 25% overlap, discard 1/8 from each end
+            let discard = size / 8;
+            out.copy_from_slice(&buf[discard .. discard + n]);
+        }
+        self.ffts += ffts as u64;
+    }
+}
+
+
+#[test]
+fn test_synthesis() {
+    // Tones in two channels should come out at
+    // their frequencies in the wideband signal.
+    let (fs, fc, size) = (102400.0, 1e6, 1024);
+    let channels = [(1e6 - 30000.0, 1e3, 0.0), (1e6 + 10100.0, -2e3, -6.0)];
+    let mut synthesizer = Synthesizer::init(fs, fc, size, &channels.iter().map(|&(fc, _, gain)| SynthesisChannelParams {
+        fs: 6400.0,
+        fc,
+        taps: None,
+        gain,
+    }).collect::<Vec<_>>()).unwrap();
+    assert!(synthesizer.channel_samples_per_fft(1) == 48);
+    assert!(Synthesizer::init(fs, fc, size, &[SynthesisChannelParams {
+        fs: 6400.0, fc: 1e6 + 50.0, taps: None, gain: 0.0 }]).is_err());
+
+    let tone = |f: f64, t: f64| {
+        let phase = 2.0 * std::f64::consts::PI * f * t;
+        Complex{ re: phase.cos() as f32, im: phase.sin() as f32 }
+    };
+    let mut output = Vec::new();
+    let mut samples = Vec::new();
+    for block in 0..8 {
+        let inputs: Vec<Vec<Complex<f32>>> = channels.iter().map(|&(_, f, _)|
+            (0..4 * 48).map(|i| tone(f, (block * 4 * 48 + i) as f64 / 6400.0)).collect()
+        ).collect();
+        let inputs: Vec<&[Complex<f32>]> = inputs.iter().map(|i| &i[..]).collect();
+        synthesizer.process(&inputs, &mut output);
+        samples.extend_from_slice(&output);
+    }
+    assert!(samples.len() == 8 * 4 * 768);
+
+    // Skip the start where the filters are still filling up
+    for (m, s) in samples.iter().enumerate().skip(size) {
+        // Output is delayed by size/8 samples
+        let t = (m as f64 - (size / 8) as f64) / fs;
+        let expected: Complex<f32> = channels.iter().map(|&(channel_fc, f, gain)|
+            // Raised cosine response has a gain of 0.5 at a quarter of the bandwidth
+            tone(channel_fc - fc + f, t) * 10.0f32.powf(gain / 20.0) *
+            (0.5 + 0.5 * (std::f32::consts::PI * (f / 3200.0) as f32).cos())
+        ).sum();
+        assert!((s - expected).norm() < 0.02, "sample {}: {} != {}", m, s, expected);
+    }
+}
@@ -0,0 +1,147 @@
+//! Channel power and SNR telemetry for filters
+//!
+//! Statistics are computed from the weighted FFT bins of each filter,
+//! so they are available without looking at the filtered signal.
+//! Powers are given in dB relative to full scale of the input,
+//! i.e. before output gain of a filter.
+
+use byte::{BytesExt, LE};
+use rustfft::num_complex::Complex;
+
+/// Telemetry parameters
+pub struct TelemetryParams {
+    pub rate: f64, // Telemetry records per second
+    pub output: super::output::OutputParams,
+}
+
+/// Statistics over one telemetry interval
+#[derive(Copy, Clone, Default)]
+pub struct TelemetryStats {
+    /// Mean channel power
+    pub power: f32,
+    /// Peak power of a single output sample
+    pub peak: f32,
+    /// Estimated noise power in the channel
+    pub noise: f32,
+    /// Ratio of channel power to noise power
+    pub snr: f32,
+}
+
+/// Telemetry accumulator for a single filter
+pub struct ChannelTelemetry {
+    fs: f64,
+    fc: f64,
+    /// Squared magnitudes of filter weights,
+    /// in the same order as the bin powers
+    weights: Vec<f32>,
+    /// Bin powers of each FFT result in a processing block,
+    /// filled by FilterDsp::process_block
+    pub bin_power: Vec<f32>,
+    /// Bin powers summed over the telemetry interval
+    bin_power_sum: Vec<f32>,
+    ffts: usize,
+    peak: f32,
+    pub stats: TelemetryStats,
+}
+
+impl ChannelTelemetry {
+    pub fn init(
+        fs: f64, // Filter sample rate
+        fc: f64, // Filter center frequency
+        weights: Vec<f32>,
+    ) -> Self {
+        let bins = weights.len();
+        Self {
+            fs,
+            fc,
+            weights,
+            bin_power: Vec::new(),
+            bin_power_sum: vec![0.0; bins],
+            ffts: 0,
+            peak: 0.0,
+            stats: TelemetryStats::default(),
+        }
+    }
+
+    /// Accumulate the bin powers and filtered samples of a processing block.
+    pub fn accumulate(&mut self, samples: &[Complex<f32>]) {
+        let bins = self.bin_power_sum.len();
+        for fft in self.bin_power.chunks_exact(bins) {
+            self.bin_power_sum.iter_mut().zip(fft.iter()).for_each(|(s, p)| *s += p);
+            self.ffts += 1;
+        }
+        self.peak = samples.iter().fold(self.peak, |peak, s| peak.max(s.norm_sqr()));
+    }
+
+    /// Compute statistics over the interval and start a new one.
+    pub fn update(&mut self) {
+        let ffts = self.ffts.max(1) as f32;
+        // Mean power of the IFFT output equals
+        // the sum of bin powers due to Parseval's theorem.
+        let power = self.bin_power_sum.iter().sum::<f32>() / ffts;
+
+        // Noise density is estimated as the median of bin powers
+        // in the passband, assuming that a signal in the channel
+        // occupies less than half of it.
+        let max_weight = self.weights.iter().fold(0.0f32, |a, &b| a.max(b));
+        let mut density: Vec<f32> = self.bin_power_sum.iter().zip(self.weights.iter())
+            .filter(|(_, &w)| w >= 0.5 * max_weight)
+            .map(|(&p, &w)| p / w / ffts)
+            .collect();
+        density.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        let noise = density.get(density.len() / 2).copied().unwrap_or(0.0)
+            * self.weights.iter().sum::<f32>();
+
+        let db = |p: f32| 10.0 * p.max(1e-30).log10();
+        self.stats = TelemetryStats {
+            power: db(power),
+            peak: db(self.peak),
+            noise: db(noise),
+            snr: db(power) - db(noise),
+        };
+
+        self.bin_power_sum.iter_mut().for_each(|s| *s = 0.0);
+        self.ffts = 0;
+        self.peak = 0.0;
+    }
+
+    /// Serialize the latest statistics as an entry of a telemetry record.
+    pub fn serialize(
+        &self,
+        buf: &mut [u8],
+        offset: &mut usize,
+    ) -> byte::Result<()> {
+        buf.write_with(offset, self.fs, LE)?;
+        buf.write_with(offset, self.fc, LE)?;
+        buf.write_with(offset, self.stats.power, LE)?;
+        buf.write_with(offset, self.stats.peak, LE)?;
+        buf.write_with(offset, self.stats.noise, LE)?;
+        buf.write_with(offset, self.stats.snr, LE)?;
+        Ok(())
+    }
+}
+
+/// Size of the header of a telemetry record after the common metadata
+pub const TELEMETRY_HEADER_SIZE: usize = 8;
+/// Size of each entry in a telemetry record
+pub const TELEMETRY_ENTRY_SIZE: usize = 32;
+
+
+#[test]
+fn test_telemetry() {
+    // Flat noise in all bins and a strong signal in one bin
+    let bins = 64;
+    let weights = vec![1.0; bins];
+    let mut telemetry = ChannelTelemetry::init(1000.0, 0.0, weights);
+    for _ in 0..3 {
+        telemetry.bin_power = (0..2 * bins).map(|i| if i % bins == 10 { 65.0 } else { 1.0 }).collect();
+        telemetry.accumulate(&[Complex{ re: 2.0, im: 0.0 }]);
+    }
+    telemetry.update();
+    let s = telemetry.stats;
+    // Channel power is 2*64, noise power 64
+    assert!((s.power - 10.0 * 128.0f32.log10()).abs() < 1e-3);
+    assert!((s.noise - 10.0 * 64.0f32.log10()).abs() < 1e-3);
+    assert!((s.snr - 10.0 * 2.0f32.log10()).abs() < 1e-3);
+    assert!((s.peak - 10.0 * 4.0f32.log10()).abs() < 1e-3);
+}
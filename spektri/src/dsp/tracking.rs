@@ -0,0 +1,209 @@
+//! Frequency tracking for filters
+//!
+//! A tracking filter follows a center frequency which changes over time,
+//! e.g. to compensate for Doppler shift during a satellite pass
+//! or to follow a chirp sounder.
+//!
+//! Coarse tuning is done by choosing the FFT bins for each FFT result,
+//! like in a normal filter. The remaining offset of less than half a bin
+//! is removed by an oscillator running at the output sample rate.
+//! The oscillator also cancels the phase of the coarse bin shift,
+//! so the phase stays continuous when the bins change.
+
+use rustfft::num_complex::Complex;
+
+use super::data::FftInfo;
+
+/// How the center frequency of a filter changes
+#[derive(Clone, Debug)]
+pub enum TrackingMode {
+    /// Frequency is set through the control interface
+    Control,
+    /// Interpolate linearly between (Unix time, frequency) pairs
+    Table(Vec<(f64, f64)>),
+    /// Sweep linearly starting from the nominal center frequency.
+    /// If span is given, start again after sweeping over it.
+    Sweep { rate: f64, span: Option<f64> },
+}
+
+pub struct Tracker {
+    mode: TrackingMode,
+    /// Nominal center frequency, also given in the topic
+    fc: f64,
+    fc_in: f64,
+    bin_spacing: f64,
+    /// Output sample rate
+    fs: f64,
+    /// IFFT size of the filter
+    bins: usize,
+    /// Center bin corresponding to the nominal center frequency
+    pub nominal_bin: isize,
+    /// Unix time of the first output sample
+    start_time: Option<f64>,
+    /// Number of output samples produced so far
+    samples: u64,
+    /// Phase of the fine tuning oscillator in cycles
+    phase: f64,
+    /// Center bin of each FFT result in the processing block,
+    /// relative to the nominal center bin
+    offsets: Vec<isize>,
+    /// Center frequency at the first sample of the processing block
+    pub freq: f64,
+}
+
+impl Tracker {
+    pub fn init(
+        fft_info: FftInfo,
+        fs_out: f64,
+        fc: f64, // Nominal center frequency
+        bins: usize,
+        mode: TrackingMode,
+    ) -> Self {
+        let bin_spacing = fft_info.fs / fft_info.size as f64;
+        Self {
+            mode,
+            fc,
+            fc_in: fft_info.fc,
+            bin_spacing,
+            fs: fs_out,
+            bins,
+            nominal_bin: ((fc - fft_info.fc) / bin_spacing).round() as isize,
+            start_time: None,
+            samples: 0,
+            phase: 0.0,
+            offsets: Vec::new(),
+            freq: fc,
+        }
+    }
+
+    /// Offset of the current center frequency from the nominal one
+    pub fn freq_offset(&self) -> f64 {
+        self.freq - self.fc
+    }
+
+    /// Set the center frequency, replacing a table or sweep.
+    pub fn set_frequency(&mut self, freq: f64) {
+        self.mode = TrackingMode::Control;
+        self.freq = freq;
+    }
+
+    /// Center frequency at a given output sample
+    fn frequency(&self, sample: f64) -> f64 {
+        let t = sample / self.fs;
+        match &self.mode {
+            TrackingMode::Control => self.freq,
+            TrackingMode::Sweep { rate, span } => {
+                let offset = rate * t;
+                self.fc + match span {
+                    Some(span) => offset % span.abs(),
+                    None => offset,
+                }
+            },
+            TrackingMode::Table(table) => {
+                let t = self.start_time.unwrap_or(0.0) + t;
+                let i = table.partition_point(|&(time, _)| time <= t);
+                if i == 0 {
+                    table.first().map(|e| e.1).unwrap_or(self.fc)
+                } else if i == table.len() {
+                    table[i - 1].1
+                } else {
+                    let ((t0, f0), (t1, f1)) = (table[i - 1], table[i]);
+                    f0 + (f1 - f0) * (t - t0) / (t1 - t0)
+                }
+            },
+        }
+    }
+
+    /// Choose the bins for each FFT result of a processing block.
+    ///
+    /// Return the offsets of center bins from the nominal center bin.
+    pub fn begin_block(
+        &mut self,
+        ffts: usize,
+        samples_per_fft: usize,
+        time: f64, // Unix time of the processing block
+    ) -> &[isize] {
+        self.start_time.get_or_insert(time);
+        let first = self.samples as f64;
+        self.freq = self.frequency(first);
+        self.offsets = (0..ffts).map(|k| {
+            let middle = first + (k * samples_per_fft + samples_per_fft / 2) as f64;
+            ((self.frequency(middle) - self.fc_in) / self.bin_spacing).round() as isize - self.nominal_bin
+        }).collect();
+        &self.offsets
+    }
+
+    /// Tune the filtered samples of a processing block to the exact frequency.
+    pub fn mix(
+        &mut self,
+        samples: &mut [Complex<f32>],
+        samples_per_fft: usize,
+    ) {
+        use std::f64::consts::PI;
+        let bins = self.bins as u64;
+        for (chunk, &offset) in samples.chunks_mut(samples_per_fft).zip(self.offsets.iter()) {
+            let center = (self.nominal_bin + offset).rem_euclid(bins as isize) as u64;
+            for s in chunk.iter_mut() {
+                // Output sample n of an IFFT is at n + bins/8,
+                // and shifting by center bins rotated its phase
+                // by center * (n + bins/8) / bins cycles.
+                let shift = (center * ((self.samples + bins / 8) % bins)) % bins;
+                let phase = 2.0 * PI * (self.phase - shift as f64 / bins as f64);
+                *s *= Complex{ re: phase.cos() as f32, im: -phase.sin() as f32 };
+                // Frequency at the middle of the sample interval
+                // integrates a linear sweep exactly.
+                let freq = self.frequency(self.samples as f64 + 0.5) - self.fc_in;
+                self.phase = (self.phase + freq / self.fs).rem_euclid(1.0);
+                self.samples += 1;
+            }
+        }
+    }
+}
+
+
+#[test]
+fn test_tracking() {
+    use super::fcfb::*;
+    // A tone sweeping over several bins should come out
+    // as a constant with continuous phase.
+    let fft_info = FftInfo { fs: 102400.0, fc: 0.0, size: 1024, complex: true };
+    let fft_interval = fft_info.size / 4 * 3;
+    let (fs_out, bins) = (1600.0, 16);
+    let (f0, rate) = (330.0, 2000.0);
+    let mut tracker = Tracker::init(fft_info, fs_out, f0, bins, TrackingMode::Sweep { rate, span: None });
+    let mut dsp = FilterDsp::init(fft_info.size, BinNumbers {
+        bins, first: tracker.nominal_bin - bins as isize / 2 }, None).unwrap();
+
+    // Output sample n is at input time (n + bins/8) / fs_out,
+    // so the sweep starts there.
+    let t0 = (bins / 8) as f64 / fs_out;
+    let mut planner = rustfft::FftPlanner::new();
+    let fft = planner.plan_fft_forward(fft_info.size);
+    let mut fft_results: Vec<Vec<Complex<f32>>> = (0..64).map(|b| {
+        let mut buf: Vec<Complex<f32>> = (0..fft_info.size).map(|i| {
+            let t = (b * fft_interval + i) as f64 / fft_info.fs - t0;
+            let phase = 2.0 * std::f64::consts::PI * (f0 * t + 0.5 * rate * t * t);
+            Complex{ re: phase.cos() as f32, im: phase.sin() as f32 } / fft_info.size as f32
+        }).collect();
+        fft.process(&mut buf);
+        buf
+    }).collect();
+    let results: Vec<&mut [Complex<f32>]> = fft_results.iter_mut().map(|r| &mut r[..]).collect();
+
+    let mut output = Vec::new();
+    let mut samples = Vec::new();
+    for block in results.chunks(8) {
+        let spf = dsp.samples_per_fft();
+        let offsets = tracker.begin_block(block.len(), spf, 0.0).to_vec();
+        dsp.process_block(block, &mut output, None, Some(&offsets));
+        tracker.mix(&mut output, spf);
+        samples.extend_from_slice(&output);
+    }
+    // Sweep covers more than 8 bins
+    assert!(tracker.freq_offset() > 800.0);
+    // Gain varies slightly with the offset from the center bin
+    for s in samples.iter() {
+        assert!((s.norm() - 1.0).abs() < 0.03, "{}", s);
+        assert!((s * samples[0].conj()).arg().abs() < 0.02, "{} != {}", s, samples[0]);
+    }
+}
@@ -0,0 +1,180 @@
+//! Wideband FM broadcast demodulator with stereo and RDS decoding
+//!
+//! The FM discriminator output (the multiplex signal) contains
+//! the L+R audio, a 19 kHz pilot, the L-R audio on a 38 kHz subcarrier
+//! and RDS data on a 57 kHz subcarrier. A PLL is locked to the pilot
+//! and both subcarriers are demodulated using multiples of its phase.
+
+use rustfft::num_complex::Complex;
+
+use super::demod::{Deemphasis, Pll};
+use super::fir::Resampler;
+use super::rds::*;
+
+/// Frequency deviation corresponding to 100% modulation
+const MAX_DEVIATION: f64 = 75000.0;
+const PILOT_FREQ: f64 = 19000.0;
+
+pub struct Wbfm {
+    prev: Complex<f32>,
+    scaling: f32,
+
+    pilot_pll: Pll,
+    /// Lowpass filtered pilot mixed down by the PLL phase
+    pilot: Complex<f32>,
+    pilot_a: f32,
+
+    /// Resampler for L+R in real and L-R in imaginary part
+    audio_resampler: Resampler<Complex<f32>>,
+    deemphasis: [Deemphasis; 2],
+    rds_resampler: Resampler<Complex<f32>>,
+    pub rds: RdsDecoder,
+
+    // Pre-allocated buffers
+    mpx: Vec<Complex<f32>>,
+    rds_mpx: Vec<Complex<f32>>,
+    audio: Vec<Complex<f32>>,
+    rds_baseband: Vec<Complex<f32>>,
+}
+
+impl Wbfm {
+    pub fn init(
+        fs_in: f64, // Sample rate of the channel
+        fs_audio: f64,
+        deemphasis: f32, // Time constant in seconds
+    ) -> Result<Self, String> {
+        Ok(Self {
+            prev: Complex{ re: 0.0, im: 0.0 },
+            scaling: (fs_in / (2.0 * std::f64::consts::PI * MAX_DEVIATION)) as f32,
+            pilot_pll: Pll::init((10.0 / fs_in) as f32, (PILOT_FREQ / fs_in) as f32),
+            pilot: Complex{ re: 0.0, im: 0.0 },
+            pilot_a: 1.0 - (-2.0 * std::f64::consts::PI * 100.0 / fs_in).exp() as f32,
+            audio_resampler: Resampler::init_lowpass(fs_in, fs_audio, 15500.0, 3000.0)?,
+            deemphasis: [Deemphasis::init(fs_audio, deemphasis), Deemphasis::init(fs_audio, deemphasis)],
+            rds_resampler: Resampler::init_lowpass(fs_in, RDS_SAMPLE_RATE, 2400.0, 1500.0)?,
+            rds: RdsDecoder::init(),
+            mpx: Vec::new(),
+            rds_mpx: Vec::new(),
+            audio: Vec::new(),
+            rds_baseband: Vec::new(),
+        })
+    }
+
+    /// Is the stereo pilot present
+    pub fn stereo(&self) -> bool {
+        // Pilot is nominally 10% of the maximum deviation,
+        // which gives 0.05 after mixing down.
+        self.pilot.norm() > 0.02
+    }
+
+    /// Number of audio samples produced at most
+    /// for a given number of input samples.
+    pub fn max_output(&self, input_samples: usize) -> usize {
+        // Two channels
+        2 * self.audio_resampler.max_output(input_samples)
+    }
+
+    /// Demodulate a block of samples.
+    /// Output is interleaved left and right audio samples.
+    pub fn process(&mut self, input: &[Complex<f32>], output: &mut Vec<f32>) {
+        self.mpx.clear();
+        self.rds_mpx.clear();
+        for &s in input.iter() {
+            let m = (s * self.prev.conj()).arg() * self.scaling;
+            self.prev = s;
+
+            // Pilot is sin(phase) when the PLL is locked
+            let phase = self.pilot_pll.phase;
+            self.pilot += (Complex::from_polar(m, -phase) - self.pilot) * self.pilot_a;
+            self.pilot_pll.update((self.pilot * Complex{ re: 0.0, im: 1.0 }).arg());
+
+            self.mpx.push(Complex{ re: m, im: m * 2.0 * (2.0 * phase).sin() });
+            self.rds_mpx.push(Complex::from_polar(m, -3.0 * phase));
+        }
+
+        self.audio.clear();
+        self.audio_resampler.process(&self.mpx, &mut self.audio);
+        let stereo = self.stereo();
+        for s in self.audio.iter() {
+            let (sum, diff) = (s.re, if stereo { s.im } else { 0.0 });
+            output.push(self.deemphasis[0].process(sum + diff));
+            output.push(self.deemphasis[1].process(sum - diff));
+        }
+
+        self.rds_baseband.clear();
+        self.rds_resampler.process(&self.rds_mpx, &mut self.rds_baseband);
+        self.rds.process(&self.rds_baseband);
+    }
+}
+
+
+#[test]
+fn test_wbfm() {
+    // Synthesize a multiplex signal with a tone only in the left channel
+    // and RDS groups containing a program service name.
+    let fs = 240000.0;
+    let pi_code = 0x6201;
+    let ps = b"SPEKTRI!";
+
+    // Encode groups into bits
+    let mut bits: Vec<bool> = Vec::new();
+    for segment in 0..4u16 {
+        let offsets = [0x0FC, 0x198, 0x168, 0x1B4];
+        let blocks = [
+            pi_code,
+            // Group 0A, PTY 10, segment address
+            (10 << 5) | segment,
+            0xE0CD,
+            (ps[2 * segment as usize] as u16) << 8 | ps[2 * segment as usize + 1] as u16,
+        ];
+        for (&info, &offset) in blocks.iter().zip(offsets.iter()) {
+            let block = (info as u32) << 10 | (rds_checkword(info) ^ offset) as u32;
+            bits.extend((0..26).rev().map(|i| block & (1 << i) != 0));
+        }
+    }
+
+    let samples = (fs * 1.5) as usize;
+    let mut phase = 0.0f64;
+    let mut diff_bit = false;
+    let mut prev_bit_index = usize::MAX;
+    let input: Vec<Complex<f32>> = (0..samples).map(|i| {
+        let t = i as f64 / fs;
+        let pilot_phase = 2.0 * std::f64::consts::PI * PILOT_FREQ * t;
+        let left = 0.5 * (2.0 * std::f64::consts::PI * 1000.0 * t).sin();
+        let right = 0.0;
+
+        // Differentially encoded biphase RDS symbols at 1187.5 bit/s
+        let bit_time = t * PILOT_FREQ / 16.0;
+        let bit_index = bit_time as usize;
+        if bit_index != prev_bit_index {
+            diff_bit ^= bits[bit_index % bits.len()];
+            prev_bit_index = bit_index;
+        }
+        let symbol = if (bit_time.fract() < 0.5) == diff_bit { 1.0 } else { -1.0 };
+
+        let mpx =
+            0.45 * (left + right) +
+            0.45 * (left - right) * (2.0 * pilot_phase).sin() +
+            0.1 * pilot_phase.sin() +
+            0.05 * symbol * (3.0 * pilot_phase).sin();
+        phase += 2.0 * std::f64::consts::PI * MAX_DEVIATION * mpx / fs;
+        Complex::from_polar(1.0, phase as f32)
+    }).collect();
+
+    let mut wbfm = Wbfm::init(fs, 48000.0, 0.0).unwrap();
+    let mut output = Vec::new();
+    for block in input.chunks(4096) {
+        wbfm.process(block, &mut output);
+    }
+    assert!(wbfm.stereo());
+    assert!(wbfm.rds.station.pi == pi_code);
+    assert!(&wbfm.rds.station.ps == ps);
+    assert!(wbfm.rds.station.pty == 10);
+
+    // Check stereo separation in the last half second
+    let audio = &output[output.len() - 48000 ..];
+    let rms = |c: usize| (audio.iter().skip(c).step_by(2).map(|v| v * v).sum::<f32>() / 24000.0).sqrt();
+    let (left, right) = (rms(0), rms(1));
+    assert!((left - 0.9 * 0.5 / 2.0f32.sqrt()).abs() < 0.05, "left {}", left);
+    assert!(right < left * 0.05, "left {} right {}", left, right);
+}
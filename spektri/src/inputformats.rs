@@ -153,3 +153,51 @@ pub fn convert_to_cf32(src: &[u8], dst: &mut [Complex<f32>], fmt: InputFormat) {
         _ => panic!("complex conversion called with real format parameter") // bug somewhere
     }
 }
+
+/// Convert complex samples between -1 and 1 to a complex format,
+/// the inverse of convert_to_cf32 followed by input_format_scaling.
+/// Integer values are clipped to their range.
+pub fn convert_from_cf32(src: &[Complex<f32>], dst: &mut [u8], fmt: InputFormat) {
+    let mut offset = 0;
+    let scaling = 1.0 / input_format_scaling(fmt);
+    let int = |v: f32, min: f32, max: f32| (v * scaling).round().clamp(min, max);
+    match fmt {
+        InputFormat::Cu8 =>
+        for v in src.iter() {
+            dst.write_with::<u8>(&mut offset, (v.re * scaling + 127.4).round().clamp(0.0, 255.0) as u8, LE).unwrap();
+            dst.write_with::<u8>(&mut offset, (v.im * scaling + 127.4).round().clamp(0.0, 255.0) as u8, LE).unwrap();
+        },
+
+        InputFormat::Cs8 =>
+        for v in src.iter() {
+            dst.write_with::<i8>(&mut offset, int(v.re, -128.0, 127.0) as i8, LE).unwrap();
+            dst.write_with::<i8>(&mut offset, int(v.im, -128.0, 127.0) as i8, LE).unwrap();
+        },
+
+        InputFormat::Cs16le =>
+        for v in src.iter() {
+            dst.write_with::<i16>(&mut offset, int(v.re, -32768.0, 32767.0) as i16, LE).unwrap();
+            dst.write_with::<i16>(&mut offset, int(v.im, -32768.0, 32767.0) as i16, LE).unwrap();
+        },
+
+        InputFormat::Cs16be =>
+        for v in src.iter() {
+            dst.write_with::<i16>(&mut offset, int(v.re, -32768.0, 32767.0) as i16, BE).unwrap();
+            dst.write_with::<i16>(&mut offset, int(v.im, -32768.0, 32767.0) as i16, BE).unwrap();
+        },
+
+        InputFormat::Cf32le =>
+        for v in src.iter() {
+            dst.write_with::<f32>(&mut offset, v.re, LE).unwrap();
+            dst.write_with::<f32>(&mut offset, v.im, LE).unwrap();
+        },
+
+        InputFormat::Cf32be =>
+        for v in src.iter() {
+            dst.write_with::<f32>(&mut offset, v.re, BE).unwrap();
+            dst.write_with::<f32>(&mut offset, v.im, BE).unwrap();
+        },
+
+        _ => panic!("complex conversion called with real format parameter") // bug somewhere
+    }
+}
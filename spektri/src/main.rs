@@ -11,8 +11,14 @@ mod dsp;
 mod inputformats;
 use inputformats::*;
 
+mod snapshot;
+use snapshot::*;
 
-fn parse_configuration() -> (dsp::DspParams, InputFormat, Vec<String>) {
+mod synthesis;
+use synthesis::*;
+
+
+fn parse_configuration() -> (dsp::DspParams, InputFormat, Vec<String>, Option<String>, Option<SnapshotParams>, Vec<SynthesisInputParams>) {
     use clap::{App};
     let matches = App::new("spektri")
         .args_from_usage("
@@ -21,10 +27,32 @@ fn parse_configuration() -> (dsp::DspParams, InputFormat, Vec<String>) {
             -n, --fftsize=[SIZE]             'FFT size'
                 --fftbuf=[NUMBER]            'Number of FFTs in each input buffer (adjust to optimize performance)'
             -a, --averages=[NUMBER]          'Number of FFTs averaged for spectrum'
+                --averagetime=[SECONDS]      'Spectrum averaging period in seconds, overrides --averages'
+                --alignutc                   'Align spectrum records to multiples of the averaging period in UTC'
+                --timeconstant=[SECONDS]     'Time constant of the exponential moving average spectrum statistic (stat=ema)'
             -I, --inputformat=[FORMAT]       'Input signal format'
                 --spectrumformat=[FORMAT]    'Spectrum output format'
+                --dropedgebin                'Leave out the spectrum bin at the Nyquist frequency'
+                --spectrumunit=[UNIT]        'Spectrum unit: dBFS, dBm or dBmHz'
+                --spectrumflag=[PARAMETERS]  'Leave out interference flagged by spectral kurtosis from the spectrum, e.g. flag=bins:flagffts=64:flagthreshold=3'
+                --detector=[PARAMETERS]      'Detect signals in the spectrum, e.g. detect=median:detectregion=64:detectsnr=10:detectfile=detections.bin'
+                --calibration=[PARAMETERS]   'Power calibration, e.g. fullscale=-10:table=gains.csv:filters=1'
+                --spectrumstat=[PARAMETERS]... 'Additional spectrum statistics, e.g. stat=max:file=max.bin'
+                --spectrum=[PARAMETERS]...   'Additional spectrum products, e.g. time=0.1:range=7e6/7.3e6:bins=1500:reduce=max:file=fast.bin'
                 --filters=[PARAMETERS]...    'Filter parameters'
+                --ssb=[PARAMETERS]...        'SSB receiver parameters'
+                --channelizer=[PARAMETERS]... 'Channelizer parameters'
+                --multiband=[PARAMETERS]...  'Multi-band filter parameters'
+                --telemetry=[RATE]           'Publish channel power and SNR of filters this many times per second'
+                --telemetryfile=[FILE]       'Also write channel telemetry into a file'
                 --zmqbind=[ADDRESS]...       'ZeroMQ binding addresses'
+                --control=[ADDRESS]          'ZeroMQ address to bind for control commands'
+                --history=[SECONDS]          'Keep FFT results in memory for filters starting in the past'
+                --snapshot=[SECONDS]         'Keep input samples in memory for snapshots triggered by SIGUSR1, control or squelch'
+                --snapshotafter=[SECONDS]    'Seconds of input to write after a snapshot is triggered'
+                --snapshotfile=[NAME]        'Snapshot file name, {time} is replaced with the start time'
+                --snapshotformat=[FORMAT]    'Snapshot file format: sigmf or raw'
+                --synthesize=[PARAMETERS]... 'Synthesize a signal with the given sample rate, center frequency and format to standard output from these channels'
             ")
         .get_matches();
 
@@ -47,27 +75,161 @@ fn parse_configuration() -> (dsp::DspParams, InputFormat, Vec<String>) {
         ffts_per_buf:
             value_t!(matches, "fftbuf", usize)
             .unwrap_or(8),
-        spectrum_format:
-            value_t!(matches, "spectrumformat", dsp::SpectrumFormat)
-            .unwrap_or(dsp::SpectrumFormat::U8),
-        spectrum_averages:
-            value_t!(matches, "averages", u32)
-            .unwrap_or(2000),
+        spectra:
+            std::iter::once(dsp::SpectrumParams {
+                averages:
+                    value_t!(matches, "averages", u32)
+                    .unwrap_or(2000),
+                period:
+                    matches.value_of("averagetime")
+                    .map(|v| v.parse().ok().filter(|&t: &f64| t > 0.0)
+                        .unwrap_or_else(|| {
+                            eprintln!("Invalid averaging time {}, must be positive", v);
+                            std::process::exit(1)
+                        })),
+                align:
+                    matches.is_present("alignutc"),
+                time_constant:
+                    value_t!(matches, "timeconstant", f64)
+                    .unwrap_or(1.0),
+                format:
+                    value_t!(matches, "spectrumformat", dsp::SpectrumFormat)
+                    .unwrap_or(dsp::SpectrumFormat::U8),
+                unit:
+                    value_t!(matches, "spectrumunit", dsp::calibration::PowerUnit)
+                    .unwrap_or(dsp::calibration::PowerUnit::Dbfs),
+                flag:
+                    matches.value_of("spectrumflag")
+                    .and_then(|x| x.split(":")
+                        .map(|x| x.split_once('=').ok_or_else(|| format!("Invalid spectrum flagging parameter {}", x)))
+                        .collect::<Result<_, _>>()
+                        .and_then(|m| parse_spectrum_flag_params(&m))
+                        .unwrap_or_else(|e| {
+                            eprintln!("{}", e);
+                            std::process::exit(1)
+                        })),
+                detector:
+                    matches.value_of("detector")
+                    .and_then(|x| x.split(":")
+                        .map(|x| x.split_once('=').ok_or_else(|| format!("Invalid detector parameter {}", x)))
+                        .collect::<Result<_, _>>()
+                        .and_then(|m| parse_detector_params(&m))
+                        .unwrap_or_else(|e| {
+                            eprintln!("{}", e);
+                            std::process::exit(1)
+                        })),
+                drop_edge:
+                    matches.is_present("dropedgebin"),
+                // Mean is written to standard output, followed by any additional statistics
+                outputs:
+                    std::iter::once(Ok(dsp::SpectrumStatParams {
+                        statistic: dsp::SpectrumStatistic::Mean,
+                        output: dsp::output::OutputParams { filename: Some("/dev/stdout".to_string()) },
+                    }))
+                    .chain(values_t![matches, "spectrumstat", String]
+                        .unwrap_or_else(|_| Vec::new())
+                        .iter()
+                        .map(|x| parse_spectrum_stat_params(x)))
+                    .map(|x| x.unwrap_or_else(|e| {
+                        eprintln!("{}", e);
+                        std::process::exit(1)
+                    }))
+                    .collect::<Vec<dsp::SpectrumStatParams>>(),
+                ..Default::default()
+            })
+            .chain(values_t![matches, "spectrum", String]
+                .unwrap_or_else(|_| Vec::new())
+                .iter()
+                .map(|x| parse_spectrum_params(x, matches.is_present("dropedgebin")).unwrap_or_else(|e| {
+                    eprintln!("{}", e);
+                    std::process::exit(1)
+                })))
+            .collect::<Vec<dsp::SpectrumParams>>(),
+        calibration:
+            matches.value_of("calibration")
+            .map(|x| parse_calibration_params(x).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(1)
+            }))
+            .unwrap_or_default(),
         filters:
             values_t![matches, "filters", String]
             .unwrap_or_else(|_| Vec::new())
             .iter()
-            .map(|x| parse_filter_params(x))
+            .map(|x| parse_filter_params(x).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(1)
+            }))
             .collect::<Vec<dsp::FilterParams>>(),
+        ssb_receivers:
+            values_t![matches, "ssb", String]
+            .unwrap_or_else(|_| Vec::new())
+            .iter()
+            .map(|x| parse_ssb_params(x).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(1)
+            }))
+            .collect::<Vec<dsp::SsbParams>>(),
+        channelizers:
+            values_t![matches, "channelizer", String]
+            .unwrap_or_else(|_| Vec::new())
+            .iter()
+            .map(|x| parse_channelizer_params(x).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(1)
+            }))
+            .collect::<Vec<dsp::ChannelizerParams>>(),
+        multibands:
+            values_t![matches, "multiband", String]
+            .unwrap_or_else(|_| Vec::new())
+            .iter()
+            .map(|x| parse_multiband_params(x).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(1)
+            }))
+            .collect::<Vec<dsp::MultibandParams>>(),
+        telemetry:
+            value_t!(matches, "telemetry", f64).ok()
+            .map(|rate| dsp::telemetry::TelemetryParams {
+                rate,
+                output: dsp::output::OutputParams {
+                    filename: matches.value_of("telemetryfile").map(|v| v.to_string()),
+                },
+            }),
+        history:
+            value_t!(matches, "history", f64)
+            .unwrap_or(0.0),
     },
     inputformat,
     values_t!(matches, "zmqbind", String)
-    .unwrap_or(vec!["ipc:///tmp/spektri.zmq".into()])
+    .unwrap_or(vec!["ipc:///tmp/spektri.zmq".into()]),
+    matches.value_of("control").map(|v| v.to_string()),
+    value_t!(matches, "snapshot", f64).ok().map(|before| SnapshotParams {
+        before,
+        after: value_t!(matches, "snapshotafter", f64).unwrap_or(0.0),
+        filename: matches.value_of("snapshotfile").unwrap_or("snapshot_{time}").to_string(),
+        format: value_t!(matches, "snapshotformat", SnapshotFormat).unwrap_or(SnapshotFormat::Sigmf),
+    }),
+    values_t![matches, "synthesize", String]
+    .unwrap_or_else(|_| Vec::new())
+    .iter()
+    .map(|x| parse_synthesis_params(x).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1)
+    }))
+    .collect(),
     )
 }
 
 
-fn parse_filter_params(s: &str) -> dsp::FilterParams {
+/// Parse an optional parameter from a map of key=value parameters.
+fn get_param<T: std::str::FromStr>(m: &std::collections::HashMap<&str, &str>, key: &str) -> Result<Option<T>, String>
+where T::Err: std::fmt::Display {
+    m.get(key).map(|v| v.parse().map_err(|e| format!("Invalid {}={}: {}", key, v, e))).transpose()
+}
+
+
+fn parse_filter_params(s: &str) -> Result<dsp::FilterParams, String> {
     use std::collections::HashMap;
 
     // , might be a nicer separator for parameters, but clap with
@@ -75,47 +237,599 @@ fn parse_filter_params(s: &str) -> dsp::FilterParams {
     // so let's use : instead.
     let m: HashMap<_, _> =
         s.split(":")
-        .map(|x| {
-            x.split_once('=').unwrap() // TODO: handle errors
+        .map(|x| x.split_once('=').ok_or_else(|| format!("Invalid filter parameter {}", x)))
+        .collect::<Result<_, _>>()?;
+
+    // Parse a required parameter
+    let required = |key: &str| -> Result<f64, String> {
+        get_param(&m, key)?.ok_or_else(|| format!("Filter parameter {} is required", key))
+    };
+
+    Ok(dsp::FilterParams {
+        fs_out: required("fs")?,
+        fc_out: required("fc")?,
+        taps: parse_taps_params(&m)?,
+        format: get_param(&m, "format")?.unwrap_or(dsp::data::SignalFormat::Cf32),
+        gain: get_param(&m, "gain")?.unwrap_or(0.0),
+        demod: match get_param::<dsp::demod::DemodMode>(&m, "demod")? {
+            Some(mode) => {
+                // Broadcast FM uses a higher audio sample rate
+                // and a shorter de-emphasis time constant by default.
+                let wfm = mode == dsp::demod::DemodMode::Wfm;
+                Some(dsp::demod::DemodParams {
+                    mode,
+                    fs_audio: get_param(&m, "audiorate")?.unwrap_or(if wfm { 48000.0 } else { 8000.0 }),
+                    bandwidth: get_param(&m, "bw")?,
+                    bfo: get_param(&m, "bfo")?.unwrap_or(700.0),
+                    deviation: get_param(&m, "deviation")?.unwrap_or(5000.0),
+                    // Given in microseconds
+                    deemphasis: get_param::<f32>(&m, "deemph")?.unwrap_or(if wfm { 50.0 } else { 750.0 }) * 1e-6,
+                    agc: m.get("agc").map(|v| *v != "0").unwrap_or(true),
+                    output: dsp::output::OutputParams {
+                        filename: m.get("audiofile").map(|v| v.to_string()),
+                    },
+                    rds_output: dsp::output::OutputParams {
+                        filename: m.get("rdsfile").map(|v| v.to_string()),
+                    },
+                })
+            },
+            None => None,
+        },
+        squelch: match get_param(&m, "squelch")? {
+            Some(threshold) => Some(dsp::squelch::SquelchParams {
+                threshold,
+                hysteresis: get_param(&m, "hysteresis")?.unwrap_or(3.0),
+                hang: get_param(&m, "hang")?.unwrap_or(0.5),
+                preroll: get_param(&m, "preroll")?.unwrap_or(0.0),
+            }),
+            None => None,
+        },
+        schedule: if ["start", "stop", "duration", "repeat"].iter().any(|k| m.contains_key(k)) {
+            Some(dsp::schedule::Schedule {
+                start: get_param(&m, "start")?,
+                stop: get_param(&m, "stop")?,
+                duration: get_param(&m, "duration")?,
+                // Given in seconds or as daily or hourly
+                repeat: match m.get("repeat") {
+                    Some(&"daily") => Some(86400.0),
+                    Some(&"hourly") => Some(3600.0),
+                    _ => get_param(&m, "repeat")?,
+                },
+            })
+        } else { None },
+        tracking: if let Some(v) = m.get("trackfile") {
+            Some(dsp::tracking::TrackingMode::Table(parse_track_table(v)?))
+        } else if let Some(rate) = get_param(&m, "sweep")? {
+            Some(dsp::tracking::TrackingMode::Sweep {
+                rate,
+                span: get_param(&m, "sweepspan")?,
+            })
+        } else {
+            match m.get("track") {
+                Some(&"control") => Some(dsp::tracking::TrackingMode::Control),
+                Some(v) => return Err(format!("Invalid tracking mode {}, use track=control, trackfile= or sweep=", v)),
+                None => None,
+            }
+        },
+        name: m.get("name").map(|v| v.to_string()),
+        history: get_param(&m, "history")?,
+        snapshot: m.get("snapshot").map(|v| *v != "0").unwrap_or(false),
+        output: dsp::output::OutputParams {
+            filename: if let Some(v) = m.get("file")  { Some(v.to_string()) } else { None },
+        },
+    })
+}
+
+
+/// Parse parameters of an additional spectrum statistic.
+///
+/// stat= is mean, max, min, median, a percentile as p0-p100
+/// or ema for an exponential moving average.
+fn parse_spectrum_stat_params(s: &str) -> Result<dsp::SpectrumStatParams, String> {
+    use std::collections::HashMap;
+    let m: HashMap<_, _> =
+        s.split(":")
+        .map(|x| x.split_once('=').ok_or_else(|| format!("Invalid spectrum statistic parameter {}", x)))
+        .collect::<Result<_, _>>()?;
+
+    Ok(dsp::SpectrumStatParams {
+        statistic: m.get("stat").ok_or("Spectrum statistic parameter stat is required")?.parse()?,
+        output: dsp::output::OutputParams {
+            filename: m.get("file").map(|v| v.to_string()),
+        },
+    })
+}
+
+
+/// Parse parameters of an additional spectrum product.
+///
+/// Each product computes one statistic (mean by default)
+/// with its own averaging, bin merging, format and window.
+/// Averaging is given either as a number of FFTs with averages=
+/// or in seconds with time=, which can be aligned to UTC with align=1.
+/// For stat=ema, this is the record interval and tc= is the time constant.
+/// Output can be cropped to a frequency range with range=low/high and
+/// reduced to at most bins= bins, merged using reduce=mean, max or min.
+/// unit= is dBFS, dBm or dBmHz.
+/// Interference can be flagged using spectral kurtosis, see parse_spectrum_flag_params,
+/// and signals detected, see parse_detector_params.
+fn parse_spectrum_params(s: &str, drop_edge: bool) -> Result<dsp::SpectrumParams, String> {
+    use std::collections::HashMap;
+    let m: HashMap<_, _> =
+        s.split(":")
+        .map(|x| x.split_once('=').ok_or_else(|| format!("Invalid spectrum parameter {}", x)))
+        .collect::<Result<_, _>>()?;
+
+    let defaults = dsp::SpectrumParams::default();
+    let merge = get_param(&m, "merge")?.unwrap_or(defaults.merge);
+    if merge == 0 {
+        return Err("Spectrum bin merge factor must be at least 1".into());
+    }
+    let period = get_param::<f64>(&m, "time")?;
+    if period.is_some_and(|t| t.is_nan() || t <= 0.0) {
+        return Err("Spectrum averaging time must be positive".into());
+    }
+    let max_bins = get_param(&m, "bins")?;
+    if max_bins == Some(0) {
+        return Err("Number of spectrum bins must be at least 1".into());
+    }
+    Ok(dsp::SpectrumParams {
+        averages: get_param(&m, "averages")?.unwrap_or(defaults.averages),
+        period,
+        align: m.get("align").map(|v| *v != "0").unwrap_or(false),
+        time_constant: get_param(&m, "tc")?.unwrap_or(defaults.time_constant),
+        format: get_param(&m, "format")?.unwrap_or(defaults.format),
+        window: get_param(&m, "window")?.unwrap_or(defaults.window),
+        merge,
+        range: m.get("range").map(|v| v.split_once('/')
+            .and_then(|(low, high)| Some((low.parse().ok()?, high.parse().ok()?)))
+            .filter(|(low, high): &(f64, f64)| low < high)
+            .ok_or_else(|| format!("Invalid spectrum frequency range {}, should be low/high", v))
+        ).transpose()?,
+        max_bins,
+        reduction: get_param(&m, "reduce")?,
+        unit: get_param(&m, "unit")?.unwrap_or(defaults.unit),
+        flag: parse_spectrum_flag_params(&m)?,
+        detector: parse_detector_params(&m)?,
+        drop_edge,
+        outputs: vec![dsp::SpectrumStatParams {
+            statistic: get_param(&m, "stat")?.unwrap_or(dsp::SpectrumStatistic::Mean),
+            output: dsp::output::OutputParams {
+                filename: m.get("file").map(|v| v.to_string()),
+            },
+        }],
+    })
+}
+
+
+/// Parse parameters for flagging interference using spectral kurtosis.
+///
+/// flag= is bins or ffts, flagffts= the number of FFTs in each block
+/// and flagthreshold= the threshold in standard deviations.
+fn parse_spectrum_flag_params(
+    m: &std::collections::HashMap<&str, &str>,
+) -> Result<Option<dsp::SpectrumFlagParams>, String> {
+    let mode = match m.get("flag") {
+        Some(v) => v.parse()?,
+        None => return Ok(None),
+    };
+    Ok(Some(dsp::SpectrumFlagParams {
+        mode,
+        ffts: match m.get("flagffts") {
+            Some(v) => v.parse().ok().filter(|&n: &u32| n >= 2)
+                .ok_or_else(|| format!("Invalid number of FFTs for flagging {}", v))?,
+            None => 64,
+        },
+        threshold: match m.get("flagthreshold") {
+            Some(v) => v.parse().map_err(|_| format!("Invalid flagging threshold {}", v))?,
+            None => 3.0,
+        },
+    }))
+}
+
+
+/// Parse parameters for detecting signals in a spectrum.
+///
+/// detect= is the noise floor estimation method, median or morphological,
+/// detectregion= the number of bins in each noise floor region,
+/// detectsnr= the detection threshold in dB and
+/// detectfile= the file detections are written to.
+fn parse_detector_params(
+    m: &std::collections::HashMap<&str, &str>,
+) -> Result<Option<dsp::detector::DetectorParams>, String> {
+    let method = match m.get("detect") {
+        Some(v) => v.parse()?,
+        None => return Ok(None),
+    };
+    Ok(Some(dsp::detector::DetectorParams {
+        method,
+        region: match m.get("detectregion") {
+            Some(v) => v.parse().ok().filter(|&n: &usize| n > 0)
+                .ok_or_else(|| format!("Invalid detector region size {}", v))?,
+            None => 64,
+        },
+        snr: match m.get("detectsnr") {
+            Some(v) => v.parse().map_err(|_| format!("Invalid detection threshold {}", v))?,
+            None => 10.0,
+        },
+        output: dsp::output::OutputParams {
+            filename: m.get("detectfile").map(|v| v.to_string()),
+        },
+    }))
+}
+
+
+/// Parse power calibration parameters.
+///
+/// fullscale= is the power in dBm of a full-scale sinusoid at the
+/// receiver input, table= a CSV file of gain in front of the receiver
+/// and filters=1 applies the gain correction also to filter outputs.
+fn parse_calibration_params(s: &str) -> Result<dsp::calibration::Calibration, String> {
+    use std::collections::HashMap;
+    let m: HashMap<_, _> =
+        s.split(":")
+        .map(|x| x.split_once('=').ok_or_else(|| format!("Invalid calibration parameter {}", x)))
+        .collect::<Result<_, _>>()?;
+
+    Ok(dsp::calibration::Calibration {
+        fullscale_dbm: match m.get("fullscale") {
+            Some(v) => v.parse().map_err(|_| format!("Invalid full-scale power {}", v))?,
+            None => 0.0,
+        },
+        gains: match m.get("table") {
+            Some(v) => dsp::calibration::load_gain_table(v)
+                .map_err(|e| format!("Error loading gain table: {}", e))?,
+            None => Vec::new(),
+        },
+        filters: m.get("filters").map(|v| *v != "0").unwrap_or(false),
+    })
+}
+
+
+fn parse_ssb_params(s: &str) -> Result<dsp::SsbParams, String> {
+    use std::collections::HashMap;
+    let m: HashMap<_, _> =
+        s.split(":")
+        .map(|x| x.split_once('=').ok_or_else(|| format!("Invalid SSB parameter {}", x)))
+        .collect::<Result<_, _>>()?;
+
+    Ok(dsp::SsbParams {
+        mode: get_param(&m, "mode")?.unwrap_or(dsp::demod::DemodMode::Usb),
+        fs_audio: get_param(&m, "fs")?.unwrap_or(8000.0),
+        fc: get_param(&m, "fc")?.ok_or("SSB parameter fc is required")?,
+        bfo: get_param(&m, "bfo")?.unwrap_or(0.0),
+        low: get_param(&m, "low")?.unwrap_or(300.0),
+        high: get_param(&m, "high")?.unwrap_or(2700.0),
+        gain: get_param(&m, "gain")?.unwrap_or(0.0),
+        output: dsp::output::OutputParams {
+            filename: m.get("file").map(|v| v.to_string()),
+        },
+    })
+}
+
+
+fn parse_channelizer_params(s: &str) -> Result<dsp::ChannelizerParams, String> {
+    use std::collections::HashMap;
+    let m: HashMap<_, _> =
+        s.split(":")
+        .map(|x| x.split_once('=').ok_or_else(|| format!("Invalid channelizer parameter {}", x)))
+        .collect::<Result<_, _>>()?;
+
+    // Parse a required parameter
+    let required = |key: &str| -> Result<f64, String> {
+        get_param(&m, key)?.ok_or_else(|| format!("Channelizer parameter {} is required", key))
+    };
+
+    Ok(dsp::ChannelizerParams {
+        start: required("start")?,
+        stop: required("stop")?,
+        spacing: required("spacing")?,
+        fs_out: required("fs")?,
+        taps: parse_taps_params(&m)?,
+        format: get_param(&m, "format")?.unwrap_or(dsp::data::SignalFormat::Cf32),
+        gain: get_param(&m, "gain")?.unwrap_or(0.0),
+        output: dsp::output::OutputParams {
+            filename: m.get("file").map(|v| v.to_string()),
+        },
+    })
+}
+
+
+/// Parse multi-band filter parameters.
+///
+/// Bands are given as bands=fc/bw;fc/bw;... where each band
+/// may also have a gain in dB as fc/bw/gain.
+fn parse_multiband_params(s: &str) -> Result<dsp::MultibandParams, String> {
+    use std::collections::HashMap;
+    let m: HashMap<_, _> =
+        s.split(":")
+        .map(|x| x.split_once('=').ok_or_else(|| format!("Invalid multi-band filter parameter {}", x)))
+        .collect::<Result<_, _>>()?;
+
+    let bands = m.get("bands").ok_or("Multi-band filter parameter bands is required")?
+        .split(';')
+        .filter(|x| !x.is_empty())
+        .map(|band| {
+            let v = band.split('/')
+                .map(|x| x.parse::<f64>().map_err(|e| format!("Invalid band {}: {}", band, e)))
+                .collect::<Result<Vec<f64>, String>>()?;
+            match v[..] {
+                [fc, bw] => Ok(dsp::Band { fc, bw, gain: 0.0 }),
+                [fc, bw, gain] => Ok(dsp::Band { fc, bw, gain: gain as f32 }),
+                _ => Err(format!("Invalid band {}, should be fc/bw or fc/bw/gain", band)),
+            }
         })
-        .collect();
+        .collect::<Result<Vec<dsp::Band>, String>>()?;
 
-    dsp::FilterParams {
-        // TODO: handle errors
-        fs_out: m.get("fs").unwrap().parse().unwrap(),
-        fc_out: m.get("fc").unwrap().parse().unwrap(),
+    Ok(dsp::MultibandParams {
+        bands,
+        fs_out: get_param(&m, "fs")?,
+        format: get_param(&m, "format")?.unwrap_or(dsp::data::SignalFormat::Cf32),
+        gain: get_param(&m, "gain")?.unwrap_or(0.0),
         output: dsp::output::OutputParams {
-            filename: if let Some(v) = m.get("file")  { Some(v.to_string()) } else { None },
+            filename: m.get("file").map(|v| v.to_string()),
+        },
+    })
+}
+
+
+/// Parse parameters of a synthesis channel.
+///
+/// Samples are read from file= in format= (cf32le by default)
+/// or received from a filter publishing at zmq=.
+fn parse_synthesis_params(s: &str) -> Result<SynthesisInputParams, String> {
+    use std::collections::HashMap;
+    // ZeroMQ addresses contain :, so a part without =
+    // continues the value of the previous parameter.
+    let parts = s.split(":").fold(Vec::<String>::new(), |mut parts, x| {
+        match parts.last_mut() {
+            Some(last) if !x.contains('=') => { last.push(':'); last.push_str(x); },
+            _ => parts.push(x.to_string()),
+        }
+        parts
+    });
+    let m: HashMap<_, _> =
+        parts.iter()
+        .map(|x| x.split_once('=').ok_or_else(|| format!("Invalid synthesis parameter {}", x)))
+        .collect::<Result<_, _>>()?;
+
+    let required = |key: &str| -> Result<f64, String> {
+        get_param(&m, key)?.ok_or_else(|| format!("Synthesis parameter {} is required", key))
+    };
+
+    Ok(SynthesisInputParams {
+        channel: dsp::synthesis::SynthesisChannelParams {
+            fs: required("fs")?,
+            fc: required("fc")?,
+            taps: parse_taps_params(&m)?,
+            gain: get_param(&m, "gain")?.unwrap_or(0.0),
         },
+        source: match (m.get("file"), m.get("zmq")) {
+            (Some(filename), None) => SynthesisSource::File {
+                filename: filename.to_string(),
+                format: get_param(&m, "format")?.unwrap_or(InputFormat::Cf32le),
+            },
+            (None, Some(address)) => SynthesisSource::Zmq { address: address.to_string() },
+            _ => return Err("Synthesis channel needs either file= or zmq=".to_string()),
+        },
+    })
+}
+
+
+/// Get FIR filter coefficients given either inline by taps=
+/// or in a file by tapfile=.
+fn parse_taps_params(m: &std::collections::HashMap<&str, &str>) -> Result<Option<Vec<f32>>, String> {
+    if m.contains_key("taps") && m.contains_key("tapfile") {
+        return Err("Only one of taps= and tapfile= can be given".to_string());
     }
+    if let Some(v) = m.get("taps") {
+        return parse_taps(v).map(Some);
+    }
+    m.get("tapfile").map(|v| {
+        let s = std::fs::read_to_string(v).map_err(|e|
+            format!("Could not read FIR coefficients from {}: {}", v, e))?;
+        parse_taps(&s)
+    }).transpose()
+}
+
+
+/// Read a table of center frequencies for a tracking filter.
+///
+/// Each line has a time and a frequency in Hz, separated by
+/// whitespace or , like the FIR coefficient files.
+/// Time is given in seconds since the Unix epoch or as
+/// YYYYMMDDTHHMMSS in UTC. Lines starting with # are ignored.
+fn parse_track_table(filename: &str) -> Result<Vec<(f64, f64)>, String> {
+    let fail = |msg: String| format!("Invalid frequency table {}: {}", filename, msg);
+    let s = std::fs::read_to_string(filename).map_err(|e| fail(e.to_string()))?;
+    let mut table: Vec<(f64, f64)> = s.lines()
+    .filter(|line| !line.trim_start().starts_with('#') && !line.trim().is_empty())
+    .map(|line| {
+        let fields: Vec<&str> = line.split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|x| !x.is_empty()).collect();
+        if fields.len() != 2 {
+            return Err(fail(format!("expected time and frequency on line {}", line)));
+        }
+        let time = fields[0].parse::<f64>().or_else(|_|
+            match fields[0].parse::<dsp::schedule::ScheduleTime>() {
+                Ok(dsp::schedule::ScheduleTime::Absolute(t)) => Ok(t),
+                _ => Err(fail(format!("invalid time {}", fields[0]))),
+            })?;
+        let freq = fields[1].parse::<f64>().map_err(|e| fail(e.to_string()))?;
+        Ok((time, freq))
+    })
+    .collect::<Result<_, _>>()?;
+    table.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+    Ok(table)
+}
+
+
+/// Parse a list of FIR filter coefficients.
+///
+/// Inline coefficients are separated by ; since both , and :
+/// have another meaning on the command line.
+/// Files may use any of whitespace, , or ; as separators,
+/// so the output of numpy.savetxt can be used as such.
+/// Lines starting with # are ignored.
+fn parse_taps(s: &str) -> Result<Vec<f32>, String> {
+    s.lines()
+    .filter(|line| !line.trim_start().starts_with('#'))
+    .flat_map(|line| line.split(|c: char| c.is_whitespace() || c == ',' || c == ';'))
+    .filter(|x| !x.is_empty())
+    .map(|x| x.parse().map_err(|e| format!("Invalid FIR coefficient {}: {}", x, e)))
+    .collect()
 }
 
 
 fn main() -> std::io::Result<()> {
-    let (dspparams, inputformat, zmqbind) = parse_configuration();
+    let (dspparams, inputformat, zmqbind, control, snapshot, synthesis) = parse_configuration();
 
     let zctx = zmq::Context::new();
+    if !synthesis.is_empty() {
+        return synthesis_mainloop(SynthesisParams {
+            fs: dspparams.fs_in,
+            fc: dspparams.fc_in,
+            fft_size: dspparams.fft_size,
+            ffts_per_buf: dspparams.ffts_per_buf,
+            format: inputformat,
+            inputs: synthesis,
+        }, &zctx);
+    }
+
     let sock = zctx.socket(zmq::PUB).unwrap();
     // TODO: set SNDBUF and HWM sizes
     for address in zmqbind.iter() {
         sock.bind(&address).unwrap();
     }
+    let control = control.map(|address| {
+        let ctl = zctx.socket(zmq::REP).unwrap();
+        ctl.bind(&address).unwrap();
+        ctl
+    });
 
     if is_input_format_complex(inputformat) {
-        mainloop_complex(dspparams, inputformat, sock)
+        mainloop_complex(dspparams, inputformat, sock, control, snapshot)
     } else {
-        mainloop_real(   dspparams, inputformat, sock)
+        mainloop_real(   dspparams, inputformat, sock, control, snapshot)
     }?;
     Ok(())
 }
 
 
+/// Handle commands received through the control interface.
+///
+/// Commands are text messages with words separated by spaces:
+///   freq NAME HZ      Set the center frequency of a tracking filter
+///   filter PARAMS     Add a filter with parameters given like in --filters,
+///                     except for parameters reading or writing files
+///   remove NAME       Remove a filter
+///   snapshot          Write a snapshot of the input signal
+/// The reply is OK or ERROR followed by a message.
+fn handle_control<T: SnapshotSample>(ctl: &zmq::Socket, dsp: &mut dsp::DspState, snapshot: &mut Snapshot<T>) {
+    while let Ok(message) = ctl.recv_string(zmq::DONTWAIT) {
+        let command = message.unwrap_or_default();
+        let words: Vec<&str> = command.split_whitespace().collect();
+        let result: Result<(), Box<dyn std::error::Error>> = match words[..] {
+            ["freq", name, freq] => freq.parse::<f64>()
+                .map_err(|e| e.into())
+                .and_then(|freq| dsp.set_filter_frequency(name, freq)),
+            ["filter", params] => parse_control_filter_params(params)
+                .map_err(|e| e.into())
+                .and_then(|p| dsp.add_filter(&p)),
+            ["remove", name] => dsp.remove_filter(name),
+            ["snapshot"] => snapshot.trigger().map(|_| ()).map_err(|e| e.into()),
+            _ => Err("Unknown command".into()),
+        };
+        let reply = match result {
+            Ok(()) => "OK".to_string(),
+            Err(err) => format!("ERROR {}", err),
+        };
+        if let Err(err) = ctl.send(&reply, 0) {
+            eprintln!("Error replying to control command: {}", err);
+        }
+    }
+}
+
+
+/// Filter parameters which read or write files
+const FILE_FILTER_PARAMS: [&str; 5] = ["tapfile", "trackfile", "file", "audiofile", "rdsfile"];
+
+/// Parse parameters of a filter added through the control interface.
+///
+/// Anyone who can reach the control socket should not be able
+/// to read or write files on the host, so filters added there
+/// can only be published by ZeroMQ. Details of a parse error are
+/// printed locally but not replied, since they may contain
+/// parts of the parameters.
+fn parse_control_filter_params(s: &str) -> Result<dsp::FilterParams, String> {
+    let keys = s.split(':').map(|x| x.split_once('=').map_or(x, |(key, _)| key));
+    if let Some(key) = keys.filter_map(|key| FILE_FILTER_PARAMS.iter().find(|&&k| k == key)).next() {
+        return Err(format!("Filter parameter {} is not allowed through control", key));
+    }
+    parse_filter_params(s).map_err(|err| {
+        eprintln!("Invalid filter from control: {}", err);
+        "Invalid filter parameters".to_string()
+    })
+}
+
+
+/// Snapshots of the input signal and their triggers
+/// other than the control interface.
+struct Snapshot<T> {
+    snapshotter: Option<Snapshotter<T>>,
+    /// Set by SIGUSR1
+    signal: std::sync::Arc<std::sync::atomic::AtomicBool>,
+}
+
+impl<T: SnapshotSample> Snapshot<T> {
+    fn init(
+        params: Option<&SnapshotParams>,
+        fs: f64,
+        fc: f64,
+        block_samples: usize,
+    ) -> std::io::Result<Self> {
+        let signal = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+        if params.is_some() {
+            signal_hook::flag::register(signal_hook::consts::SIGUSR1, signal.clone())?;
+        }
+        Ok(Self {
+            snapshotter: params.map(|p| Snapshotter::init(p, fs, fc, block_samples)),
+            signal,
+        })
+    }
+
+    fn trigger(&mut self) -> Result<String, String> {
+        match &mut self.snapshotter {
+            Some(s) => s.trigger(),
+            None => Err("Snapshots are not enabled".to_string()),
+        }
+    }
+
+    /// Trigger a snapshot if a filter or SIGUSR1 has triggered one.
+    fn update(&mut self, triggered: bool) {
+        let signal = self.signal.swap(false, std::sync::atomic::Ordering::Relaxed);
+        if (triggered || signal) && self.snapshotter.is_some() {
+            if let Err(err) = self.trigger() {
+                eprintln!("Error triggering snapshot: {}", err);
+            }
+        }
+    }
+}
+
+
 fn mainloop_complex(
     dspparams: dsp::DspParams,
     fmt: InputFormat,
     sock: zmq::Socket,
+    control: Option<zmq::Socket>,
+    snapshot: Option<SnapshotParams>,
 ) -> std::io::Result<()> {
-    let (mut dsp, bufsize) = dsp::DspState::init(dspparams);
+    let (fs, fc) = (dspparams.fs_in, dspparams.fc_in);
+    let (mut dsp, bufsize) = dsp::DspState::init(dspparams).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1)
+    });
+    let mut snapshot = Snapshot::init(snapshot.as_ref(), fs, fc, bufsize.new)?;
 
     // buffer for raw input data
     let mut rawbuf: Vec<u8> = vec![0; bytes_per_input_sample(fmt) * bufsize.new];
@@ -138,6 +852,9 @@ fn mainloop_complex(
         }
         let systemtime = std::time::SystemTime::now();
         convert_to_cf32(&rawbuf, &mut buf[bufsize.overlap .. bufsize.total], fmt);
+        if let Some(s) = &mut snapshot.snapshotter {
+            s.push(&buf[bufsize.overlap .. bufsize.total], systemtime);
+        }
 
         let metadata = dsp::Metadata {
             seq: seq,
@@ -145,6 +862,10 @@ fn mainloop_complex(
         };
 
         dsp.process_complex(&buf, &metadata, &sock)?;
+        if let Some(ctl) = &control {
+            handle_control(ctl, &mut dsp, &mut snapshot);
+        }
+        snapshot.update(dsp.take_snapshot_trigger());
 
         seq += 1;
     }
@@ -155,8 +876,15 @@ fn mainloop_real(
     dspparams: dsp::DspParams,
     fmt: InputFormat,
     sock: zmq::Socket,
+    control: Option<zmq::Socket>,
+    snapshot: Option<SnapshotParams>,
 ) -> std::io::Result<()> {
-    let (mut dsp, bufsize) = dsp::DspState::init(dspparams);
+    let (fs, fc) = (dspparams.fs_in, dspparams.fc_in);
+    let (mut dsp, bufsize) = dsp::DspState::init(dspparams).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1)
+    });
+    let mut snapshot = Snapshot::init(snapshot.as_ref(), fs, fc, bufsize.new)?;
 
     // buffer for raw input data
     let mut rawbuf: Vec<u8> = vec![0; bytes_per_input_sample(fmt) * bufsize.new];
@@ -179,6 +907,9 @@ fn mainloop_real(
         }
         let systemtime = std::time::SystemTime::now();
         convert_to_f32(&rawbuf, &mut buf[bufsize.overlap .. bufsize.total], fmt);
+        if let Some(s) = &mut snapshot.snapshotter {
+            s.push(&buf[bufsize.overlap .. bufsize.total], systemtime);
+        }
 
         let metadata = dsp::Metadata {
             seq: seq,
@@ -186,8 +917,26 @@ fn mainloop_real(
         };
 
         dsp.process_real(&buf, &metadata, &sock)?;
+        if let Some(ctl) = &control {
+            handle_control(ctl, &mut dsp, &mut snapshot);
+        }
+        snapshot.update(dsp.take_snapshot_trigger());
 
         seq += 1;
     }
     Ok(())
 }
+
+
+#[test]
+fn test_control_filter_params() {
+    let err = parse_control_filter_params("fs=8000:fc=1000:tapfile=/etc/passwd").err().unwrap();
+    assert!(err == "Filter parameter tapfile is not allowed through control");
+    for key in FILE_FILTER_PARAMS {
+        assert!(parse_control_filter_params(&format!("fs=8000:fc=1000:{}=/tmp/x", key)).is_err());
+    }
+    // Parse errors do not echo the parameters
+    let err = parse_control_filter_params("fs=8000:fc=1000:gain=secret").err().unwrap();
+    assert!(!err.contains("secret"));
+    assert!(parse_control_filter_params("fs=8000:fc=1000").is_ok());
+}
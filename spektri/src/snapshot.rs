@@ -0,0 +1,276 @@
+//! Wideband I/Q snapshots
+//!
+//! The most recent converted input samples are kept in a ring buffer.
+//! When a snapshot is triggered, the buffered samples and the samples
+//! following the trigger are written to a file by a separate thread,
+//! so writing does not stall the signal processing loop.
+
+use std::collections::VecDeque;
+use std::io::Write;
+use std::sync::{Arc, mpsc};
+use rustfft::num_complex::Complex;
+
+use crate::dsp::schedule::{format_datetime, format_time, unix_time};
+
+arg_enum! { // needed for command line parsing
+    #[derive(Debug, Copy, Clone, PartialEq)]
+    pub enum SnapshotFormat { Raw, Sigmf }
+}
+
+/// Snapshot parameters
+pub struct SnapshotParams {
+    pub before: f64, // Seconds of input kept before a trigger
+    pub after: f64, // Seconds of input written after a trigger
+    /// File name without extension.
+    /// {time} is replaced with the time of the first sample.
+    pub filename: String,
+    pub format: SnapshotFormat,
+}
+
+/// Input sample types which can be written into a snapshot
+pub trait SnapshotSample: Copy + Send + Sync + 'static {
+    /// SigMF data type
+    const DATATYPE: &'static str;
+    fn write_le(&self, out: &mut Vec<u8>);
+}
+
+impl SnapshotSample for f32 {
+    const DATATYPE: &'static str = "rf32_le";
+    fn write_le(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_le_bytes());
+    }
+}
+
+impl SnapshotSample for Complex<f32> {
+    const DATATYPE: &'static str = "cf32_le";
+    fn write_le(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.re.to_le_bytes());
+        out.extend_from_slice(&self.im.to_le_bytes());
+    }
+}
+
+/// Input samples of one processing block
+struct Block<T> {
+    samples: Vec<T>,
+    /// Unix time of the first sample
+    time: f64,
+}
+
+/// Snapshot being written
+struct ActiveSnapshot<T> {
+    sender: mpsc::Sender<Arc<Block<T>>>,
+    /// Number of blocks still to be sent
+    blocks_left: usize,
+}
+
+pub struct Snapshotter<T> {
+    fs: f64,
+    fc: f64,
+    filename: String,
+    format: SnapshotFormat,
+    /// Number of blocks kept before a trigger
+    capacity: usize,
+    /// Number of blocks written after a trigger
+    blocks_after: usize,
+    blocks: VecDeque<Arc<Block<T>>>,
+    active: Option<ActiveSnapshot<T>>,
+}
+
+impl<T: SnapshotSample> Snapshotter<T> {
+    pub fn init(
+        p: &SnapshotParams,
+        fs: f64, // Input sample rate
+        fc: f64, // Input center frequency
+        block_samples: usize, // Number of new samples in each processing block
+    ) -> Self {
+        let block_duration = block_samples as f64 / fs;
+        let capacity = (p.before / block_duration).ceil() as usize;
+        eprintln!("Keeping {} s of input samples for snapshots, using {:.1} MiB of memory",
+            p.before, (capacity * block_samples * std::mem::size_of::<T>()) as f64 / 1048576.0);
+        Self {
+            fs,
+            fc,
+            filename: p.filename.clone(),
+            format: p.format,
+            capacity,
+            blocks_after: (p.after / block_duration).ceil() as usize,
+            blocks: VecDeque::with_capacity(capacity + 1),
+            active: None,
+        }
+    }
+
+    /// Add the new input samples of a processing block.
+    pub fn push(
+        &mut self,
+        samples: &[T],
+        systemtime: std::time::SystemTime, // Time when the block was received
+    ) {
+        // Reuse the buffer of the oldest block if it is not being written
+        let mut buf = if self.blocks.len() >= self.capacity {
+            self.blocks.pop_front()
+                .and_then(|b| Arc::try_unwrap(b).ok())
+                .map(|b| b.samples)
+                .unwrap_or_default()
+        } else {
+            Vec::new()
+        };
+        buf.clear();
+        buf.extend_from_slice(samples);
+        let block = Arc::new(Block {
+            samples: buf,
+            // Block is received after its last sample
+            time: unix_time(systemtime) - samples.len() as f64 / self.fs,
+        });
+
+        if let Some(active) = &mut self.active {
+            if active.sender.send(block.clone()).is_err() || active.blocks_left <= 1 {
+                // Dropping the sender makes the writer thread finish.
+                self.active = None;
+            } else {
+                active.blocks_left -= 1;
+            }
+        }
+        if self.capacity > 0 {
+            self.blocks.push_back(block);
+        }
+    }
+
+    /// Start writing a snapshot.
+    /// If one is already being written, it is extended instead.
+    ///
+    /// Return the name of the file.
+    pub fn trigger(&mut self) -> Result<String, String> {
+        if let Some(active) = &mut self.active {
+            active.blocks_left = self.blocks_after;
+            return Ok("snapshot in progress extended".to_string());
+        }
+        let time = self.blocks.front().map(|b| b.time).unwrap_or_else(|| unix_time(std::time::SystemTime::now()));
+        let name = self.filename.replace("{time}", &format_time(time));
+        let (sender, receiver) = mpsc::channel();
+        for block in self.blocks.iter() {
+            sender.send(block.clone()).map_err(|e| e.to_string())?;
+        }
+        let writer = SnapshotWriter {
+            name: name.clone(),
+            format: self.format,
+            fs: self.fs,
+            fc: self.fc,
+        };
+        std::thread::spawn(move || {
+            if let Err(err) = writer.write(receiver) {
+                eprintln!("Error writing snapshot {}: {}", writer.name, err);
+            }
+        });
+        if self.blocks_after > 0 {
+            self.active = Some(ActiveSnapshot { sender, blocks_left: self.blocks_after });
+        }
+        eprintln!("Writing snapshot {}", name);
+        Ok(name)
+    }
+}
+
+/// Writes a snapshot in a separate thread
+struct SnapshotWriter {
+    name: String,
+    format: SnapshotFormat,
+    fs: f64,
+    fc: f64,
+}
+
+impl SnapshotWriter {
+    fn write<T: SnapshotSample>(&self, receiver: mpsc::Receiver<Arc<Block<T>>>) -> std::io::Result<()> {
+        let data_name = match self.format {
+            SnapshotFormat::Raw => self.name.clone(),
+            SnapshotFormat::Sigmf => format!("{}.sigmf-data", self.name),
+        };
+        let mut file = std::io::BufWriter::new(std::fs::File::create(&data_name)?);
+        let mut buf = Vec::new();
+        let mut start_time = None;
+        // Loop ends when the sender is dropped
+        for block in receiver.iter() {
+            start_time.get_or_insert(block.time);
+            buf.clear();
+            block.samples.iter().for_each(|s| s.write_le(&mut buf));
+            file.write_all(&buf)?;
+        }
+        file.flush()?;
+
+        if self.format == SnapshotFormat::Sigmf {
+            std::fs::write(format!("{}.sigmf-meta", self.name),
+                sigmf_metadata(T::DATATYPE, self.fs, self.fc, start_time.unwrap_or(0.0)))?;
+        }
+        Ok(())
+    }
+}
+
+/// SigMF metadata for a snapshot
+fn sigmf_metadata(
+    datatype: &str,
+    fs: f64,
+    fc: f64,
+    time: f64, // Unix time of the first sample
+) -> String {
+    format!(concat!(
+        "{{\n",
+        "    \"global\": {{\n",
+        "        \"core:datatype\": \"{}\",\n",
+        "        \"core:sample_rate\": {},\n",
+        "        \"core:version\": \"1.0.0\",\n",
+        "        \"core:recorder\": \"spektri\"\n",
+        "    }},\n",
+        "    \"captures\": [\n",
+        "        {{\n",
+        "            \"core:sample_start\": 0,\n",
+        "            \"core:frequency\": {},\n",
+        "            \"core:datetime\": \"{}\"\n",
+        "        }}\n",
+        "    ],\n",
+        "    \"annotations\": []\n",
+        "}}\n"),
+        datatype, fs, fc, format_datetime(time))
+}
+
+
+#[test]
+fn test_snapshot() {
+    let name = std::env::temp_dir().join(format!("spektri_test_{}_{{time}}", std::process::id()));
+    let mut snapshotter = Snapshotter::<Complex<f32>>::init(&SnapshotParams {
+        before: 0.2,
+        after: 0.1,
+        filename: name.to_str().unwrap().to_string(),
+        format: SnapshotFormat::Sigmf,
+    }, 1000.0, 1e6, 100);
+    let start = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1792305000);
+    let block = |i: u64| (
+        vec![Complex{ re: i as f32, im: 0.0 }; 100],
+        start + std::time::Duration::from_millis(100 * (i + 1)),
+    );
+    // Blocks 2-3 are before the trigger and 4 after it
+    for i in 0..4 {
+        let (samples, time) = block(i);
+        snapshotter.push(&samples, time);
+    }
+    let name = snapshotter.trigger().unwrap();
+    assert!(name.ends_with("20261018T063000Z"));
+    for i in 4..6 {
+        let (samples, time) = block(i);
+        snapshotter.push(&samples, time);
+    }
+    assert!(snapshotter.active.is_none());
+
+    // Wait for the writer thread
+    let meta = format!("{}.sigmf-meta", name);
+    for _ in 0..100 {
+        if std::path::Path::new(&meta).exists() { break; }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    let data = std::fs::read(format!("{}.sigmf-data", name)).unwrap();
+    let meta = std::fs::read_to_string(meta).unwrap();
+    assert!(data.len() == 3 * 100 * 8);
+    assert!(f32::from_le_bytes([data[0], data[1], data[2], data[3]]) == 2.0);
+    assert!(f32::from_le_bytes([data[1600], data[1601], data[1602], data[1603]]) == 4.0);
+    assert!(meta.contains("\"core:datatype\": \"cf32_le\""));
+    assert!(meta.contains("\"core:datetime\": \"2026-10-18T06:30:00.200000Z\""));
+    std::fs::remove_file(format!("{}.sigmf-data", name)).unwrap();
+    std::fs::remove_file(format!("{}.sigmf-meta", name)).unwrap();
+}
@@ -0,0 +1,231 @@
+//! Synthesis of a wideband signal from narrowband channels
+//!
+//! Channels are read from files or received through ZeroMQ,
+//! e.g. from the filters of another spektri instance,
+//! and the combined signal is written to standard output.
+
+use std::io::{Read, Write};
+use rustfft::num_complex::Complex;
+
+use crate::dsp;
+use crate::dsp::data::*;
+use crate::dsp::synthesis::*;
+use crate::inputformats::*;
+
+/// Where the samples of a synthesis channel come from
+pub enum SynthesisSource {
+    /// File with samples in a given format
+    File { filename: String, format: InputFormat },
+    /// ZeroMQ address publishing a signal with the sample rate
+    /// and center frequency of the channel
+    Zmq { address: String },
+}
+
+pub struct SynthesisInputParams {
+    pub channel: SynthesisChannelParams,
+    pub source: SynthesisSource,
+}
+
+/// Synthesis parameters
+pub struct SynthesisParams {
+    pub fs: f64, // Output sample rate
+    pub fc: f64, // Output center frequency
+    pub fft_size: usize, // IFFT size
+    pub ffts_per_buf: usize,
+    pub format: InputFormat, // Output sample format
+    pub inputs: Vec<SynthesisInputParams>,
+}
+
+/// Longest gap in seconds between records received through ZeroMQ
+/// which is filled with zeros
+const MAX_GAP: f64 = 10.0;
+
+enum Input {
+    File {
+        file: std::io::BufReader<std::fs::File>,
+        format: InputFormat,
+        rawbuf: Vec<u8>,
+        eof: bool,
+    },
+    Zmq {
+        socket: zmq::Socket,
+        fs: f64,
+        fc: f64,
+        /// Sequence number of the latest record
+        seq: Option<u64>,
+    },
+}
+
+/// Buffered samples of a synthesis channel
+struct InputChannel {
+    input: Input,
+    samples: Vec<Complex<f32>>,
+}
+
+impl InputChannel {
+    fn init(p: &SynthesisInputParams, zctx: &zmq::Context) -> std::io::Result<Self> {
+        let input = match &p.source {
+            SynthesisSource::File { filename, format } => {
+                if !is_input_format_complex(*format) {
+                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+                        format!("Synthesis input {} must have a complex format", filename)));
+                }
+                Input::File {
+                    file: std::io::BufReader::new(std::fs::File::open(filename)?),
+                    format: *format,
+                    rawbuf: Vec::new(),
+                    eof: false,
+                }
+            },
+            SynthesisSource::Zmq { address } => {
+                let socket = zctx.socket(zmq::SUB)?;
+                socket.connect(address)?;
+                // Topic starts with protocol version and message type,
+                // sample rate and center frequency are checked for each message.
+                let topic = serialize_signal_topic(&SignalInfo {
+                    fs: p.channel.fs,
+                    fc: p.channel.fc,
+                    format: SignalFormat::Cf32,
+                });
+                socket.set_subscribe(&topic[0..2])?;
+                Input::Zmq { socket, fs: p.channel.fs, fc: p.channel.fc, seq: None }
+            },
+        };
+        Ok(Self { input, samples: Vec::new() })
+    }
+
+    /// Whether the input has ended.
+    /// ZeroMQ inputs never end, they just wait for more records.
+    fn ended(&self) -> bool {
+        match self.input {
+            Input::File { eof, .. } => eof,
+            Input::Zmq { .. } => false,
+        }
+    }
+
+    /// Wait until at least n samples are buffered.
+    /// After the end of a file, zeros are added instead.
+    fn fill(&mut self, n: usize) -> std::io::Result<()> {
+        if self.samples.len() >= n {
+            return Ok(());
+        }
+        match &mut self.input {
+            Input::File { file, format, rawbuf, eof } => {
+                let start = self.samples.len();
+                rawbuf.resize((n - start) * bytes_per_input_sample(*format), 0);
+                // Read as much as there is, a partial last sample is dropped
+                let mut read = 0;
+                while read < rawbuf.len() && !*eof {
+                    match file.read(&mut rawbuf[read..])? {
+                        0 => { *eof = true; },
+                        r => { read += r; },
+                    }
+                }
+                let new = read / bytes_per_input_sample(*format);
+                self.samples.resize(start + new, Complex{ re: 0.0, im: 0.0 });
+                convert_to_cf32(&rawbuf[..new * bytes_per_input_sample(*format)], &mut self.samples[start..], *format);
+                let scaling = input_format_scaling(*format);
+                self.samples[start..].iter_mut().for_each(|v| *v *= scaling);
+                self.samples.resize(n, Complex{ re: 0.0, im: 0.0 });
+            },
+            Input::Zmq { socket, fs, fc, seq } => {
+                while self.samples.len() < n {
+                    let message = socket.recv_multipart(0)?;
+                    let (topic, record) = match &message[..] {
+                        [topic, record] if topic.len() >= 24 => (topic, record),
+                        _ => continue,
+                    };
+                    // Records from a different signal or in an unknown format
+                    // are skipped. Tracking filters are not handled specially.
+                    let info = &topic[8..24];
+                    if info[0..8] != fs.to_le_bytes() || info[8..16] != fc.to_le_bytes() {
+                        continue;
+                    }
+                    let format = match SignalFormat::from_code(topic[2]) {
+                        Some(format) => format,
+                        None => continue,
+                    };
+                    let start = self.samples.len();
+                    let record_seq = match deserialize_signal_record(record, format, &mut self.samples) {
+                        Ok(s) => s,
+                        Err(_) => {
+                            eprintln!("Invalid record received from synthesis input");
+                            self.samples.truncate(start);
+                            continue;
+                        },
+                    };
+                    let record_len = self.samples.len() - start;
+                    match *seq {
+                        // Sequence numbers start again from 0
+                        // when the other instance is restarted,
+                        // so the timing of buffered samples is lost.
+                        Some(prev) if record_seq <= prev => {
+                            eprintln!("Synthesis input restarted, resynchronizing");
+                            self.samples.drain(..start);
+                        },
+                        // Records are missing, e.g. while a squelch was closed,
+                        // so fill the gap with zeros to keep the timing.
+                        // Longer gaps are not filled to limit memory use.
+                        Some(prev) if record_seq > prev + 1 => {
+                            let gap = (record_seq - prev - 1) as usize * record_len;
+                            if gap as f64 <= MAX_GAP * *fs {
+                                self.samples.splice(start..start, vec![Complex{ re: 0.0, im: 0.0 }; gap]);
+                            } else {
+                                eprintln!("Gap of {} samples in synthesis input, resynchronizing", gap);
+                            }
+                        },
+                        _ => {},
+                    }
+                    *seq = Some(record_seq);
+                }
+            },
+        }
+        Ok(())
+    }
+}
+
+pub fn synthesis_mainloop(
+    params: SynthesisParams,
+    zctx: &zmq::Context,
+) -> std::io::Result<()> {
+    if !is_input_format_complex(params.format) {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput,
+            "Synthesis output format must be complex"));
+    }
+    let channel_params: Vec<SynthesisChannelParams> = params.inputs.iter().map(|i| i.channel.clone()).collect();
+    let mut synthesizer = dsp::synthesis::Synthesizer::init(params.fs, params.fc, params.fft_size, &channel_params)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string()))?;
+    let mut channels = params.inputs.iter()
+        .map(|p| InputChannel::init(p, zctx))
+        .collect::<std::io::Result<Vec<InputChannel>>>()?;
+    eprintln!("Synthesizing {} channels into fs={} fc={}, output delayed by {} samples",
+        channels.len(), params.fs, params.fc, params.fft_size / 8);
+
+    let mut output = Vec::new();
+    let mut rawbuf = Vec::new();
+    let mut stdout = std::io::stdout();
+    loop {
+        for (c, channel) in channels.iter_mut().enumerate() {
+            channel.fill(synthesizer.channel_samples_per_fft(c) * params.ffts_per_buf)?;
+        }
+        // Stop after the block where all files ended
+        let ended = channels.iter().all(|c| c.ended());
+
+        let inputs: Vec<&[Complex<f32>]> = channels.iter().enumerate().map(|(c, channel)|
+            &channel.samples[.. synthesizer.channel_samples_per_fft(c) * params.ffts_per_buf]
+        ).collect();
+        synthesizer.process(&inputs, &mut output);
+        for (c, channel) in channels.iter_mut().enumerate() {
+            channel.samples.drain(.. synthesizer.channel_samples_per_fft(c) * params.ffts_per_buf);
+        }
+
+        rawbuf.resize(output.len() * bytes_per_input_sample(params.format), 0);
+        convert_from_cf32(&output, &mut rawbuf, params.format);
+        stdout.write_all(&rawbuf)?;
+
+        if ended {
+            break;
+        }
+    }
+    stdout.flush()
+}
@@ -4,3 +4,9 @@ FILTER="fs=80000:fc=0"
 PARAMS="--fftsize=16384 --samplerate=163840000 --filters $FILTER $FILTER $FILTER"
 dd bs=8192 count=200k status=progress if=/dev/zero | (time spektri --inputformat=cs16le $PARAMS >/dev/null)
 dd bs=8192 count=200k status=progress if=/dev/zero | (time spektri --inputformat=s16le $PARAMS >/dev/null)
+
+# Wideband filter outputs, such as 8 and 16 MHz wide sub-bands
+# of the whole HF band from an RX888.
+WIDEPARAMS="--fftsize=65536 --fftbuf=8 --samplerate=131072000 --filters fs=8192000:fc=10000000 fs=16384000:fc=40000000"
+dd bs=8192 count=200k status=progress if=/dev/zero | (time spektri --inputformat=s16le $WIDEPARAMS >/dev/null)
+dd bs=8192 count=200k status=progress if=/dev/zero | (time spektri --inputformat=s16le $WIDEPARAMS --filters fs=16384000:fc=40000000:format=cs16 >/dev/null)
@@ -44,7 +44,7 @@ def main(fs = 32000, fc = 0, filename = "data/test", address = "ipc:///tmp/spekt
         # Write data to a file
         #output_file.write(msg)
         # Write only the signal without the timestamps etc
-        output_file.write(msg[24:])
+        output_file.write(msg[40:])
 
 if __name__ == "__main__":
     import sys
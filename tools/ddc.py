@@ -188,7 +188,7 @@ def test_lo():
 def test(fs_in = 1000, fs_out = 300, fc = 150):
     """Test the DDC algorithm.
 
-    Read signal from stdin, mix and resample it and write the result to stdout.
+    Read raw cf32 signal from stdin, mix and resample it and write the result to stdout.
     Test by running:
     ../testsignal/target/release/testsignal --format=cf32le --samples=1000000 | ./ddc.py t > ../data/ddc_test
 
@@ -205,6 +205,21 @@ def test(fs_in = 1000, fs_out = 300, fc = 150):
         signalout = ddc.execute(signalin)
         sys.stdout.buffer.write(signalout.tobytes())
 
+def filter_output(fs_in, fc_in, fs_out, fc):
+    """Apply the DDC to the output of a Spektri filter.
+
+    Receive the filter output by ZeroMQ and write the result to stdout.
+    The metadata of each record is left out, so the output is raw cf32.
+    Note that output files of Spektri filters also contain the metadata
+    of each record, so they cannot be given to test() as such.
+    """
+    import sys
+    import spektri
+
+    ddc = DesignDdc(fs_in, fs_out, fc - fc_in)
+    for _, samples in spektri.recv_signal(fs_in, fc_in):
+        sys.stdout.buffer.write(ddc.execute(samples).tobytes())
+
 def benchmark(fs_in = 500000, fs_out = 16000, fc = 500, buflen = 4096, repeats = 1000):
     """Benchmark the polyphase DDC algorithm."""
     import time
@@ -230,5 +245,7 @@ if __name__ == "__main__":
     import sys
     if len(sys.argv) >= 2 and sys.argv[1] == 't':
         test()
+    elif len(sys.argv) == 6 and sys.argv[1] == 'z':
+        filter_output(*(float(v) for v in sys.argv[2:6]))
     else:
         benchmark()
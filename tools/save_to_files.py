@@ -11,8 +11,8 @@ import spektri
 zctx = spektri.zctx
 
 def main(
-    # Subscribe to any uint8 spectrum data by default
-    sub_topic = spektri.spectrum_topic(),
+    # Subscribe to uint8 spectrum data by default
+    sub_topic = spektri.spectrum_topic(spektri.SPECTRUM_FORMAT_U8),
     address = "ipc:///tmp/spektri.zmq",
     filename_fmt = "../data/test_%Y%m%d_%H%M%S",
     file_interval = 60
@@ -58,7 +58,7 @@ def main(
             # This is a temporary hack until the file format is more stable.
             output_file.write(msg[8:20])
             # Spectrum or signal data
-            output_file.write(msg[24:])
+            output_file.write(spektri.record_payload(topic, msg))
 
             prev_t_s = t_s
 
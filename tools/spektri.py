@@ -12,21 +12,140 @@ DEFAULT_ADDRESS = "ipc:///tmp/spektri.zmq"
 zctx = zmq.Context()
 
 
-def signal_topic(fs, fc):
+PROTOCOL_VERSION = 4
+
+# Sample formats of signal data
+FORMAT_CF32 = 0x5C
+FORMAT_CS16 = 0x48
+FORMAT_CS8 = 0x44
+FORMAT_CS16BFP = 0x78
+
+# numpy data types of the integer components of each format
+_format_dtypes = {
+    FORMAT_CF32: np.float32,
+    FORMAT_CS16: np.int16,
+    FORMAT_CS8: np.int8,
+    FORMAT_CS16BFP: np.int16,
+}
+
+
+def signal_topic(fs, fc, fmt=FORMAT_CF32):
     """Serialize subscription topic for waveform data
-    with given sample rate and center frequency.
+    with given sample rate, center frequency and sample format."""
+    return bytes((PROTOCOL_VERSION, 0x40, fmt, 0,0,0,0,0)) + struct.pack("<dd", fs, fc)
+
+
+# Demodulator modes
+DEMOD_MODES = {"am": 1, "sam": 2, "usb": 3, "lsb": 4, "fm": 5, "cw": 6, "wfm": 7}
+
+
+def audio_topic(fs, fc, mode, channels=1):
+    """Serialize subscription topic for demodulated audio
+    with given sample rate, frequency and demodulator mode."""
+    return bytes((PROTOCOL_VERSION, 0x80, 0x1C, channels, DEMOD_MODES[mode], 0,0,0)) + struct.pack("<dd", fs, fc)
+
+
+def squelch_topic(fs, fc):
+    """Serialize subscription topic for squelch events
+    of a filter with given sample rate and center frequency."""
+    return bytes((PROTOCOL_VERSION, 0x20, 0x01, 0,0,0,0,0)) + struct.pack("<dd", fs, fc)
+
+
+def telemetry_topic():
+    """Serialize subscription topic for channel telemetry of all filters."""
+    return bytes((PROTOCOL_VERSION, 0x20, 0x02, 0,0,0,0,0))
+
+
+def detection_topic(product=None, fd=None, f0=None):
+    """Serialize subscription topic for signals detected in spectrum data.
+    product is the index of the spectrum product, 0 for the one
+    given by the main options and then each --spectrum in order.
+    If it is not given, subscribe to detections from all spectra.
+    Bin spacing and first bin frequency of the spectrum
+    can be given to only subscribe to matching spectra."""
+    if product is None:
+        return bytes((PROTOCOL_VERSION, 0x20, 0x03))
+    if fd is None or f0 is None:
+        return bytes((PROTOCOL_VERSION, 0x20, 0x03, product))
+    return bytes((PROTOCOL_VERSION, 0x20, 0x03, product, 0,0,0,0)) + struct.pack("<dd", fd, f0)
+
+
+def rds_topic(fs, fc):
+    """Serialize subscription topic for RDS data decoded
+    from a wideband FM channel with given sample rate and frequency."""
+    return bytes((PROTOCOL_VERSION, 0xA0, 0, 0,0,0,0,0)) + struct.pack("<dd", fs, fc)
+
+
+# Data formats of spectrum data
+SPECTRUM_FORMAT_U8 = 0x24
+SPECTRUM_FORMAT_U16 = 0x29  # big endian
+SPECTRUM_FORMAT_U16LE = 0x28
+SPECTRUM_FORMAT_F32 = 0x1C
+
+_spectrum_dtypes = {
+    SPECTRUM_FORMAT_U8: np.uint8,
+    SPECTRUM_FORMAT_U16: ">u2",
+    SPECTRUM_FORMAT_U16LE: "<u2",
+    SPECTRUM_FORMAT_F32: "<f4",
+}
+
+# Window functions of spectrum data
+SPECTRUM_WINDOW_RECTANGULAR = 0
+SPECTRUM_WINDOW_HANN = 1
+SPECTRUM_WINDOW_BLACKMAN_HARRIS = 2
+
+# Statistics of spectrum data
+SPECTRUM_STATISTIC_MEAN = 0
+SPECTRUM_STATISTIC_MAX = 1
+SPECTRUM_STATISTIC_MIN = 2
+SPECTRUM_STATISTIC_PERCENTILE = 3
+SPECTRUM_STATISTIC_EMA = 4  # Exponential moving average
+SPECTRUM_STATISTIC_KURTOSIS = 5  # Spectral kurtosis in dB, 0 dB for Gaussian noise
 
-    Sample format is fixed as complex 32-bit float since that is
-    the only output format currently supported in Spektri."""
-    return bytes((2, 0x40, 0x5C, 0,0,0,0,0)) + struct.pack("<dd", fs, fc)
+# Units of spectrum data
+SPECTRUM_UNIT_DBFS = 0
+SPECTRUM_UNIT_DBM = 1
+SPECTRUM_UNIT_DBM_PER_HZ = 2
 
 
-def spectrum_topic():
-    """Serialize subscription topic for any spectrum data.
+def spectrum_topic(fmt=None):
+    """Serialize subscription topic for spectrum data
+    in a given format, or in any format if not given."""
+    if fmt is None:
+        return bytes((PROTOCOL_VERSION, 0x60))
+    return bytes((PROTOCOL_VERSION, 0x60, fmt))
 
-    Sample format is fixed as unsigned 8-bit int since that is
-    the only output format currently supported in Spektri."""
-    return bytes((2, 0x60, 0x24, 0,0,0,0,0))
+
+@dataclass
+class SpectrumInfo:
+    """Information about spectrum data given in its topic."""
+    fmt: int          # Data format, one of SPECTRUM_FORMAT_*
+    window: int       # Window function, one of SPECTRUM_WINDOW_*
+    statistic: int    # One of SPECTRUM_STATISTIC_*
+    percentile: int   # Percentile for SPECTRUM_STATISTIC_PERCENTILE
+    fd: float         # Spacing of bins in Hz
+    f0: float         # Frequency of the first bin
+    fft_size: int
+    averages: int     # Number of FFTs averaged, or time constant in FFTs for EMA
+    db_scale: float   # Value v of a bin is v * db_scale + db_offset dB
+    db_offset: float
+    unit: int         # One of SPECTRUM_UNIT_*
+    gain_table: bool  # Whether a frequency-dependent gain table was applied
+    fullscale_dbm: float    # Calibrated power of a full-scale sinusoid
+    noise_bandwidth: float  # Equivalent noise bandwidth of a bin in Hz
+
+def unpack_spectrum_topic(topic):
+    """Deserialize the topic of spectrum data."""
+    fd, f0, fft_size, averages, db_scale, db_offset, fullscale_dbm, noise_bandwidth = struct.unpack("<ddIIffff", topic[8:48])
+    return SpectrumInfo(fmt=topic[2], window=topic[3], statistic=topic[4], percentile=topic[5], fd=fd, f0=f0, fft_size=fft_size,
+        averages=averages, db_scale=db_scale, db_offset=db_offset, unit=topic[6], gain_table=bool(topic[7]),
+        fullscale_dbm=fullscale_dbm, noise_bandwidth=noise_bandwidth)
+
+
+def unpack_spectrum(msg, info):
+    """Convert bins of a spectrum record to dB."""
+    values = np.frombuffer(msg[24:], dtype=_spectrum_dtypes[info.fmt]).astype(np.float32)
+    return values * info.db_scale + info.db_offset
 
 
 @dataclass
@@ -42,28 +161,194 @@ def unpack_metadata(msg):
     return Metadata(seq=seq, time_s=time_s, time_ns=time_ns)
 
 
-def recv_signal(fs, fc, address=DEFAULT_ADDRESS, zctx=zctx):
+# Flags in signal metadata
+SIGNAL_FLAG_SQUELCH = 0x1  # Filter has a squelch
+SIGNAL_FLAG_PREROLL = 0x2  # Record is from before squelch opened
+SIGNAL_FLAG_HANG = 0x4     # Squelch is open only because of hang time
+
+@dataclass
+class SignalMetadata:
+    """Metadata specific to a record of signal data."""
+    scale: float    # Scaling factor to convert samples to floating point
+    saturated: int  # Number of samples clipped in conversion
+    flags: int      # Combination of SIGNAL_FLAG_* values
+    freq_offset: float  # Center frequency relative to the topic, tracking filters only
+
+def unpack_signal_metadata(msg):
+    """Deserialize signal metadata following the common metadata."""
+    scale, saturated, flags, freq_offset = struct.unpack("<fIIf", msg[24:40])
+    return SignalMetadata(scale=scale, saturated=saturated, flags=flags, freq_offset=freq_offset)
+
+
+def record_payload(topic, msg):
+    """Return the data of a record without its metadata.
+    Signal records have signal metadata after the common metadata."""
+    if topic[1] == 0x40:
+        return msg[40:]
+    return msg[24:]
+
+
+def unpack_signal(msg, fmt=FORMAT_CF32):
+    """Convert samples of a signal record to complex floating point."""
+    samples = np.frombuffer(msg[40:], dtype=_format_dtypes[fmt]).astype(np.float32)
+    samples *= unpack_signal_metadata(msg).scale
+    return samples.view(np.complex64)
+
+
+def recv_signal(fs, fc, address=DEFAULT_ADDRESS, zctx=zctx, fmt=FORMAT_CF32):
     """Receive waveform data from Spektri."""
 
     s = zctx.socket(zmq.SUB)
     #s.setsockopt(zmq.RCVBUF, 100000)
     #s.set_hwm(10)
-    s.subscribe(signal_topic(fs, fc))
+    s.subscribe(signal_topic(fs, fc, fmt))
     s.connect(address)
     while True:
         _, msg = s.recv_multipart()
-        yield (unpack_metadata(msg), np.frombuffer(msg[24:], dtype=np.complex64))
+        yield (unpack_metadata(msg), unpack_signal(msg, fmt))
 
 
-def recv_spectrum(fs, fc, address=DEFAULT_ADDRESS, zctx=zctx):
-    """Receive spectrum data from Spektri."""
+def recv_audio(fs, fc, mode, channels=1, address=DEFAULT_ADDRESS, zctx=zctx):
+    """Receive demodulated audio from Spektri."""
+
+    s = zctx.socket(zmq.SUB)
+    s.subscribe(audio_topic(fs, fc, mode, channels))
+    s.connect(address)
+    while True:
+        _, msg = s.recv_multipart()
+        yield (unpack_metadata(msg), np.frombuffer(msg[24:], dtype=np.float32))
+
+
+@dataclass
+class RdsData:
+    """Station information and groups decoded from RDS."""
+    pi: int      # Program identification code
+    pty: int     # Program type
+    tp: bool     # Traffic program
+    ta: bool     # Traffic announcement
+    ms: bool     # Music/speech switch
+    stereo: bool # Stereo pilot detected
+    ps: str      # Program service name
+    rt: str      # RadioText
+    groups: list # Groups received, each as a tuple of 4 blocks
+
+def unpack_rds(msg):
+    """Deserialize an RDS record following the common metadata."""
+    pi, pty, flags = struct.unpack("<HBB", msg[24:28])
+    ngroups, = struct.unpack("<I", msg[100:104])
+    groups = list(struct.iter_unpack("<4H", msg[108:108 + 8 * ngroups]))
+    return RdsData(
+        pi=pi, pty=pty,
+        tp=bool(flags & 1), ta=bool(flags & 2), ms=bool(flags & 4), stereo=bool(flags & 8),
+        ps=msg[28:36].decode("latin-1"),
+        rt=msg[36:100].decode("latin-1").rstrip(),
+        groups=groups,
+    )
+
+
+def recv_rds(fs, fc, address=DEFAULT_ADDRESS, zctx=zctx):
+    """Receive decoded RDS data from Spektri."""
+
+    s = zctx.socket(zmq.SUB)
+    s.subscribe(rds_topic(fs, fc))
+    s.connect(address)
+    while True:
+        _, msg = s.recv_multipart()
+        yield (unpack_metadata(msg), unpack_rds(msg))
+
+
+@dataclass
+class SquelchEvent:
+    """Squelch opening or closing."""
+    open: bool         # True if squelch opened, False if closed
+    snr: float         # Channel SNR in dB
+    noise_floor: float # Noise floor estimate in dB
+
+def recv_squelch_events(fs, fc, address=DEFAULT_ADDRESS, zctx=zctx):
+    """Receive squelch events of a filter from Spektri."""
+
+    s = zctx.socket(zmq.SUB)
+    s.subscribe(squelch_topic(fs, fc))
+    s.connect(address)
+    while True:
+        _, msg = s.recv_multipart()
+        is_open, snr, noise_floor = struct.unpack("<Iff", msg[24:36])
+        yield (unpack_metadata(msg), SquelchEvent(open=bool(is_open), snr=snr, noise_floor=noise_floor))
+
+
+@dataclass
+class ChannelTelemetry:
+    """Power and SNR of a filter channel over a telemetry interval.
+    Powers are in dB relative to input full scale."""
+    fs: float     # Sample rate of the filter
+    fc: float     # Center frequency of the filter
+    power: float  # Mean channel power
+    peak: float   # Peak sample power
+    noise: float  # Estimated noise power
+    snr: float    # Ratio of channel power to noise power in dB
+
+def recv_telemetry(address=DEFAULT_ADDRESS, zctx=zctx):
+    """Receive channel telemetry of all filters from Spektri."""
+
+    s = zctx.socket(zmq.SUB)
+    s.subscribe(telemetry_topic())
+    s.connect(address)
+    while True:
+        _, msg = s.recv_multipart()
+        n, = struct.unpack("<I", msg[24:28])
+        yield (unpack_metadata(msg), [
+            ChannelTelemetry(*struct.unpack("<ddffff", msg[32 + 32 * i : 64 + 32 * i]))
+            for i in range(n)
+        ])
+
+
+@dataclass
+class Detection:
+    """Signal detected in spectrum data."""
+    fc: float     # Center frequency
+    bw: float     # Bandwidth
+    power: float  # Total power in dB
+    peak: float   # Power of the strongest bin in dB
+    noise: float  # Noise floor power over the bandwidth in dB
+    snr: float    # Ratio of power to noise floor in dB
+
+def recv_detections(product=None, fd=None, f0=None, address=DEFAULT_ADDRESS, zctx=zctx):
+    """Receive signals detected in spectrum data from Spektri."""
+
+    s = zctx.socket(zmq.SUB)
+    s.subscribe(detection_topic(product, fd, f0))
+    s.connect(address)
+    while True:
+        _, msg = s.recv_multipart()
+        n, = struct.unpack("<I", msg[24:28])
+        yield (unpack_metadata(msg), [
+            Detection(*struct.unpack("<ddffff", msg[32 + 32 * i : 64 + 32 * i]))
+            for i in range(n)
+        ])
+
+
+def recv_spectrum(fmt=None, address=DEFAULT_ADDRESS, zctx=zctx):
+    """Receive spectrum data from Spektri.
+    Yield metadata, spectrum information and bins in dB."""
 
     s = zctx.socket(zmq.SUB)
     #s.setsockopt(zmq.RCVBUF, 100000)
     #s.set_hwm(10)
-    s.subscribe(spectrum_topic())
+    s.subscribe(spectrum_topic(fmt))
     s.connect(address)
     while True:
-        _, msg = s.recv_multipart()
-        # TODO: return metadata too. None is placeholder for that now
-        yield (unpack_metadata(msg), np.frombuffer(msg[24:], dtype=np.complex64))
+        topic, msg = s.recv_multipart()
+        info = unpack_spectrum_topic(topic)
+        yield (unpack_metadata(msg), info, unpack_spectrum(msg, info))
+
+
+def control(command, address, zctx=zctx):
+    """Send a command to the control interface of Spektri, e.g.
+    "freq NAME HZ" to set the center frequency of a tracking filter.
+    Return the reply."""
+    sock = zctx.socket(zmq.REQ)
+    sock.connect(address)
+    sock.send_string(command)
+    reply = sock.recv_string()
+    sock.close()
+    return reply